@@ -0,0 +1,56 @@
+use crate::models::structured::{call_structured, OutputSchema};
+use crate::utils::{ArgModels, BedrustConfig};
+use anyhow::anyhow;
+use aws_sdk_bedrockruntime::types::ContentBlock;
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::path::Path;
+
+// NOTE:
+// `bedrust ask --schema schema.json "question"` - a single question, answered with JSON that
+// matches the schema (see `models/structured.rs`). Only the JSON goes to stdout, everything else
+// goes to stderr, so the output can be piped into `jq` or another program.
+
+pub async fn ask_command(
+    prompt: Option<&String>,
+    schema_path: &Path,
+    attempts: usize,
+    client: &aws_sdk_bedrockruntime::Client,
+    model: &ArgModels,
+    config: &BedrustConfig,
+) -> Result<(), anyhow::Error> {
+    let schema = fs::read_to_string(schema_path)
+        .map_err(|e| anyhow!("🔴 | Could not read {}: {}", schema_path.display(), e))?;
+    let schema: serde_json::Value = serde_json::from_str(&schema)
+        .map_err(|e| anyhow!("🔴 | {} is not valid JSON: {}", schema_path.display(), e))?;
+    let schema = OutputSchema::new(&schema).map_err(|e| anyhow!("🔴 | {}", e))?;
+
+    // the question can also be piped in
+    let prompt = match prompt {
+        Some(prompt) => prompt.clone(),
+        None if !io::stdin().is_terminal() => {
+            let mut prompt = String::new();
+            io::stdin().read_to_string(&mut prompt)?;
+            prompt
+        }
+        None => return Err(anyhow!("🔴 | Give a question, or pipe it in")),
+    };
+    if prompt.trim().is_empty() {
+        return Err(anyhow!("🔴 | The question is empty"));
+    }
+
+    eprintln!("☎️  | Calling Model: {}", model.to_str());
+    let answer = call_structured(
+        client,
+        model.to_str(),
+        &config.inference_params_for(model),
+        &config.guardrail,
+        vec![ContentBlock::Text(prompt.trim().to_string())],
+        &schema,
+        attempts.max(1),
+    )
+    .await
+    .map_err(|e| anyhow!("🔴 | {}", e))?;
+    println!("{}", serde_json::to_string_pretty(&answer)?);
+    Ok(())
+}
@@ -0,0 +1,587 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use aws_sdk_bedrock::types::{
+    ModelInvocationJobInputDataConfig, ModelInvocationJobOutputDataConfig,
+    ModelInvocationJobS3InputDataConfig, ModelInvocationJobS3OutputDataConfig,
+    ModelInvocationJobStatus, S3InputFormat,
+};
+use aws_sdk_s3::primitives::ByteStream;
+use base64::{engine::general_purpose, Engine as _};
+use chrono::Local;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::AsyncBufReadExt;
+
+use crate::caption_source::split_s3_uri;
+use crate::captioner::{
+    caption_inference_params, fields_from_value, load_image, prepare_image, CaptionJournal,
+    CaptionTask, FieldValue, Image,
+};
+use crate::models::structured::{OUTPUT_TOOL, OUTPUT_TOOL_DESCRIPTION};
+use crate::utils::{print_warning, CaptionConfig, InferenceParams};
+
+// NOTE:
+// `--batch` captions the images with a Bedrock batch inference job instead of one Converse call
+// per image - slower to start, but half the price and no throttling. The images go into JSONL
+// files of model inputs (the native request body of the model, batch jobs do not take Converse
+// requests), uploaded to `caption.batch.s3_uri`. Once the job is done the output files are read
+// back and every result is matched to its image by the record id.
+// The job is remembered in a state file next to the output, so a run that gets interrupted while
+// waiting picks the same job up again instead of starting a new one.
+
+// Bedrock refuses jobs with fewer records
+const MIN_RECORDS: usize = 100;
+// input files are split before they get to the 1 GB limit
+const MAX_INPUT_FILE_BYTES: usize = 900_000_000;
+
+// Batch jobs take the native request body of the model, these are the ones we know
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ModelFamily {
+    Anthropic,
+    Nova,
+}
+
+impl ModelFamily {
+    fn for_model(model_id: &str) -> Result<Self, anyhow::Error> {
+        if model_id.contains("anthropic.claude-3") {
+            Ok(ModelFamily::Anthropic)
+        } else if model_id.contains("amazon.nova") {
+            Ok(ModelFamily::Nova)
+        } else {
+            Err(anyhow!(
+                "🔴 | Batch captioning works with the Claude 3 and Nova models, not with {}",
+                model_id
+            ))
+        }
+    }
+}
+
+// What we need to know to find the job (and its results) again
+#[derive(Debug, Serialize, Deserialize)]
+struct BatchState {
+    job_arn: String,
+    output_uri: String,
+    // record id -> image path
+    records: BTreeMap<String, PathBuf>,
+}
+
+pub struct CaptionBatch<'a> {
+    pub bedrock_client: &'a aws_sdk_bedrock::Client,
+    pub s3_client: &'a aws_sdk_s3::Client,
+    pub model_id: &'a str,
+    pub config: &'a CaptionConfig,
+    // captions.json -> captions.batch.json
+    pub state_path: PathBuf,
+}
+
+impl CaptionBatch<'_> {
+    // Captions the images that do not have a caption yet, in one batch job
+    pub async fn run(
+        &self,
+        images: &mut [Image],
+        task: &CaptionTask,
+        journal: &mut CaptionJournal,
+    ) -> Result<(), anyhow::Error> {
+        let family = ModelFamily::for_model(self.model_id)?;
+        let state = match fs::read_to_string(&self.state_path) {
+            Ok(state) => {
+                let state: BatchState = serde_json::from_str(&state)?;
+                println!(
+                    "⏭️  | Picking up the batch job started by an earlier run: {} (delete `{}` to start a new one)",
+                    state.job_arn,
+                    self.state_path.display()
+                );
+                state
+            }
+            Err(_) => match self.submit(images, task, family).await? {
+                Some(state) => state,
+                None => return Ok(()),
+            },
+        };
+
+        let status = self.wait_for(&state.job_arn).await?;
+        if status == ModelInvocationJobStatus::Failed {
+            fs::remove_file(&self.state_path)?;
+            return Err(anyhow!("🔴 | The batch job failed"));
+        }
+
+        // stopped and expired jobs still have the results of the records they got to. The output
+        // can be huge, every line is applied to its image as it is read
+        let positions: BTreeMap<PathBuf, usize> = images
+            .iter()
+            .enumerate()
+            .map(|(n, image)| (image.path.clone(), n))
+            .collect();
+        let mut answered = BTreeSet::new();
+        self.read_output(&state.output_uri, |line| {
+            let Some((record_id, result)) = parse_output_record(line, task) else {
+                return Ok(());
+            };
+            let Some(&n) = state.records.get(&record_id).and_then(|p| positions.get(p)) else {
+                return Ok(());
+            };
+            let image = &mut images[n];
+            match result {
+                Ok((caption, fields)) => {
+                    image.caption = Some(caption);
+                    image.fields = fields;
+                }
+                Err(e) => image.error = Some(e),
+            }
+            journal.record(image)?;
+            answered.insert(record_id);
+            Ok(())
+        })
+        .await?;
+        for (record_id, path) in &state.records {
+            let Some(&n) = positions.get(path) else {
+                continue;
+            };
+            if !answered.contains(record_id) {
+                let image = &mut images[n];
+                image.error = Some("The batch job has no result for this image".into());
+                journal.record(image)?;
+            }
+        }
+        fs::remove_file(&self.state_path)?;
+        Ok(())
+    }
+
+    // Writes and uploads the model inputs, and starts the job. Returns None if there is nothing
+    // left to caption. The images that can not be sent get their error.
+    async fn submit(
+        &self,
+        images: &mut [Image],
+        task: &CaptionTask,
+        family: ModelFamily,
+    ) -> Result<Option<BatchState>, anyhow::Error> {
+        let batch = &self.config.batch;
+        let (Some(s3_uri), Some(role_arn)) = (&batch.s3_uri, &batch.role_arn) else {
+            return Err(anyhow!(
+                "🔴 | Batch captioning needs `s3_uri` and `role_arn` in the `caption.batch` section of the configuration"
+            ));
+        };
+        let pending: Vec<&mut Image> = images
+            .iter_mut()
+            .filter(|i| i.caption.is_none() && i.error.is_none())
+            .collect();
+        if pending.is_empty() {
+            return Ok(None);
+        }
+
+        let job_name = format!("bedrust-captions-{}", Local::now().format("%Y%m%d%H%M%S"));
+        let job_uri = format!("{}/{}", s3_uri.trim_end_matches('/'), job_name);
+        let input_dir = std::env::temp_dir().join(&job_name);
+        fs::create_dir_all(&input_dir)?;
+
+        println!("📦 | Preparing {} images for the batch job", pending.len());
+        let progress_bar = ProgressBar::new(pending.len().try_into()?);
+        progress_bar.set_style(
+            ProgressStyle::with_template(
+                "{spinner:.green} [{wide_bar:.cyan/blue}] {msg} ({pos}/{len})",
+            )
+            .unwrap()
+            .progress_chars("#>-"),
+        );
+        let inference_parameters = caption_inference_params();
+        let mut records = BTreeMap::new();
+        let mut files = vec![input_dir.join("part-0001.jsonl")];
+        let mut writer = BufWriter::new(fs::File::create(&files[0])?);
+        let mut written = 0;
+        for (n, image) in pending.into_iter().enumerate() {
+            progress_bar.set_message(image.uri());
+            let max_dimension = self.config.max_dimension;
+            let prepared = match load_image(&image.path) {
+                Ok(bytes) => {
+                    tokio::task::spawn_blocking(move || prepare_image(bytes, max_dimension)).await?
+                }
+                Err(e) => Err(e),
+            };
+            let (bytes, format) = match prepared {
+                Ok(prepared) => prepared,
+                Err(e) => {
+                    print_warning(&format!("Skipping {}: {}", image.uri(), e));
+                    image.error = Some(format!("Could not prepare the image: {}", e));
+                    progress_bar.inc(1);
+                    continue;
+                }
+            };
+            let record_id = format!("IMG{:08}", n);
+            let line = serde_json::to_string(&json!({
+                "recordId": record_id,
+                "modelInput": model_input(
+                    family,
+                    task,
+                    &inference_parameters,
+                    &general_purpose::STANDARD.encode(bytes),
+                    format.as_str(),
+                ),
+            }))?;
+            if written > 0 && written + line.len() > MAX_INPUT_FILE_BYTES {
+                writer.flush()?;
+                files.push(input_dir.join(format!("part-{:04}.jsonl", files.len() + 1)));
+                writer = BufWriter::new(fs::File::create(files.last().unwrap())?);
+                written = 0;
+            }
+            writeln!(writer, "{}", line)?;
+            written += line.len() + 1;
+            records.insert(record_id, image.path.clone());
+            progress_bar.inc(1);
+        }
+        writer.flush()?;
+        progress_bar.finish();
+        // counted without the images that were skipped
+        if records.len() < MIN_RECORDS {
+            fs::remove_dir_all(&input_dir)?;
+            return Err(anyhow!(
+                "🔴 | A batch job needs at least {} images, caption these {} without --batch",
+                MIN_RECORDS,
+                records.len()
+            ));
+        }
+
+        println!("⬆️  | Uploading the model inputs to {}/input/", job_uri);
+        for file in &files {
+            let name = file.file_name().unwrap_or_default().to_string_lossy();
+            let uri = format!("{}/input/{}", job_uri, name);
+            let (bucket, key) = split_s3_uri(&uri)?;
+            self.s3_client
+                .put_object()
+                .bucket(bucket)
+                .key(key)
+                .body(ByteStream::from_path(file).await?)
+                .send()
+                .await
+                .map_err(|e| {
+                    anyhow!(
+                        "🔴 | Could not upload {}: {}",
+                        name,
+                        aws_sdk_s3::error::DisplayErrorContext(e)
+                    )
+                })?;
+        }
+        fs::remove_dir_all(&input_dir)?;
+
+        let output_uri = format!("{}/output/", job_uri);
+        let mut request = self
+            .bedrock_client
+            .create_model_invocation_job()
+            .job_name(&job_name)
+            .role_arn(role_arn)
+            .model_id(self.model_id)
+            .input_data_config(ModelInvocationJobInputDataConfig::S3InputDataConfig(
+                ModelInvocationJobS3InputDataConfig::builder()
+                    .s3_uri(format!("{}/input/", job_uri))
+                    .s3_input_format(S3InputFormat::Jsonl)
+                    .build()?,
+            ))
+            .output_data_config(ModelInvocationJobOutputDataConfig::S3OutputDataConfig(
+                ModelInvocationJobS3OutputDataConfig::builder()
+                    .s3_uri(&output_uri)
+                    .build()?,
+            ));
+        if let Some(hours) = batch.timeout_hours {
+            request = request.timeout_duration_in_hours(hours);
+        }
+        let job = request.send().await.map_err(|e| {
+            anyhow!(
+                "🔴 | Could not start the batch job: {}",
+                aws_sdk_bedrock::error::DisplayErrorContext(e)
+            )
+        })?;
+        println!("🚀 | Started the batch job: {}", job.job_arn());
+
+        let state = BatchState {
+            job_arn: job.job_arn().to_string(),
+            output_uri,
+            records,
+        };
+        fs::write(&self.state_path, serde_json::to_string_pretty(&state)?)?;
+        Ok(Some(state))
+    }
+
+    // Checks on the job every `poll_seconds` until it stops running
+    async fn wait_for(&self, job_arn: &str) -> Result<ModelInvocationJobStatus, anyhow::Error> {
+        let spinner = ProgressBar::new_spinner();
+        spinner.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+        spinner.enable_steady_tick(Duration::from_millis(100));
+        let started = Instant::now();
+        loop {
+            let job = self
+                .bedrock_client
+                .get_model_invocation_job()
+                .job_identifier(job_arn)
+                .send()
+                .await
+                .map_err(|e| {
+                    anyhow!(
+                        "🔴 | Could not check on the batch job: {}",
+                        aws_sdk_bedrock::error::DisplayErrorContext(e)
+                    )
+                })?;
+            let status = job
+                .status()
+                .cloned()
+                .unwrap_or(ModelInvocationJobStatus::Submitted);
+            match status {
+                ModelInvocationJobStatus::Completed
+                | ModelInvocationJobStatus::PartiallyCompleted
+                | ModelInvocationJobStatus::Stopped
+                | ModelInvocationJobStatus::Expired
+                | ModelInvocationJobStatus::Failed => {
+                    spinner.finish_and_clear();
+                    println!("📬 | The batch job is {}", status.as_str());
+                    if let Some(message) = job.message() {
+                        println!("📝 | {}", message);
+                    }
+                    return Ok(status);
+                }
+                _ => spinner.set_message(format!(
+                    "The batch job is {} ({} minutes so far, it can take up to a day)",
+                    status.as_str(),
+                    started.elapsed().as_secs() / 60
+                )),
+            }
+            tokio::time::sleep(Duration::from_secs(self.config.batch.poll_seconds.max(1))).await;
+        }
+    }
+
+    // Hands every line of the `.jsonl.out` files of the job to `on_line`, as they download
+    async fn read_output(
+        &self,
+        output_uri: &str,
+        mut on_line: impl FnMut(&str) -> Result<(), anyhow::Error>,
+    ) -> Result<(), anyhow::Error> {
+        let (bucket, prefix) = split_s3_uri(output_uri)?;
+        let mut pages = self
+            .s3_client
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(|e| {
+                anyhow!(
+                    "🔴 | Could not list {}: {}",
+                    output_uri,
+                    aws_sdk_s3::error::DisplayErrorContext(e)
+                )
+            })?;
+            for key in page.contents().iter().filter_map(|o| o.key()) {
+                if !key.ends_with(".jsonl.out") {
+                    continue;
+                }
+                let object = self
+                    .s3_client
+                    .get_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .send()
+                    .await
+                    .map_err(|e| anyhow!("🔴 | Could not download {}: {}", key, e))?;
+                let mut reader = object.body.into_async_read();
+                let mut line = Vec::new();
+                loop {
+                    line.clear();
+                    if reader.read_until(b'\n', &mut line).await? == 0 {
+                        break;
+                    }
+                    on_line(String::from_utf8_lossy(&line).trim_end())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The request body for one image, in the format of the model
+fn model_input(
+    family: ModelFamily,
+    task: &CaptionTask,
+    inference_parameters: &InferenceParams,
+    image: &str,
+    format: &str,
+) -> Value {
+    let (system, text, schema) = match task {
+        CaptionTask::Prompt(prompt) => (Some(prompt), None, None),
+        CaptionTask::Profile(profile, schema) => {
+            (None, Some(&profile.prompt), Some(schema.tool_schema()))
+        }
+    };
+    match family {
+        ModelFamily::Anthropic => {
+            let mut content = vec![json!({
+                "type": "image",
+                "source": { "type": "base64", "media_type": format!("image/{}", format), "data": image },
+            })];
+            if let Some(text) = text {
+                content.push(json!({ "type": "text", "text": text }));
+            }
+            let mut input = json!({
+                "anthropic_version": "bedrock-2023-05-31",
+                "max_tokens": inference_parameters.max_tokens,
+                "temperature": inference_parameters.temperature,
+                "top_p": inference_parameters.top_p,
+                "messages": [{ "role": "user", "content": content }],
+            });
+            if let Some(system) = system {
+                input["system"] = json!(system);
+            }
+            if let Some(schema) = schema {
+                input["tools"] = json!([{
+                    "name": OUTPUT_TOOL,
+                    "description": OUTPUT_TOOL_DESCRIPTION,
+                    "input_schema": schema,
+                }]);
+                input["tool_choice"] = json!({ "type": "tool", "name": OUTPUT_TOOL });
+            }
+            input
+        }
+        ModelFamily::Nova => {
+            let mut content = vec![json!({
+                "image": { "format": format, "source": { "bytes": image } },
+            })];
+            if let Some(text) = text {
+                content.push(json!({ "text": text }));
+            }
+            let mut input = json!({
+                "schemaVersion": "messages-v1",
+                "messages": [{ "role": "user", "content": content }],
+                "inferenceConfig": {
+                    "maxTokens": inference_parameters.max_tokens,
+                    "temperature": inference_parameters.temperature,
+                    "topP": inference_parameters.top_p,
+                },
+            });
+            if let Some(system) = system {
+                input["system"] = json!([{ "text": system }]);
+            }
+            if let Some(schema) = schema {
+                input["toolConfig"] = json!({
+                    "tools": [{ "toolSpec": {
+                        "name": OUTPUT_TOOL,
+                        "description": OUTPUT_TOOL_DESCRIPTION,
+                        "inputSchema": { "json": schema },
+                    } }],
+                    "toolChoice": { "any": {} },
+                });
+            }
+            input
+        }
+    }
+}
+
+type BatchResult = Result<(String, BTreeMap<String, FieldValue>), String>;
+
+// A line of the job output: the record id, and the caption (or why there is none). Both the
+// Claude and the Nova response bodies are understood.
+fn parse_output_record(line: &str, task: &CaptionTask) -> Option<(String, BatchResult)> {
+    let record: Value = serde_json::from_str(line).ok()?;
+    let record_id = record["recordId"].as_str()?.to_string();
+    if let Some(error) = record.get("error").filter(|e| !e.is_null()) {
+        let message = error["errorMessage"]
+            .as_str()
+            .map(String::from)
+            .unwrap_or_else(|| error.to_string());
+        return Some((record_id, Err(message)));
+    }
+    let output = &record["modelOutput"];
+    let content = output["content"]
+        .as_array()
+        .or_else(|| output["output"]["message"]["content"].as_array());
+    let Some(content) = content else {
+        return Some((record_id, Err("The model returned nothing".to_string())));
+    };
+    let mut text = String::new();
+    let mut tool_input = None;
+    for block in content {
+        if let Some(t) = block["text"].as_str() {
+            text.push_str(t);
+        }
+        if block["type"] == "tool_use" {
+            tool_input = Some(block["input"].clone());
+        }
+        if let Some(tool_use) = block.get("toolUse") {
+            tool_input = Some(tool_use["input"].clone());
+        }
+    }
+    let result = match task {
+        CaptionTask::Prompt(_) if text.trim().is_empty() => {
+            Err("The model returned no caption".to_string())
+        }
+        CaptionTask::Prompt(_) => Ok((text.trim().to_string(), BTreeMap::new())),
+        CaptionTask::Profile(profile, schema) => match tool_input {
+            None => Err("The model did not return the fields".to_string()),
+            Some(input) => schema
+                .check(input)
+                .map_err(|errors| {
+                    format!("The fields do not match the profile: {}", errors.join("; "))
+                })
+                .and_then(|value| fields_from_value(profile, value).map_err(|e| e.to_string())),
+        },
+    };
+    Some((record_id, result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::structured::OutputSchema;
+    use crate::utils::CaptionProfile;
+    use std::sync::Arc;
+
+    #[test]
+    fn builds_model_inputs_and_reads_the_results_back() {
+        let prompt = CaptionTask::Prompt("Caption this".into());
+        let params = caption_inference_params();
+        let input = model_input(ModelFamily::Anthropic, &prompt, &params, "aGk=", "png");
+        assert_eq!(input["system"], "Caption this");
+        assert_eq!(
+            input["messages"][0]["content"][0]["source"]["media_type"],
+            "image/png"
+        );
+        assert!(input.get("tools").is_none());
+
+        let claude = r#"{"recordId":"IMG00000001","modelInput":{},"modelOutput":{"content":[{"type":"text","text":" A red square "}],"stop_reason":"end_turn"}}"#;
+        let (id, result) = parse_output_record(claude, &prompt).unwrap();
+        assert_eq!(id, "IMG00000001");
+        assert_eq!(result.unwrap().0, "A red square");
+        let failed = r#"{"recordId":"IMG00000002","modelInput":{},"error":{"errorCode":400,"errorMessage":"Bad image"}}"#;
+        assert_eq!(
+            parse_output_record(failed, &prompt).unwrap().1,
+            Err("Bad image".to_string())
+        );
+        assert!(parse_output_record("{\"recordId\":", &prompt).is_none());
+
+        let profile = CaptionProfile::default();
+        let schema = OutputSchema::new(&crate::captioner::profile_schema(&profile)).unwrap();
+        let task = CaptionTask::Profile(profile, Arc::new(schema));
+        let input = model_input(ModelFamily::Nova, &task, &params, "aGk=", "jpeg");
+        assert_eq!(
+            input["messages"][0]["content"][0]["image"]["format"],
+            "jpeg"
+        );
+        assert_eq!(
+            input["toolConfig"]["tools"][0]["toolSpec"]["name"],
+            OUTPUT_TOOL
+        );
+
+        let nova = r#"{"recordId":"IMG00000003","modelOutput":{"output":{"message":{"role":"assistant","content":[{"toolUse":{"toolUseId":"1","name":"respond","input":{"alt_text":"A cat","description":"A cat on a mat.","tags":["cat"],"text":"","sensitive":false}}}]}},"stopReason":"tool_use"}}"#;
+        let (caption, fields) = parse_output_record(nova, &task).unwrap().1.unwrap();
+        assert_eq!(caption, "A cat");
+        assert_eq!(fields["tags"], FieldValue::List(vec!["cat".into()]));
+        let incomplete = r#"{"recordId":"IMG00000004","modelOutput":{"content":[{"type":"tool_use","name":"respond","input":{"alt_text":"A cat"}}]}}"#;
+        assert!(parse_output_record(incomplete, &task)
+            .unwrap()
+            .1
+            .unwrap_err()
+            .starts_with("The fields do not match the profile"));
+    }
+}
@@ -0,0 +1,487 @@
+use std::collections::BTreeSet;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use anyhow::anyhow;
+use chrono::Local;
+use exif::experimental::Writer;
+use exif::{Field, In, Tag, Value};
+use handlebars::Handlebars;
+use img_parts::jpeg::{markers, JpegSegment};
+use img_parts::png::PngChunk;
+use img_parts::{Bytes, DynImage, ImageEXIF};
+use quick_xml::se;
+use regex::Regex;
+use serde_json::json;
+
+use crate::captioner::Image;
+use crate::constants;
+use crate::utils::{print_warning, SidecarFormat};
+
+// NOTE:
+// Where the captions end up. There is always one file with all of them (JSON, XML, CSV or an HTML
+// gallery, picked by the extension of `--output`), and optionally a sidecar file next to every
+// image (`--sidecar`) and the caption written into the image itself (`--embed`), as the EXIF
+// ImageDescription and the XMP dc:description - where most publishing tools look for alt text.
+
+pub enum OutputFormat {
+    Json,
+    Xml,
+    Csv,
+    Html,
+}
+
+impl OutputFormat {
+    pub fn from_path(p: &Path) -> Result<Self, anyhow::Error> {
+        let extension = p
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        match extension.as_deref() {
+            Some("json") => Ok(OutputFormat::Json),
+            Some("xml") => Ok(OutputFormat::Xml),
+            Some("csv") => Ok(OutputFormat::Csv),
+            Some("html") | Some("htm") => Ok(OutputFormat::Html),
+            _ => Err(anyhow!(
+                "🔴 | Can not tell the format of {}, use a .json, .xml, .csv or .html file",
+                p.display()
+            )),
+        }
+    }
+}
+
+// Everything that gets written once the images are captioned
+pub struct CaptionOutput {
+    pub path: PathBuf,
+    pub format: OutputFormat,
+    pub sidecar: Option<SidecarFormat>,
+    pub embed: bool,
+    // where to write the manifest (source URI -> captions), a path or an `s3://` URI
+    pub manifest: Option<String>,
+}
+
+impl CaptionOutput {
+    pub fn new(
+        output: Option<PathBuf>,
+        xml: bool,
+        sidecar: Option<SidecarFormat>,
+        embed: bool,
+        manifest: Option<String>,
+    ) -> Result<Self, anyhow::Error> {
+        // NOTE: `-x` only changes the default file, the extension of `--output` wins
+        let path = output
+            .unwrap_or_else(|| PathBuf::from(if xml { "captions.xml" } else { "captions.json" }));
+        Ok(CaptionOutput {
+            format: OutputFormat::from_path(&path)?,
+            path,
+            sidecar,
+            embed,
+            manifest,
+        })
+    }
+
+    pub fn write(&self, images: &[Image]) -> Result<(), anyhow::Error> {
+        write_captions(images, &self.format, &self.path)?;
+        // NOTE: downloaded images only have a copy in the cache, there is no point writing to it
+        let downloaded = images.iter().filter(|i| i.source.is_some()).count();
+        if downloaded > 0 && (self.sidecar.is_some() || self.embed) {
+            print_warning(&format!(
+                "{} downloaded image(s) get no sidecar files or embedded captions, find them in the manifest",
+                downloaded
+            ));
+        }
+        let images: Vec<&Image> = images.iter().filter(|i| i.source.is_none()).collect();
+        if let Some(sidecar) = self.sidecar {
+            let written = write_sidecars(images.iter().copied(), sidecar)?;
+            println!(
+                "📝 | Wrote {} .{} file(s) next to the images",
+                written,
+                sidecar.extension()
+            );
+        }
+        if self.embed {
+            let mut embedded = 0;
+            for image in &images {
+                let Some(caption) = &image.caption else {
+                    continue;
+                };
+                match embed_caption(&image.path, caption) {
+                    Ok(()) => embedded += 1,
+                    Err(e) => print_warning(&format!(
+                        "Could not write the caption into {}: {}",
+                        image.path.display(),
+                        e
+                    )),
+                }
+            }
+            println!("📝 | Wrote the caption into {} image(s)", embedded);
+        }
+        Ok(())
+    }
+}
+
+pub fn write_captions(
+    i: &[Image],
+    format: &OutputFormat,
+    filename: &Path,
+) -> Result<(), anyhow::Error> {
+    if let Some(parent) = filename.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    match format {
+        OutputFormat::Json => {
+            let mut json_file = std::fs::File::create(filename)?;
+            let json_serialized = serde_json::to_string_pretty(&i)?;
+            json_file.write_all(json_serialized.as_bytes())?;
+        }
+        OutputFormat::Xml => {
+            let mut xml_file = std::fs::File::create(filename)?;
+            let xmled = se::to_string_with_root("captions", &i)?;
+            xml_file.write_all(xmled.as_bytes())?;
+        }
+        OutputFormat::Csv => {
+            // the fields of a caption profile get a column each, lists are joined with `; `
+            let fields: BTreeSet<&String> = i.iter().flat_map(|i| i.fields.keys()).collect();
+            let mut writer = csv::Writer::from_path(filename)?;
+            writer.write_record(
+                ["path", "caption", "error"]
+                    .into_iter()
+                    .chain(fields.iter().map(|f| f.as_str())),
+            )?;
+            for image in i {
+                // the source of a downloaded image, not its file in the cache
+                let mut record = vec![
+                    image.uri(),
+                    image.caption.clone().unwrap_or_default(),
+                    image.error.clone().unwrap_or_default(),
+                ];
+                record.extend(fields.iter().map(|f| {
+                    image
+                        .fields
+                        .get(*f)
+                        .map(|v| v.to_string())
+                        .unwrap_or_default()
+                }));
+                writer.write_record(record)?;
+            }
+            writer.flush()?;
+        }
+        OutputFormat::Html => fs::write(filename, render_gallery(i, filename)?)?,
+    }
+
+    Ok(())
+}
+
+// The thumbnails link to the images, relative to the gallery when they are next to (or under) it
+fn render_gallery(i: &[Image], filename: &Path) -> Result<String, anyhow::Error> {
+    let gallery_dir = filename
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+        .canonicalize()?;
+    let images: Vec<_> = i
+        .iter()
+        .map(|image| {
+            let absolute = image
+                .path
+                .canonicalize()
+                .unwrap_or_else(|_| image.path.clone());
+            let src = match (&image.source, absolute.strip_prefix(&gallery_dir)) {
+                (Some(url), _) if url.starts_with("http") => url.clone(),
+                (_, Ok(relative)) => relative.to_string_lossy().replace('\\', "/"),
+                (_, Err(_)) => format!("file://{}", absolute.to_string_lossy()),
+            };
+            json!({
+                "path": image.uri(),
+                "src": src,
+                "caption": image.caption,
+                "error": image.error,
+            })
+        })
+        .collect();
+    let title = filename
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let handlebars = Handlebars::new();
+    Ok(handlebars.render_template(
+        constants::CAPTION_GALLERY_TEMPLATE,
+        &json!({
+            "title": title,
+            "generated": Local::now().format("%Y-%m-%d %H:%M").to_string(),
+            "images": images,
+        }),
+    )?)
+}
+
+// photo.jpg -> photo.txt (or photo.alt)
+pub fn write_sidecars<'a>(
+    i: impl IntoIterator<Item = &'a Image>,
+    format: SidecarFormat,
+) -> Result<usize, anyhow::Error> {
+    let mut written = BTreeSet::new();
+    for image in i {
+        if let Some(caption) = &image.caption {
+            let sidecar = image.path.with_extension(format.extension());
+            // photo.jpg and photo.png would both get photo.txt, the first one keeps it
+            if written.contains(&sidecar) {
+                print_warning(&format!(
+                    "Not writing {} for {}, it already holds the caption of another image",
+                    sidecar.display(),
+                    image.path.display()
+                ));
+                continue;
+            }
+            fs::write(&sidecar, format!("{}\n", caption.trim()))?;
+            written.insert(sidecar);
+        }
+    }
+    Ok(written.len())
+}
+
+// ######################################## IMAGE METADATA
+// JPEG and PNG get both the EXIF and the XMP description, WebP only the EXIF one. The other EXIF
+// fields and XMP properties of the image are kept.
+
+const XMP_JPEG_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_PNG_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+const PNG_ITXT: [u8; 4] = *b"iTXt";
+
+pub fn embed_caption(path: &Path, caption: &str) -> Result<(), anyhow::Error> {
+    let bytes = Bytes::from(fs::read(path)?);
+    let mut image = DynImage::from_bytes(bytes)?
+        .ok_or_else(|| anyhow!("only JPEG, PNG and WebP images can hold a caption"))?;
+
+    let exif = exif_with_description(image.exif(), caption)?;
+    image.set_exif(Some(Bytes::from(exif)));
+    match &mut image {
+        DynImage::Jpeg(jpeg) => {
+            let segments = jpeg.segments_mut();
+            let existing = segments.iter().position(|s| {
+                s.marker() == markers::APP1 && s.contents().starts_with(XMP_JPEG_PREFIX)
+            });
+            let xmp = existing.map(|n| {
+                let segment = segments.remove(n);
+                String::from_utf8_lossy(&segment.contents()[XMP_JPEG_PREFIX.len()..]).to_string()
+            });
+            let mut contents = XMP_JPEG_PREFIX.to_vec();
+            contents.extend(xmp_with_description(xmp.as_deref(), caption).as_bytes());
+            // right after the other APPn segments (the EXIF one is among them)
+            let at = segments
+                .iter()
+                .position(|s| !(markers::APP0..=markers::APP15).contains(&s.marker()))
+                .unwrap_or(segments.len());
+            segments.insert(
+                at,
+                JpegSegment::new_with_contents(markers::APP1, Bytes::from(contents)),
+            );
+        }
+        DynImage::Png(png) => {
+            let chunks = png.chunks_mut();
+            let existing = chunks
+                .iter()
+                .position(|c| c.kind() == PNG_ITXT && c.contents().starts_with(XMP_PNG_KEYWORD));
+            // keyword, null, no compression, compression method, no language, no translation
+            let header_len = XMP_PNG_KEYWORD.len() + 5;
+            let xmp = existing.map(|n| {
+                let chunk = chunks.remove(n);
+                String::from_utf8_lossy(chunk.contents().get(header_len..).unwrap_or_default())
+                    .to_string()
+            });
+            let mut contents = XMP_PNG_KEYWORD.to_vec();
+            contents.extend([0, 0, 0, 0, 0]);
+            contents.extend(xmp_with_description(xmp.as_deref(), caption).as_bytes());
+            // before IEND
+            let at = chunks.len().saturating_sub(1);
+            chunks.insert(at, PngChunk::new(PNG_ITXT, Bytes::from(contents)));
+        }
+        DynImage::WebP(_) => {}
+    }
+
+    // written next to the image first, so a failure does not leave a broken image behind
+    let temporary = path.with_extension("bedrust-tmp");
+    image
+        .encoder()
+        .write_to(io::BufWriter::new(fs::File::create(&temporary)?))?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+// The EXIF of the image, with the ImageDescription set to the caption. The thumbnail is dropped.
+fn exif_with_description(existing: Option<Bytes>, caption: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let existing = existing.and_then(|b| exif::Reader::new().read_raw(b.to_vec()).ok());
+    let description = Field {
+        tag: Tag::ImageDescription,
+        ifd_num: In::PRIMARY,
+        value: Value::Ascii(vec![caption.as_bytes().to_vec()]),
+    };
+    let mut writer = Writer::new();
+    let mut little_endian = false;
+    if let Some(exif) = &existing {
+        little_endian = exif.little_endian();
+        for field in exif.fields() {
+            if field.ifd_num == In::PRIMARY && field.tag != Tag::ImageDescription {
+                writer.push_field(field);
+            }
+        }
+    }
+    writer.push_field(&description);
+    let mut out = Cursor::new(Vec::new());
+    writer
+        .write(&mut out, little_endian)
+        .map_err(|e| anyhow!("Could not write the EXIF data: {}", e))?;
+    Ok(out.into_inner())
+}
+
+// The XMP packet of the image with dc:description set to the caption, or a new packet
+fn xmp_with_description(existing: Option<&str>, caption: &str) -> String {
+    let description = format!(
+        r#"<dc:description><rdf:Alt><rdf:li xml:lang="x-default">{}</rdf:li></rdf:Alt></dc:description>"#,
+        html_escape::encode_text(caption)
+    );
+    if let Some(xmp) = existing {
+        let existing_description =
+            Regex::new(r"(?s)<dc:description\b.*?</dc:description>|<dc:description\s*/>").unwrap();
+        if existing_description.is_match(xmp) {
+            return existing_description
+                .replace(xmp, regex::NoExpand(&description))
+                .to_string();
+        }
+        if let Some(at) = xmp.find("</rdf:RDF>") {
+            return format!(
+                r#"{}<rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/">{}</rdf:Description>{}"#,
+                &xmp[..at],
+                description,
+                &xmp[at..]
+            );
+        }
+    }
+    format!(
+        r#"<?xpacket begin="{}" id="W5M0MpCehiHzreSzNTczkc9d"?><x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/">{}</rdf:Description></rdf:RDF></x:xmpmeta><?xpacket end="w"?>"#,
+        '\u{feff}', description
+    )
+}
+// ######################################## END IMAGE METADATA
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::captioner::FieldValue;
+    use crate::utils::TempDir;
+    use image::{Rgb, RgbImage};
+    use std::collections::BTreeMap;
+
+    // red.jpg and red.png, both with the same caption
+    fn red_squares(dir: &Path) -> Vec<Image> {
+        ["red.jpg", "red.png"]
+            .iter()
+            .map(|name| {
+                let path = dir.join(name);
+                RgbImage::from_pixel(8, 8, Rgb([255, 0, 0]))
+                    .save(&path)
+                    .unwrap();
+                let mut image = Image::from_path(&path).unwrap();
+                image.caption = Some("A red square, \"tiny\" & bright".into());
+                image
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sidecars_that_would_share_a_name_are_written_once() {
+        let dir = TempDir::new("bedrust-output").unwrap();
+        let images = red_squares(dir.path());
+
+        // red.jpg and red.png would share red.alt, only the first one writes it
+        assert_eq!(write_sidecars(&images, SidecarFormat::Alt).unwrap(), 1);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("red.alt")).unwrap(),
+            "A red square, \"tiny\" & bright\n"
+        );
+    }
+
+    #[test]
+    fn csv_lists_downloaded_images_by_their_source() {
+        let dir = TempDir::new("bedrust-output").unwrap();
+        let mut images = red_squares(dir.path());
+        images[1].source = Some("https://example.com/red.png".into());
+
+        let csv = dir.path().join("out/captions.csv");
+        write_captions(&images, &OutputFormat::Csv, &csv).unwrap();
+        let csv = fs::read_to_string(csv).unwrap();
+        assert!(csv.starts_with("path,caption,error\n"));
+        assert!(csv.contains("\nhttps://example.com/red.png,"));
+        assert!(csv.contains(r#""A red square, ""tiny"" & bright","#));
+    }
+
+    #[test]
+    fn profile_fields_are_written_in_every_format() {
+        let dir = TempDir::new("bedrust-output").unwrap();
+        let mut images = red_squares(dir.path());
+        images[1].fields = BTreeMap::from([
+            ("sensitive".to_string(), FieldValue::Flag(false)),
+            (
+                "tags".to_string(),
+                FieldValue::List(vec!["red".into(), "square".into()]),
+            ),
+        ]);
+
+        let csv = dir.path().join("fields.csv");
+        write_captions(&images, &OutputFormat::Csv, &csv).unwrap();
+        let csv = fs::read_to_string(csv).unwrap();
+        assert!(csv.starts_with("path,caption,error,sensitive,tags\n"));
+        assert!(csv.contains(",,false,red; square\n"));
+        let xml = dir.path().join("fields.xml");
+        write_captions(&images, &OutputFormat::Xml, &xml).unwrap();
+        let xml = fs::read_to_string(xml).unwrap();
+        assert!(xml.contains(
+            "<fields><sensitive>false</sensitive><tags>red</tags><tags>square</tags></fields>"
+        ));
+        let json = dir.path().join("fields.json");
+        write_captions(&images, &OutputFormat::Json, &json).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(json).unwrap()).unwrap();
+        assert_eq!(json[1]["fields"]["tags"], json!(["red", "square"]));
+        assert!(json[0].get("fields").is_none());
+    }
+
+    #[test]
+    fn gallery_escapes_the_captions() {
+        let dir = TempDir::new("bedrust-output").unwrap();
+        let images = red_squares(dir.path());
+
+        let gallery = dir.path().join("gallery.html");
+        write_captions(&images, &OutputFormat::Html, &gallery).unwrap();
+        let gallery = fs::read_to_string(gallery).unwrap();
+        assert!(gallery.contains("2 image(s)"));
+        assert!(gallery
+            .contains(r#"<img src="red.jpg" alt="A red square, &quot;tiny&quot; &amp; bright""#));
+    }
+
+    #[test]
+    fn captions_are_embedded_in_the_image_metadata() {
+        let dir = TempDir::new("bedrust-output").unwrap();
+        for image in red_squares(dir.path()) {
+            let path = &image.path;
+            embed_caption(path, "A red square").unwrap();
+            // twice, the second time replaces what the first wrote
+            embed_caption(path, "A red & white square").unwrap();
+            let bytes = Bytes::from(fs::read(path).unwrap());
+            let image = DynImage::from_bytes(bytes.clone()).unwrap().unwrap();
+            let exif = exif::Reader::new()
+                .read_raw(image.exif().unwrap().to_vec())
+                .unwrap();
+            let description = exif.get_field(Tag::ImageDescription, In::PRIMARY).unwrap();
+            assert_eq!(
+                description.display_value().to_string(),
+                "\"A red & white square\""
+            );
+            let text = String::from_utf8_lossy(&bytes);
+            assert_eq!(text.matches("<dc:description>").count(), 1);
+            assert!(text.contains("A red &amp; white square</rdf:li>"));
+            // still an image
+            image::load_from_memory(&bytes).unwrap();
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use aws_sdk_s3::primitives::ByteStream;
+use serde::Serialize;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::captioner::{FieldValue, Image};
+use crate::code::hash_contents;
+use crate::utils::{print_warning, CaptionConfig};
+
+// NOTE:
+// Images that are not on the disk: `s3://bucket/prefix` (every image under the prefix), `https://`
+// URLs, and `.txt` files listing them (one per line, local paths work too). They are downloaded to
+// a cache directory before captioning - the cached file is what the captioner sees, so interrupted
+// runs resume without downloading again - and the captions are written to a manifest keyed by the
+// URI the image came from.
+
+pub fn is_remote(uri: &str) -> bool {
+    uri.starts_with("s3://") || uri.starts_with("https://") || uri.starts_with("http://")
+}
+
+// A list of images to caption, rather than an image
+pub fn is_uri_list(input: &Path) -> bool {
+    input.is_file()
+        && input
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("txt"))
+}
+
+// The S3 client used for the images, talks to `caption.s3_endpoint` instead of AWS when it is set
+// (MinIO, LocalStack and the like)
+pub fn s3_client(sdk_config: &aws_types::SdkConfig, config: &CaptionConfig) -> aws_sdk_s3::Client {
+    let mut builder = aws_sdk_s3::config::Builder::from(sdk_config);
+    if let Some(endpoint) = &config.s3_endpoint {
+        builder = builder.endpoint_url(endpoint).force_path_style(true);
+    }
+    aws_sdk_s3::Client::from_conf(builder.build())
+}
+
+pub(crate) fn split_s3_uri(uri: &str) -> Result<(&str, &str), anyhow::Error> {
+    let rest = uri
+        .strip_prefix("s3://")
+        .ok_or_else(|| anyhow!("{} is not an S3 URI", uri))?;
+    let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+    if bucket.is_empty() {
+        return Err(anyhow!("{} has no bucket", uri));
+    }
+    Ok((bucket, key))
+}
+
+#[derive(Clone)]
+pub struct RemoteImages {
+    s3: aws_sdk_s3::Client,
+    http: reqwest::Client,
+    // where the downloaded images are kept
+    cache_dir: PathBuf,
+}
+
+impl RemoteImages {
+    pub fn new(s3: aws_sdk_s3::Client, cache_dir: PathBuf) -> Self {
+        RemoteImages {
+            s3,
+            http: reqwest::Client::new(),
+            cache_dir,
+        }
+    }
+
+    // ~/.cache/bedrust/images
+    pub fn default_cache_dir() -> PathBuf {
+        dirs::cache_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("bedrust/images")
+    }
+
+    // Every image URI (or local path, from a list file) the input stands for
+    pub async fn expand(&self, input: &str, ext: &[String]) -> Result<Vec<String>, anyhow::Error> {
+        let lines = if is_remote(input) {
+            vec![input.to_string()]
+        } else {
+            fs::read_to_string(input)
+                .map_err(|e| anyhow!("🔴 | Could not read {}: {}", input, e))?
+                .lines()
+                .map(|l| l.trim())
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(String::from)
+                .collect()
+        };
+        let mut uris = Vec::new();
+        for line in lines {
+            if line.starts_with("s3://") {
+                uris.extend(self.list_s3(&line, ext).await?);
+            } else {
+                uris.push(line);
+            }
+        }
+        Ok(uris)
+    }
+
+    // The images under an S3 prefix (or the object itself), filtered by extension
+    async fn list_s3(&self, uri: &str, ext: &[String]) -> Result<Vec<String>, anyhow::Error> {
+        let (bucket, prefix) = split_s3_uri(uri)?;
+        let mut pages = self
+            .s3
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .into_paginator()
+            .send();
+        let mut uris = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(|e| {
+                anyhow!(
+                    "🔴 | Could not list {}: {}",
+                    uri,
+                    aws_sdk_s3::error::DisplayErrorContext(e)
+                )
+            })?;
+            for object in page.contents() {
+                let Some(key) = object.key() else {
+                    continue;
+                };
+                let supported = Path::new(key)
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .is_some_and(|e| ext.iter().any(|x| x.eq_ignore_ascii_case(e)));
+                if supported {
+                    uris.push(format!("s3://{}/{}", bucket, key));
+                }
+            }
+        }
+        Ok(uris)
+    }
+
+    // The same URI always ends up in the same file: <hash of the URI>-<file name>
+    pub fn cache_path(&self, uri: &str) -> PathBuf {
+        let without_query = uri.split(['?', '#']).next().unwrap_or(uri);
+        let name: String = without_query
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        // the captioner needs an extension, the actual format is sniffed anyway
+        let name = if Path::new(&name).extension().is_some() {
+            name
+        } else {
+            format!("{}.img", name)
+        };
+        let hash = hash_contents(uri);
+        self.cache_dir.join(format!("{}-{}", &hash[..16], name))
+    }
+
+    // Downloads the image unless it is in the cache already, returns where it is
+    pub async fn download(&self, uri: &str) -> Result<PathBuf, anyhow::Error> {
+        let path = self.cache_path(uri);
+        if path.is_file() {
+            return Ok(path);
+        }
+        let bytes = if uri.starts_with("s3://") {
+            let (bucket, key) = split_s3_uri(uri)?;
+            let object = self
+                .s3
+                .get_object()
+                .bucket(bucket)
+                .key(key)
+                .send()
+                .await
+                .map_err(|e| anyhow!("{}", e.into_service_error()))?;
+            object.body.collect().await?.into_bytes().to_vec()
+        } else {
+            let response = self.http.get(uri).send().await?.error_for_status()?;
+            response.bytes().await?.to_vec()
+        };
+        fs::create_dir_all(&self.cache_dir)?;
+        // written next to it and renamed, so an interrupted download never looks complete
+        let partial = path.with_extension("part");
+        fs::write(&partial, bytes)?;
+        fs::rename(&partial, &path)?;
+        Ok(path)
+    }
+
+    // The images to caption, downloaded `concurrency` at a time. The images that are already
+    // captioned (`done`) are not downloaded again, the ones that fail to download get an error.
+    pub async fn images(
+        &self,
+        uris: Vec<String>,
+        done: impl Fn(&Path) -> bool,
+        concurrency: usize,
+    ) -> Result<Vec<Image>, anyhow::Error> {
+        let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+        let mut tasks = JoinSet::new();
+        for (n, uri) in uris.iter().enumerate() {
+            if !is_remote(uri) || done(&self.cache_path(uri)) {
+                continue;
+            }
+            let remote = self.clone();
+            let semaphore = semaphore.clone();
+            let uri = uri.clone();
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                (n, remote.download(&uri).await)
+            });
+        }
+        let mut failed = BTreeMap::new();
+        while let Some(result) = tasks.join_next().await {
+            let (n, result) = result?;
+            if let Err(e) = result {
+                failed.insert(n, format!("Could not download the image: {}", e));
+            }
+        }
+
+        let mut images = Vec::new();
+        for (n, uri) in uris.into_iter().enumerate() {
+            if !is_remote(&uri) {
+                // a local file in a list
+                let path = PathBuf::from(&uri);
+                if !path.is_file() {
+                    print_warning(&format!("Skipping {}, the file does not exist", uri));
+                } else if path.extension().is_none() {
+                    print_warning(&format!("Skipping {}, it has no extension", uri));
+                } else {
+                    images.push(Image::from_path(&path)?);
+                }
+                continue;
+            }
+            let mut image = Image::from_path(&self.cache_path(&uri))?;
+            image.error = failed.remove(&n);
+            image.source = Some(uri);
+            images.push(image);
+        }
+        Ok(images)
+    }
+
+    // The manifest goes to a local file, or to S3
+    pub async fn write_manifest(&self, images: &[Image], to: &str) -> Result<(), anyhow::Error> {
+        let manifest = serde_json::to_string_pretty(&manifest(images))?;
+        if to.starts_with("s3://") {
+            let (bucket, key) = split_s3_uri(to)?;
+            self.s3
+                .put_object()
+                .bucket(bucket)
+                .key(key)
+                .content_type("application/json")
+                .body(ByteStream::from(manifest.into_bytes()))
+                .send()
+                .await
+                .map_err(|e| {
+                    anyhow!(
+                        "🔴 | Could not upload the manifest to {}: {}",
+                        to,
+                        aws_sdk_s3::error::DisplayErrorContext(e)
+                    )
+                })?;
+        } else {
+            let path = Path::new(to);
+            if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, manifest)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct ManifestEntry<'a> {
+    caption: &'a Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    fields: &'a BTreeMap<String, FieldValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: &'a Option<String>,
+}
+
+// source URI -> its captions
+fn manifest(images: &[Image]) -> BTreeMap<String, ManifestEntry<'_>> {
+    images
+        .iter()
+        .map(|image| {
+            let source = image
+                .source
+                .clone()
+                .unwrap_or_else(|| image.path.to_string_lossy().to_string());
+            let entry = ManifestEntry {
+                caption: &image.caption,
+                fields: &image.fields,
+                error: &image.error,
+            };
+            (source, entry)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+    use aws_sdk_s3::config::{Credentials, Region};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    // Just enough of S3 (path style ListObjectsV2 and GetObject) and a web server to download from
+    fn stand_in(objects: Vec<(&'static str, Vec<u8>)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let objects = objects.clone();
+                std::thread::spawn(move || {
+                    let stream = stream.unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut writer = stream;
+                    loop {
+                        let mut request = String::new();
+                        if reader.read_line(&mut request).unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let mut length = 0;
+                        loop {
+                            let mut header = String::new();
+                            reader.read_line(&mut header).unwrap();
+                            if header.trim().is_empty() {
+                                break;
+                            }
+                            if let Some((name, value)) = header.split_once(':') {
+                                if name.eq_ignore_ascii_case("content-length") {
+                                    length = value.trim().parse().unwrap();
+                                }
+                            }
+                        }
+                        let mut body = vec![0; length];
+                        reader.read_exact(&mut body).unwrap();
+
+                        let target = request.split(' ').nth(1).unwrap().to_string();
+                        let (path, _) = target.split_once('?').unwrap_or((&target, ""));
+                        let (status, content) = if target.contains("list-type=2") {
+                            let contents: String = objects
+                                .iter()
+                                .map(|(key, bytes)| {
+                                    format!(
+                                        "<Contents><Key>{}</Key><Size>{}</Size></Contents>",
+                                        key,
+                                        bytes.len()
+                                    )
+                                })
+                                .collect();
+                            let xml = format!(
+                                "<?xml version=\"1.0\" encoding=\"UTF-8\"?><ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\"><Name>photos</Name><KeyCount>{}</KeyCount><MaxKeys>1000</MaxKeys><IsTruncated>false</IsTruncated>{}</ListBucketResult>",
+                                objects.len(),
+                                contents
+                            );
+                            ("200 OK", xml.into_bytes())
+                        } else {
+                            let key = path.trim_start_matches("/photos/");
+                            match objects.iter().find(|(k, _)| *k == key) {
+                                Some((_, bytes)) => ("200 OK", bytes.clone()),
+                                None => (
+                                    "404 Not Found",
+                                    b"<Error><Code>NoSuchKey</Code></Error>".to_vec(),
+                                ),
+                            }
+                        };
+                        write!(
+                            writer,
+                            "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n",
+                            status,
+                            content.len()
+                        )
+                        .unwrap();
+                        writer.write_all(&content).unwrap();
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    // a client for the stand-in, caching into the given directory
+    fn remote_images(address: &str, cache_dir: &Path) -> RemoteImages {
+        let config = aws_sdk_s3::Config::builder()
+            .behavior_version_latest()
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+            .endpoint_url(address)
+            .force_path_style(true)
+            .build();
+        RemoteImages::new(
+            aws_sdk_s3::Client::from_conf(config),
+            cache_dir.to_path_buf(),
+        )
+    }
+
+    fn photos() -> Vec<(&'static str, Vec<u8>)> {
+        vec![
+            ("2024/cat.JPG", b"cat".to_vec()),
+            ("2024/notes.txt", b"notes".to_vec()),
+        ]
+    }
+
+    #[tokio::test]
+    async fn lists_the_images_under_an_s3_prefix() {
+        let address = stand_in(photos());
+        let dir = TempDir::new("bedrust-remote").unwrap();
+        let remote = remote_images(&address, &dir.path().join("cache"));
+
+        let ext = vec!["jpg".to_string()];
+        let uris = remote.expand("s3://photos/2024/", &ext).await.unwrap();
+        assert_eq!(uris, vec!["s3://photos/2024/cat.JPG"]);
+    }
+
+    #[tokio::test]
+    async fn lists_the_images_in_a_file() {
+        let address = stand_in(photos());
+        let dir = TempDir::new("bedrust-remote").unwrap();
+        let remote = remote_images(&address, &dir.path().join("cache"));
+
+        let list = dir.path().join("images.txt");
+        // a local file without an extension is skipped, not the end of the run
+        let no_extension = dir.path().join("cat");
+        fs::write(&no_extension, b"cat").unwrap();
+        fs::write(
+            &list,
+            format!(
+                "# images\ns3://photos/2024/\n\n{}/photos/2024/dog.jpg\n{}\n",
+                address,
+                no_extension.display()
+            ),
+        )
+        .unwrap();
+        let ext = vec!["jpg".to_string()];
+        let uris = remote.expand(list.to_str().unwrap(), &ext).await.unwrap();
+        assert_eq!(uris.len(), 3);
+        assert_eq!(uris[0], "s3://photos/2024/cat.JPG");
+    }
+
+    #[tokio::test]
+    async fn downloads_the_images_and_maps_them_back_to_their_source() {
+        let address = stand_in(photos());
+        let dir = TempDir::new("bedrust-remote").unwrap();
+        let cache_dir = dir.path().join("cache");
+        let remote = remote_images(&address, &cache_dir);
+
+        let missing = format!("{}/photos/2024/dog.jpg", address);
+        let uris = vec!["s3://photos/2024/cat.JPG".to_string(), missing.clone()];
+        let mut images = remote.images(uris, |_| false, 2).await.unwrap();
+        assert_eq!(images.len(), 2);
+        assert_eq!(fs::read(&images[0].path).unwrap(), b"cat");
+        assert!(images[0].path.starts_with(&cache_dir));
+        assert!(images[0].error.is_none());
+        assert!(images[1].error.as_ref().unwrap().contains("404"));
+
+        images[0].caption = Some("A cat".into());
+        let manifest_path = dir.path().join("manifest.json");
+        remote
+            .write_manifest(&images, manifest_path.to_str().unwrap())
+            .await
+            .unwrap();
+        let manifest: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&manifest_path).unwrap()).unwrap();
+        assert_eq!(manifest["s3://photos/2024/cat.JPG"]["caption"], "A cat");
+        assert!(manifest[&missing]["error"].is_string());
+    }
+
+    #[test]
+    fn a_query_string_does_not_end_up_in_the_file_name() {
+        let dir = TempDir::new("bedrust-remote").unwrap();
+        let remote = remote_images("http://127.0.0.1:9", dir.path());
+        assert!(remote
+            .cache_path("https://example.com/a/b.png?size=large")
+            .to_string_lossy()
+            .ends_with("-b.png"));
+    }
+}
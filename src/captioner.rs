@@ -1,55 +1,172 @@
-use std::io::Write;
-use std::str::FromStr;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Cursor;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Arc;
 use std::{fs, io::Read, path::PathBuf};
 
 use anyhow::anyhow;
 use aws_sdk_bedrockruntime::primitives::Blob;
 use aws_sdk_bedrockruntime::types::{
-    ContentBlock, ImageBlock, ImageFormat, ImageSource, InferenceConfiguration, SystemContentBlock,
+    ContentBlock, ImageBlock, ImageFormat, ImageSource, SystemContentBlock,
 };
 
+use ignore::overrides::OverrideBuilder;
+use ignore::WalkBuilder;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageReader};
 use indicatif::{ProgressBar, ProgressStyle};
-use quick_xml::se;
-use serde::Serialize;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 
+use crate::caption_batch::CaptionBatch;
+use crate::caption_output::CaptionOutput;
+use crate::caption_source::{is_remote, is_uri_list, RemoteImages};
 use crate::models::check_model_features;
-use crate::models::converse::call_converse;
+use crate::models::converse::{call_converse, BedrockConverseError};
+use crate::models::structured::{call_structured, OutputSchema};
 use crate::models::ModelFeatures;
-use crate::utils::BedrustConfig;
+use crate::utils::{
+    print_warning, BedrustConfig, CaptionConfig, CaptionFieldKind, CaptionProfile, GuardrailConfig,
+    InferenceParams,
+};
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Image {
     pub path: PathBuf,
-    #[serde(skip_serializing)]
+    #[serde(skip)]
     pub extension: String,
     // FIX: Think about setting the base64 as optional
-    #[serde(skip_serializing)]
+    #[serde(skip)]
     //pub base64: String,
     pub base64: Vec<u8>,
     pub caption: Option<String>,
+    // why the image could not be captioned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // the fields extracted by a caption profile (`--profile`)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, FieldValue>,
+    // where the image was downloaded from (`s3://` or `https://`), `path` is the downloaded copy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+// The value of a caption profile field, as it comes back from the model
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FieldValue {
+    Flag(bool),
+    List(Vec<String>),
+    Text(String),
 }
 
-pub enum OutputFormat {
-    Json,
-    Xml,
+impl std::fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldValue::Flag(b) => write!(f, "{}", b),
+            FieldValue::List(l) => write!(f, "{}", l.join("; ")),
+            FieldValue::Text(t) => write!(f, "{}", t),
+        }
+    }
+}
+
+// What the model is asked about every image
+#[derive(Clone)]
+pub enum CaptionTask {
+    // a single caption, answered as text
+    Prompt(String),
+    // the fields of a profile, answered as structured output
+    Profile(CaptionProfile, Arc<OutputSchema>),
+}
+
+impl CaptionTask {
+    pub fn from_config(config: &BedrustConfig) -> Result<Self, anyhow::Error> {
+        let Some(name) = &config.caption.profile else {
+            return Ok(CaptionTask::Prompt(config.caption_prompt.clone()));
+        };
+        let profile = config.caption.profiles.get(name).ok_or_else(|| {
+            let known: Vec<&String> = config.caption.profiles.keys().collect();
+            anyhow!(
+                "🔴 | There is no caption profile named `{}`, the configured profiles are: {:?}",
+                name,
+                known
+            )
+        })?;
+        match profile.fields.first() {
+            Some(field) if field.kind == CaptionFieldKind::Text => {}
+            _ => {
+                return Err(anyhow!(
+                    "🔴 | The first field of the `{}` caption profile has to be a Text field, it is also the caption of the image",
+                    name
+                ))
+            }
+        }
+        let schema = OutputSchema::new(&profile_schema(profile))?;
+        Ok(CaptionTask::Profile(profile.clone(), Arc::new(schema)))
+    }
+}
+
+// Every field of the profile is required, and nothing else is allowed
+pub(crate) fn profile_schema(profile: &CaptionProfile) -> Value {
+    let properties: serde_json::Map<String, Value> = profile
+        .fields
+        .iter()
+        .map(|field| {
+            let schema = match field.kind {
+                CaptionFieldKind::Text => json!({ "type": "string" }),
+                CaptionFieldKind::List => json!({ "type": "array", "items": { "type": "string" } }),
+                CaptionFieldKind::Flag => json!({ "type": "boolean" }),
+            };
+            let mut schema = schema;
+            schema["description"] = json!(field.description);
+            (field.name.clone(), schema)
+        })
+        .collect();
+    let required: Vec<&String> = profile.fields.iter().map(|f| &f.name).collect();
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false,
+    })
 }
 
 impl Image {
     pub fn new(p: &PathBuf) -> Result<Self, anyhow::Error> {
+        let mut image = Self::from_path(p)?;
+        image.base64 = load_image(p)?;
+        Ok(image)
+    }
+
+    // An image that is only loaded from the disk when it gets captioned - so a run over thousands
+    // of images does not keep all of them in memory
+    pub fn from_path(p: &Path) -> Result<Self, anyhow::Error> {
         let extension = &p
             .extension()
             .and_then(|ext| ext.to_str())
             .ok_or_else(|| anyhow!("The file provided has no extension, this is an issue."))?;
-        let img_base64 = load_image(p)?;
 
-        // FIX: Clone happens - see if we should remove that
-        let image = Self {
-            path: p.clone(),
+        Ok(Self {
+            path: p.to_path_buf(),
             extension: extension.to_string(),
-            base64: img_base64,
+            base64: Vec::new(),
             caption: None,
-        };
-        Ok(image)
+            error: None,
+            fields: BTreeMap::new(),
+            source: None,
+        })
+    }
+
+    // the URI of a downloaded image, the path of a local one
+    pub fn uri(&self) -> String {
+        self.source
+            .clone()
+            .unwrap_or_else(|| self.path.display().to_string())
     }
 }
 
@@ -60,9 +177,10 @@ pub async fn caption_process(
     model_id: &str,
     bedrock_client: &aws_sdk_bedrock::Client,
     bedrockruntime_client: &aws_sdk_bedrockruntime::Client,
+    s3_client: &aws_sdk_s3::Client,
     images_path: Option<PathBuf>,
     bedrust_config: &BedrustConfig,
-    xml: bool,
+    output: &CaptionOutput,
 ) -> Result<(), anyhow::Error> {
     match check_model_features(model_id, bedrock_client, ModelFeatures::Images).await {
         Ok(b) => {
@@ -71,46 +189,133 @@ pub async fn caption_process(
                     println!("----------------------------------------");
                     println!("🖼️ | Image captioner running.");
                     let path = images_path.ok_or_else(|| anyhow!("No path specified"))?;
-                    println!("⌛ | Processing images in: {:?}", &path);
-                    let files = list_files_in_path_by_extension(
-                        path,
-                        bedrust_config.supported_images.clone(),
-                    )?;
-                    println!("🔎 | Found {:?} images in path.", &files.len());
-
-                    let mut images: Vec<Image> = Vec::new();
-                    for file in &files {
-                        images.push(Image::new(file)?);
+                    let input = path.to_string_lossy().to_string();
+                    let remote = is_remote(&input) || is_uri_list(&path);
+                    if path == Path::new("-") {
+                        println!("⌛ | Reading the list of images from stdin");
+                    } else {
+                        println!("⌛ | Processing images in: {}", input);
+                    }
+
+                    // every caption is written to the journal as soon as we have it, so an
+                    // interrupted run picks up where it stopped
+                    let task = CaptionTask::from_config(bedrust_config)?;
+                    if let CaptionTask::Profile(profile, _) = &task {
+                        let names: Vec<&str> =
+                            profile.fields.iter().map(|f| f.name.as_str()).collect();
+                        println!("📋 | Extracting the fields: {}", names.join(", "));
                     }
+                    let mut journal = CaptionJournal::open(&progress_path(&output.path))?;
+                    // an image captioned without the profile still needs its fields
+                    let profiled = matches!(task, CaptionTask::Profile(..));
+                    let finished = |path: &Path| {
+                        journal
+                            .finished(path)
+                            .filter(|done| !profiled || !done.fields.is_empty())
+                    };
 
-                    caption_image(
-                        &mut images,
-                        model_id,
-                        &bedrust_config.caption_prompt,
-                        bedrockruntime_client,
-                        bedrock_client,
-                    )
-                    .await?;
-
-                    // NOTE: This is parsing the `-x` argument and then writing or not, an XML file
-                    // Thanks StellyUK <3
-
-                    // FIX: This whole if else statement does not look nice.
-                    // i feel it can be better. As doing the whole logic
-                    // behind an expression seems ... weird
-                    let outfile = if xml {
-                        let outfile = "captions.xml";
-                        write_captions(images, OutputFormat::Xml, outfile)?;
-                        outfile
+                    let remote_images =
+                        RemoteImages::new(s3_client.clone(), RemoteImages::default_cache_dir());
+                    let mut images: Vec<Image> = if remote {
+                        let uris = remote_images
+                            .expand(&input, &bedrust_config.supported_images)
+                            .await?;
+                        println!("🔎 | Found {} images, downloading them", uris.len());
+                        remote_images
+                            .images(
+                                uris,
+                                |p| finished(p).is_some(),
+                                bedrust_config.caption.concurrency,
+                            )
+                            .await?
                     } else {
-                        let outfile = "captions.json";
-                        write_captions(images, OutputFormat::Json, outfile)?;
-                        outfile
+                        let files = find_images(
+                            &path,
+                            &bedrust_config.supported_images,
+                            &bedrust_config.caption,
+                        )?;
+                        println!("🔎 | Found {:?} images in path.", &files.len());
+                        files
+                            .iter()
+                            .map(|f| Image::from_path(f))
+                            .collect::<Result<_, _>>()?
                     };
+                    for image in images.iter_mut() {
+                        if let Some(done) = finished(&image.path) {
+                            image.caption = done.caption.clone();
+                            image.fields = done.fields.clone();
+                        }
+                    }
+                    let done = images.iter().filter(|i| i.caption.is_some()).count();
+                    if done > 0 {
+                        println!(
+                            "⏭️  | Skipping {} image(s) captioned by an earlier run (delete `{}` to caption them again)",
+                            done,
+                            journal.path.display()
+                        );
+                    }
+
+                    if bedrust_config.caption.batch.enabled {
+                        // the model inputs of a batch job are the native request bodies, which
+                        // have no place for a guardrail
+                        if bedrust_config.guardrail.is_enabled() {
+                            return Err(anyhow!(
+                                "🔴 | Batch jobs can not apply the guardrail, caption without --batch or remove the `guardrail` section"
+                            ));
+                        }
+                        let batch = CaptionBatch {
+                            bedrock_client,
+                            s3_client,
+                            model_id,
+                            config: &bedrust_config.caption,
+                            state_path: output.path.with_extension("batch.json"),
+                        };
+                        batch.run(&mut images, &task, &mut journal).await?;
+                    } else {
+                        caption_image(
+                            &mut images,
+                            model_id,
+                            &task,
+                            &bedrust_config.guardrail,
+                            bedrockruntime_client,
+                            &bedrust_config.caption,
+                            Some(&mut journal),
+                        )
+                        .await?;
+                    }
+
+                    let mut failed = 0;
+                    for image in &images {
+                        if let Some(error) = &image.error {
+                            eprintln!("🔴 | {}: {}", image.uri(), error);
+                            failed += 1;
+                        }
+                    }
+                    output.write(&images)?;
+                    // downloaded images are listed by where they came from
+                    let manifest = output.manifest.clone().or_else(|| {
+                        remote.then(|| {
+                            output
+                                .path
+                                .with_extension("manifest.json")
+                                .to_string_lossy()
+                                .to_string()
+                        })
+                    });
+                    if let Some(manifest) = manifest {
+                        remote_images.write_manifest(&images, &manifest).await?;
+                        println!("📝 | Wrote the manifest to `{}`", manifest);
+                    }
                     println!(
                         "✅ | Captioning complete, find the generated captions in `{}`",
-                        outfile
+                        output.path.display()
                     );
+                    if failed > 0 {
+                        println!(
+                            "🔴 | {} image(s) could not be captioned, run the same command again to retry them",
+                            failed
+                        );
+                    }
                     println!("----------------------------------------");
                 }
                 false => {
@@ -124,26 +329,124 @@ pub async fn caption_process(
     Ok(())
 }
 
+// The images to caption - every supported image in a directory, the images matching a glob, or
+// `-` for a list of files (one per line) on stdin
+pub fn find_images(
+    input: &Path,
+    ext: &[String],
+    config: &CaptionConfig,
+) -> Result<Vec<PathBuf>, anyhow::Error> {
+    if input == Path::new("-") {
+        let mut files = Vec::new();
+        for line in io::stdin().lock().lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let path = PathBuf::from(line.trim());
+            if !path.is_file() {
+                print_warning(&format!(
+                    "Skipping {}, the file does not exist",
+                    path.display()
+                ));
+                continue;
+            }
+            match has_supported_extension(&path, ext) {
+                Some(true) => files.push(path),
+                Some(false) => print_warning(&format!(
+                    "Skipping {}, it is not a supported image",
+                    path.display()
+                )),
+                None => print_warning(&format!("Skipping {}, it has no extension", path.display())),
+            }
+        }
+        return Ok(files);
+    }
+    if input.is_file() {
+        return Ok(vec![input.to_path_buf()]);
+    }
+    if input.is_dir() {
+        return list_files_in_path_by_extension(input.to_path_buf(), ext.to_vec(), config);
+    }
+    // a glob: walk the directory before the first component with a wildcard
+    let pattern = input.to_string_lossy();
+    if !pattern.contains(['*', '?', '[', '{']) {
+        return Err(anyhow!("🔴 | {} does not exist", input.display()));
+    }
+    let mut base = PathBuf::new();
+    let mut glob = Vec::new();
+    for component in input.components() {
+        let part = component.as_os_str().to_string_lossy();
+        if glob.is_empty() && !part.contains(['*', '?', '[', '{']) {
+            base.push(component);
+        } else {
+            glob.push(part.to_string());
+        }
+    }
+    let base = if base.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        base
+    };
+    let mut config = config.clone();
+    config.include.push(glob.join("/"));
+    // a glob says how deep to look on its own
+    config.recursive = true;
+    list_files_in_path_by_extension(base, ext.to_vec(), &config)
+}
+
+// Every image with one of the `ext` extensions (in any case) in a directory, and its
+// subdirectories if `config.recursive` is set (or an --include glob looks into them). Hidden files are skipped, and so are files without
+// an extension (with a warning).
 pub fn list_files_in_path_by_extension(
     p: PathBuf,
     ext: Vec<String>,
+    config: &CaptionConfig,
 ) -> Result<Vec<PathBuf>, anyhow::Error> {
-    let entries = fs::read_dir(p)?;
+    let mut filters = OverrideBuilder::new(&p);
+    filters.case_insensitive(true)?;
+    for glob in &config.include {
+        filters.add(glob)?;
+    }
+    for glob in &config.exclude {
+        filters.add(&format!("!{}", glob))?;
+    }
+
+    // a glob like `products/**` says how deep to look on its own
+    let recursive = config.recursive || config.include.iter().any(|g| g.contains('/'));
+    // NOTE: unlike code chat, we do not follow .gitignore files - images are often ignored by git
+    let walker = WalkBuilder::new(&p)
+        .standard_filters(false)
+        .hidden(true)
+        .max_depth(if recursive { None } else { Some(1) })
+        .overrides(filters.build()?)
+        .build();
     let mut files = Vec::new();
-    for entry in entries {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        let extension = path
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .ok_or_else(|| anyhow!("There was no files with extensions in this directory"))?;
-        if ext.contains(&extension.to_string()) {
-            files.push(path);
+    for entry in walker {
+        let entry = entry?;
+        if !entry.file_type().is_some_and(|ft| ft.is_file()) {
+            continue;
+        }
+        match has_supported_extension(entry.path(), &ext) {
+            Some(true) => files.push(entry.into_path()),
+            Some(false) => {}
+            None => print_warning(&format!(
+                "Skipping {}, it has no extension",
+                entry.path().display()
+            )),
         }
     }
+    // the walk order depends on the file system, we want the same order every time
+    files.sort();
     Ok(files)
 }
 
+// None if the file has no extension
+fn has_supported_extension(path: &Path, ext: &[String]) -> Option<bool> {
+    let extension = path.extension()?.to_string_lossy();
+    Some(ext.iter().any(|e| e.eq_ignore_ascii_case(&extension)))
+}
+
 //pub fn load_image(p: &PathBuf) -> Result<String, anyhow::Error> {
 pub fn load_image(p: &PathBuf) -> Result<Vec<u8>, anyhow::Error> {
     let mut file = fs::File::open(p)?;
@@ -153,25 +456,26 @@ pub fn load_image(p: &PathBuf) -> Result<Vec<u8>, anyhow::Error> {
     Ok(buffer)
 }
 
+// Captions every image that does not have a caption yet, `config.concurrency` at a time. An image
+// that fails gets its `error` set instead of stopping the run. Throttled requests are retried with
+// an exponential backoff.
 pub async fn caption_image(
-    i: &mut Vec<crate::captioner::Image>,
+    i: &mut [crate::captioner::Image],
     model: &str,
-    prompt: &str,
+    task: &CaptionTask,
+    guardrail: &GuardrailConfig,
     runtime_client: &aws_sdk_bedrockruntime::Client,
-    _bedrock_client: &aws_sdk_bedrock::Client,
+    config: &CaptionConfig,
+    mut journal: Option<&mut CaptionJournal>,
 ) -> Result<(), anyhow::Error> {
-    // Needs to be hardcoded for images
-    let inference_parameters: InferenceConfiguration = InferenceConfiguration::builder()
-        .max_tokens(2048)
-        .top_p(0.8)
-        .temperature(0.5)
-        .build();
-
-    // FIX: Remove the clone
-    let system_prompt = Some(vec![SystemContentBlock::Text(prompt.to_owned())]);
+    let inference_parameters = caption_inference_params();
 
+    // images that failed before getting here (e.g. could not be downloaded) are skipped too
+    let pending: Vec<usize> = (0..i.len())
+        .filter(|&n| i[n].caption.is_none() && i[n].error.is_none())
+        .collect();
     // progress bar shenanigans
-    let progress_bar = ProgressBar::new(i.len().try_into()?);
+    let progress_bar = ProgressBar::new(pending.len().try_into()?);
     progress_bar.set_style(
         ProgressStyle::with_template(
             "{spinner:.green} [{wide_bar:.cyan/blue}] {msg} ({pos}/{len})",
@@ -181,80 +485,297 @@ pub async fn caption_image(
     );
     progress_bar.enable_steady_tick(std::time::Duration::from_millis(100));
 
-    for image in i {
-        let message = image.path.as_path().display().to_string();
-
-        //let imagesrc: ImageSource = ImageSource::Bytes(image.base64.to_blob());
-        let imagesrc: ImageSource = ImageSource::Bytes(
-            // FIX: Try to remove the clone
-            Blob::new(image.base64.clone()),
-        );
+    let semaphore = Arc::new(Semaphore::new(config.concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    for n in pending {
+        // the image goes into the task, and comes back with its caption (or error)
+        let mut image = Image {
+            path: i[n].path.clone(),
+            extension: std::mem::take(&mut i[n].extension),
+            base64: std::mem::take(&mut i[n].base64),
+            caption: None,
+            error: None,
+            fields: BTreeMap::new(),
+            source: i[n].source.clone(),
+        };
+        let semaphore = semaphore.clone();
+        let client = runtime_client.clone();
+        let model = model.to_string();
+        let guardrail = guardrail.clone();
+        let inference_parameters = inference_parameters.clone();
+        let task = task.clone();
+        let config = config.clone();
+        let progress_bar = progress_bar.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            progress_bar.set_message(image.path.display().to_string());
+            let result = caption_one(
+                &mut image,
+                &client,
+                &model,
+                &inference_parameters,
+                &guardrail,
+                &task,
+                &config,
+            )
+            .await;
+            match result {
+                Ok((caption, fields)) => {
+                    image.caption = Some(caption);
+                    image.fields = fields;
+                }
+                Err(e) => image.error = Some(e.to_string()),
+            }
+            // no need to keep the image in memory once it is captioned
+            image.base64 = Vec::new();
+            (n, image)
+        });
+    }
 
-        let image_block = ImageBlock::builder()
-            .source(imagesrc)
-            .format(ImageFormat::from_str(image.extension.as_str())?)
-            .build()?;
-
-        let content = ContentBlock::Image(image_block);
-        progress_bar.set_message(message);
-        // let caption = ask_bedrock(
-        //     prompt,
-        //     Some(image),
-        //     model,
-        //     RunType::Captioning,
-        //     runtime_client,
-        //     bedrock_client,
-        // )
-        // .await?;
-        let caption = call_converse(
-            runtime_client,
-            model.to_string(),
-            // FIX: Avoid the clone
-            inference_parameters.clone(),
-            content,
-            // FIX: Avoid the clone
-            system_prompt.clone(),
-            false,
-        )
-        .await?;
+    while let Some(result) = tasks.join_next().await {
+        let (n, image) = result?;
+        if let Some(journal) = journal.as_mut() {
+            journal.record(&image)?;
+        }
+        i[n] = image;
         progress_bar.inc(1);
-        image.caption = Some(caption);
     }
     progress_bar.finish();
 
     Ok(())
 }
 
-pub fn write_captions(
-    i: Vec<crate::captioner::Image>,
-    format: OutputFormat,
-    filename: &str,
-) -> Result<(), anyhow::Error> {
-    match format {
-        OutputFormat::Json => {
-            let mut json_file = std::fs::File::create(filename).expect("Failed to create file");
-            let json_serialized =
-                serde_json::to_string_pretty(&i).expect("Failed to serialize data");
-            json_file
-                .write_all(json_serialized.as_bytes())
-                .expect("Failed to write to file");
+// Needs to be hardcoded for images
+pub(crate) fn caption_inference_params() -> InferenceParams {
+    InferenceParams {
+        max_tokens: 2048,
+        top_p: 0.8,
+        temperature: 0.5,
+        ..Default::default()
+    }
+}
+
+async fn caption_one(
+    image: &mut Image,
+    client: &aws_sdk_bedrockruntime::Client,
+    model: &str,
+    inference_parameters: &InferenceParams,
+    guardrail: &GuardrailConfig,
+    task: &CaptionTask,
+    config: &CaptionConfig,
+) -> Result<(String, BTreeMap<String, FieldValue>), anyhow::Error> {
+    if image.base64.is_empty() {
+        image.base64 = load_image(&image.path)?;
+    }
+    // decoding and resizing takes a while, and would hold up the other captions
+    let bytes = std::mem::take(&mut image.base64);
+    let max_dimension = config.max_dimension;
+    let (bytes, format) =
+        tokio::task::spawn_blocking(move || prepare_image(bytes, max_dimension)).await??;
+    image.base64 = bytes;
+    let image_block = ImageBlock::builder()
+        .source(ImageSource::Bytes(Blob::new(image.base64.clone())))
+        .format(format)
+        .build()?;
+    let content = ContentBlock::Image(image_block);
+
+    let mut retries = 0;
+    loop {
+        let result = match task {
+            CaptionTask::Prompt(prompt) => call_converse(
+                client,
+                model.to_string(),
+                inference_parameters,
+                guardrail,
+                content.clone(),
+                Some(vec![SystemContentBlock::Text(prompt.clone())]),
+                false,
+            )
+            .await
+            .map(|caption| (caption, BTreeMap::new()))
+            .map_err(anyhow::Error::from),
+            CaptionTask::Profile(profile, schema) => call_structured(
+                client,
+                model,
+                inference_parameters,
+                guardrail,
+                vec![content.clone(), ContentBlock::Text(profile.prompt.clone())],
+                schema,
+                STRUCTURED_ATTEMPTS,
+            )
+            .await
+            .and_then(|value| fields_from_value(profile, value)),
+        };
+        match result {
+            Ok(captioned) => return Ok(captioned),
+            Err(e) if is_retryable(&e) && retries < config.max_retries => {
+                retries += 1;
+                tokio::time::sleep(backoff(retries)).await;
+            }
+            Err(e) => return Err(e),
         }
-        OutputFormat::Xml => {
-            let mut xml_file = std::fs::File::create(filename).expect("Failed to create file");
-            let xmled = se::to_string_with_root("captions", &i).expect("Failed to convert to XML");
-            xml_file
-                .write_all(xmled.as_bytes())
-                .expect("Failed to write to file");
+    }
+}
+
+// how many answers that do not match the schema of the profile we take before giving up on an image
+const STRUCTURED_ATTEMPTS: usize = 3;
+
+fn is_retryable(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<BedrockConverseError>()
+        .is_some_and(|e| e.is_retryable())
+}
+
+// The answer is already validated against the schema of the profile, the caption is its first field
+pub(crate) fn fields_from_value(
+    profile: &CaptionProfile,
+    value: Value,
+) -> Result<(String, BTreeMap<String, FieldValue>), anyhow::Error> {
+    let fields: BTreeMap<String, FieldValue> = serde_json::from_value(value)?;
+    let caption = profile
+        .fields
+        .first()
+        .and_then(|f| fields.get(&f.name))
+        .map(|v| v.to_string())
+        .ok_or_else(|| anyhow!("The answer has no caption"))?;
+    Ok((caption, fields))
+}
+
+// ######################################## IMAGE PREPROCESSING
+// The Converse API takes PNG, JPEG, GIF and WebP images of at most 3.75 MB and 8000x8000 pixels.
+// Anything else is decoded, downscaled and re-encoded, the format is sniffed from the contents
+// (so a PNG named `.jpg` still works).
+const MAX_IMAGE_BYTES: usize = 3_750_000;
+const MAX_IMAGE_DIMENSION: u32 = 8000;
+
+// Returns the image as it gets sent, and its format
+pub fn prepare_image(
+    bytes: Vec<u8>,
+    max_dimension: u32,
+) -> Result<(Vec<u8>, ImageFormat), anyhow::Error> {
+    let max_dimension = max_dimension.clamp(1, MAX_IMAGE_DIMENSION);
+    let detected = image::guess_format(&bytes)
+        .map_err(|_| anyhow!("The file is not an image (or not a format we can read)"))?;
+    let (width, height) = ImageReader::with_format(Cursor::new(&bytes), detected)
+        .into_dimensions()
+        .map_err(|e| anyhow!("Could not read the image: {}", e))?;
+
+    let format = match detected {
+        image::ImageFormat::Png => Some(ImageFormat::Png),
+        image::ImageFormat::Jpeg => Some(ImageFormat::Jpeg),
+        image::ImageFormat::Gif => Some(ImageFormat::Gif),
+        image::ImageFormat::WebP => Some(ImageFormat::Webp),
+        _ => None,
+    };
+    // no need to touch images that are fine as they are
+    if let Some(format) = format {
+        if bytes.len() <= MAX_IMAGE_BYTES && width.max(height) <= max_dimension {
+            return Ok((bytes, format));
         }
     }
 
-    Ok(())
+    let mut decoded = image::load_from_memory_with_format(&bytes, detected)
+        .map_err(|e| anyhow!("Could not decode the image: {}", e))?;
+    let mut limit = max_dimension;
+    loop {
+        if decoded.width().max(decoded.height()) > limit {
+            decoded = decoded.resize(limit, limit, FilterType::Lanczos3);
+        }
+        let encoded = encode_image(&decoded)?;
+        if encoded.0.len() <= MAX_IMAGE_BYTES {
+            return Ok(encoded);
+        }
+        // still too large, try again smaller
+        limit = decoded.width().max(decoded.height()) * 3 / 4;
+    }
+}
+
+// Images with transparency become PNG, everything else JPEG
+fn encode_image(image: &DynamicImage) -> Result<(Vec<u8>, ImageFormat), anyhow::Error> {
+    let mut out = Cursor::new(Vec::new());
+    if image.color().has_alpha() {
+        image.write_to(&mut out, image::ImageFormat::Png)?;
+        Ok((out.into_inner(), ImageFormat::Png))
+    } else {
+        image
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut out, 85))?;
+        Ok((out.into_inner(), ImageFormat::Jpeg))
+    }
+}
+// ######################################## END IMAGE PREPROCESSING
+
+// 2, 4, 8 ... seconds (at most a minute), with some jitter so the waiting tasks do not all retry
+// at the same moment
+fn backoff(retry: u32) -> std::time::Duration {
+    let base = 1000 * 2u64.pow(retry.min(6));
+    let jitter = rand::thread_rng().gen_range(0..=base / 2);
+    std::time::Duration::from_millis((base + jitter).min(60_000))
+}
+
+// Every captioned (or failed) image, one JSON object per line. Appended to as the captions come
+// in, and read on the next run to skip the images that already have a caption.
+pub struct CaptionJournal {
+    pub path: PathBuf,
+    file: fs::File,
+    captions: HashMap<PathBuf, Image>,
+}
+
+impl CaptionJournal {
+    pub fn open(path: &Path) -> Result<Self, anyhow::Error> {
+        let mut captions = HashMap::new();
+        if let Ok(file) = fs::File::open(path) {
+            for line in BufReader::new(file).lines() {
+                // a run that was killed can leave half a line behind
+                let Ok(image) = serde_json::from_str::<Image>(&line?) else {
+                    continue;
+                };
+                if image.caption.is_some() {
+                    captions.insert(image.path.clone(), image);
+                } else {
+                    captions.remove(&image.path);
+                }
+            }
+        }
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow!("Could not open {}: {}", path.display(), e))?;
+        Ok(CaptionJournal {
+            path: path.to_path_buf(),
+            file,
+            captions,
+        })
+    }
+
+    pub fn caption_for(&self, path: &Path) -> Option<&String> {
+        self.captions.get(path).and_then(|i| i.caption.as_ref())
+    }
+
+    // the captioned image, with its fields
+    pub fn finished(&self, path: &Path) -> Option<&Image> {
+        self.captions.get(path)
+    }
+
+    pub fn record(&mut self, image: &Image) -> Result<(), anyhow::Error> {
+        writeln!(self.file, "{}", serde_json::to_string(image)?)?;
+        self.file.flush()?;
+        if image.caption.is_some() {
+            self.captions.insert(image.path.clone(), image.clone());
+        }
+        Ok(())
+    }
+}
+
+// captions.json -> captions.progress.jsonl
+fn progress_path(outfile: &Path) -> PathBuf {
+    outfile.with_extension("progress.jsonl")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::utils::load_bedrust_config;
+    use crate::utils::{load_bedrust_config, TempDir};
     use base64::{engine::general_purpose, Engine as _};
     use image::{Rgb, RgbImage};
     use rand::distributions::{Alphanumeric, DistString};
@@ -285,12 +806,15 @@ mod tests {
         // load supported file extensions
         let config = load_bedrust_config().unwrap();
 
-        let list =
-            list_files_in_path_by_extension(PathBuf::from(dir_path), config.supported_images);
+        let list = list_files_in_path_by_extension(
+            PathBuf::from(dir_path),
+            config.supported_images,
+            &config.caption,
+        );
         let expected_vec = vec![
-            PathBuf::from(&file1_path),
             PathBuf::from(&file3_path),
             PathBuf::from(&file4_path),
+            PathBuf::from(&file1_path),
             PathBuf::from(&file5_path),
         ];
         assert_eq!(expected_vec, list.unwrap());
@@ -322,4 +846,196 @@ mod tests {
         // Clean up: remove the test image file
         fs::remove_file(image_path).unwrap();
     }
+
+    #[test]
+    fn journal_skips_captioned_images_on_the_next_run() {
+        let dir = TempDir::new("bedrust-captions").unwrap();
+        let path = dir.path().join("captions.progress.jsonl");
+        let image = |name: &str, caption: Option<&str>, error: Option<&str>| Image {
+            path: PathBuf::from(name),
+            extension: "png".into(),
+            base64: Vec::new(),
+            caption: caption.map(|c| c.into()),
+            error: error.map(|e| e.into()),
+            fields: BTreeMap::new(),
+            source: None,
+        };
+        let mut journal = CaptionJournal::open(&path).unwrap();
+        journal
+            .record(&image("a.png", Some("A cat"), None))
+            .unwrap();
+        journal
+            .record(&image("b.png", None, Some("throttled")))
+            .unwrap();
+        drop(journal);
+        // an interrupted write
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"path\":\"c.p")
+            .unwrap();
+
+        let journal = CaptionJournal::open(&path).unwrap();
+        assert_eq!(
+            journal.caption_for(Path::new("a.png")),
+            Some(&"A cat".to_string())
+        );
+        assert_eq!(journal.caption_for(Path::new("b.png")), None);
+    }
+
+    #[test]
+    fn profile_fields_are_required_and_typed() {
+        let profile = CaptionProfile::default();
+        let schema = profile_schema(&profile);
+        assert_eq!(schema["required"].as_array().unwrap().len(), 5);
+        assert_eq!(schema["properties"]["tags"]["type"], "array");
+        assert_eq!(schema["properties"]["sensitive"]["type"], "boolean");
+        OutputSchema::new(&schema).unwrap();
+
+        let (caption, fields) = fields_from_value(
+            &profile,
+            json!({
+                "alt_text": "A red bicycle",
+                "description": "A red bicycle leaning on a brick wall.",
+                "tags": ["bicycle", "red"],
+                "text": "",
+                "sensitive": false
+            }),
+        )
+        .unwrap();
+        assert_eq!(caption, "A red bicycle");
+        assert_eq!(
+            fields["tags"],
+            FieldValue::List(vec!["bicycle".into(), "red".into()])
+        );
+        assert_eq!(fields["sensitive"], FieldValue::Flag(false));
+        assert_eq!(fields["tags"].to_string(), "bicycle; red");
+    }
+
+    // a catalogue with images in any case, a few files that are not images, and hidden thumbnails
+    fn product_photos() -> TempDir {
+        let dir = TempDir::new("bedrust-images").unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("products/shoes")).unwrap();
+        fs::create_dir_all(root.join(".thumbnails")).unwrap();
+        for file in [
+            "cover.PNG",
+            "README",
+            "products/shoes/red.JPG",
+            "products/shoes/blue.jpeg",
+            "products/notes.txt",
+            ".thumbnails/red.jpg",
+        ] {
+            fs::File::create(root.join(file)).unwrap();
+        }
+        dir
+    }
+
+    fn image_extensions() -> Vec<String> {
+        vec!["jpg".to_string(), "jpeg".to_string(), "png".to_string()]
+    }
+
+    #[test]
+    fn finds_only_the_top_of_a_directory_by_default() {
+        let dir = product_photos();
+        let root = dir.path();
+        let found = find_images(root, &image_extensions(), &CaptionConfig::default()).unwrap();
+        assert_eq!(found, vec![root.join("cover.PNG")]);
+    }
+
+    #[test]
+    fn finds_images_recursively_in_any_case() {
+        let dir = product_photos();
+        let root = dir.path();
+        let config = CaptionConfig {
+            recursive: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            find_images(root, &image_extensions(), &config).unwrap(),
+            vec![
+                root.join("cover.PNG"),
+                root.join("products/shoes/blue.jpeg"),
+                root.join("products/shoes/red.JPG"),
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_images_by_glob() {
+        let dir = product_photos();
+        let root = dir.path();
+        let config = CaptionConfig {
+            recursive: true,
+            ..Default::default()
+        };
+        let found = find_images(
+            &root.join("products/**/*.jpg"),
+            &image_extensions(),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(found, vec![root.join("products/shoes/red.JPG")]);
+    }
+
+    #[test]
+    fn include_and_exclude_patterns_narrow_the_images_down() {
+        let dir = product_photos();
+        let root = dir.path();
+        // a pattern with a directory in it looks below the top
+        let including = CaptionConfig {
+            include: vec!["products/**".into()],
+            ..Default::default()
+        };
+        assert_eq!(
+            find_images(root, &image_extensions(), &including)
+                .unwrap()
+                .len(),
+            2
+        );
+        let excluding = CaptionConfig {
+            exclude: vec!["**/red.*".into()],
+            recursive: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            find_images(root, &image_extensions(), &excluding)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn images_are_sniffed_downscaled_and_converted() {
+        let encode = |img: &RgbImage, format: image::ImageFormat| {
+            let mut out = Cursor::new(Vec::new());
+            img.write_to(&mut out, format).unwrap();
+            out.into_inner()
+        };
+        let small = RgbImage::new(32, 16);
+
+        // a PNG that fits is sent as it is, whatever its name says
+        let png = encode(&small, image::ImageFormat::Png);
+        let (bytes, format) = prepare_image(png.clone(), 1568).unwrap();
+        assert_eq!((bytes, format), (png, ImageFormat::Png));
+
+        // BMP is not supported by Converse
+        let bmp = encode(&small, image::ImageFormat::Bmp);
+        let (bytes, format) = prepare_image(bmp, 1568).unwrap();
+        assert_eq!(format, ImageFormat::Jpeg);
+        assert_eq!(
+            image::guess_format(&bytes).unwrap(),
+            image::ImageFormat::Jpeg
+        );
+
+        // too large, downscaled keeping the aspect ratio
+        let large = encode(&RgbImage::new(400, 200), image::ImageFormat::Png);
+        let (bytes, _) = prepare_image(large, 100).unwrap();
+        let resized = image::load_from_memory(&bytes).unwrap();
+        assert_eq!((resized.width(), resized.height()), (100, 50));
+
+        assert!(prepare_image(b"not an image".to_vec(), 1568).is_err());
+    }
 }
@@ -1,21 +1,29 @@
 use crate::models::converse::call_converse;
+use crate::models::{cache_point, HelperModel, HelperModels, HelperTask};
 use anyhow::anyhow;
-use aws_sdk_bedrockruntime::types::{ContentBlock, ConversationRole, Message};
+use aws_sdk_bedrockruntime::types::{
+    ContentBlock, ConversationRole, GuardrailAssessment, GuardrailTraceAssessment, Message,
+    ReasoningContentBlock,
+};
 use dialoguer::Confirm;
 
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 
-use crate::utils::print_warning;
+use crate::utils::{print_warning, ExportConfig, InferenceParams};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::{
     fmt::Display,
     fs,
     io::{self, Write},
+    path::{Path, PathBuf},
 };
 
 use regex::Regex;
 
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+
 use handlebars::{
     Handlebars,
     // Helper,
@@ -48,6 +56,125 @@ use crate::constants;
 pub struct SerializableMessage {
     pub role: String,
     pub content: Vec<String>,
+    // the reasoning (thinking) of the model, stored apart from the content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<String>,
+    // what the guardrail did with this response (only when a guardrail is configured)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guardrail: Option<GuardrailOutcome>,
+    // a cache point goes after this content block (e.g. the source code in code chat), on the
+    // models that support prompt caching
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_point: Option<usize>,
+}
+
+// The result of running a turn through the guardrail, kept in the history for auditing
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GuardrailOutcome {
+    pub intervened: bool,
+    pub action_reason: Option<String>,
+    // the guardrail trace (assessments of the input and output), in the JSON shape of the
+    // Bedrock API. Chats saved by older versions have a text dump here
+    pub trace: Option<serde_json::Value>,
+}
+
+impl GuardrailOutcome {
+    pub fn new(intervened: bool, trace: Option<&GuardrailTraceAssessment>) -> Self {
+        GuardrailOutcome {
+            intervened,
+            action_reason: trace.and_then(|t| t.action_reason()).map(String::from),
+            trace: trace.map(guardrail_trace_json),
+        }
+    }
+}
+
+// The policies that matched, for the input (by guardrail id) and for every output
+fn guardrail_trace_json(trace: &GuardrailTraceAssessment) -> serde_json::Value {
+    let input = trace
+        .input_assessment()
+        .into_iter()
+        .flatten()
+        .map(|(id, a)| (id.clone(), guardrail_assessment_json(a)))
+        .collect::<serde_json::Map<_, _>>();
+    let outputs = trace
+        .output_assessments()
+        .into_iter()
+        .flatten()
+        .map(|(id, a)| {
+            let assessments = a.iter().map(guardrail_assessment_json).collect();
+            (id.clone(), serde_json::Value::Array(assessments))
+        })
+        .collect::<serde_json::Map<_, _>>();
+    json!({
+        "actionReason": trace.action_reason(),
+        "modelOutput": trace.model_output(),
+        "inputAssessment": input,
+        "outputAssessments": outputs,
+    })
+}
+
+fn guardrail_assessment_json(a: &GuardrailAssessment) -> serde_json::Value {
+    let mut policies = serde_json::Map::new();
+    if let Some(p) = a.topic_policy() {
+        let topics: Vec<_> = p
+            .topics()
+            .iter()
+            .map(|t| json!({"name": t.name(), "type": t.r#type().as_str(), "action": t.action().as_str(), "detected": t.detected()}))
+            .collect();
+        policies.insert("topicPolicy".into(), json!({ "topics": topics }));
+    }
+    if let Some(p) = a.content_policy() {
+        let filters: Vec<_> = p
+            .filters()
+            .iter()
+            .map(|f| json!({"type": f.r#type().as_str(), "confidence": f.confidence().as_str(), "filterStrength": f.filter_strength().map(|s| s.as_str()), "action": f.action().as_str(), "detected": f.detected()}))
+            .collect();
+        policies.insert("contentPolicy".into(), json!({ "filters": filters }));
+    }
+    if let Some(p) = a.word_policy() {
+        let custom: Vec<_> = p
+            .custom_words()
+            .iter()
+            .map(|w| json!({"match": w.r#match(), "action": w.action().as_str(), "detected": w.detected()}))
+            .collect();
+        let managed: Vec<_> = p
+            .managed_word_lists()
+            .iter()
+            .map(|w| json!({"match": w.r#match(), "type": w.r#type().as_str(), "action": w.action().as_str(), "detected": w.detected()}))
+            .collect();
+        policies.insert(
+            "wordPolicy".into(),
+            json!({ "customWords": custom, "managedWordLists": managed }),
+        );
+    }
+    if let Some(p) = a.sensitive_information_policy() {
+        let pii: Vec<_> = p
+            .pii_entities()
+            .iter()
+            .map(|e| json!({"match": e.r#match(), "type": e.r#type().as_str(), "action": e.action().as_str(), "detected": e.detected()}))
+            .collect();
+        let regexes: Vec<_> = p
+            .regexes()
+            .iter()
+            .map(|r| json!({"name": r.name(), "match": r.r#match(), "regex": r.regex(), "action": r.action().as_str(), "detected": r.detected()}))
+            .collect();
+        policies.insert(
+            "sensitiveInformationPolicy".into(),
+            json!({ "piiEntities": pii, "regexes": regexes }),
+        );
+    }
+    if let Some(p) = a.contextual_grounding_policy() {
+        let filters: Vec<_> = p
+            .filters()
+            .iter()
+            .map(|f| json!({"type": f.r#type().as_str(), "threshold": f.threshold(), "score": f.score(), "action": f.action().as_str(), "detected": f.detected()}))
+            .collect();
+        policies.insert(
+            "contextualGroundingPolicy".into(),
+            json!({ "filters": filters }),
+        );
+    }
+    serde_json::Value::Object(policies)
 }
 
 // Convert Message to SerializableMessage
@@ -57,21 +184,54 @@ impl From<Message> for SerializableMessage {
             role: message.role().as_str().to_string(),
             // Iterating throught the Vec<ContentBlock> of the Message.content()
             // And then storing them all as a vector of Strings. Just for text in this case.
-            content: vec![message
+            content: message
                 .content()
                 .iter()
-                .find_map(|block| {
+                .filter_map(|block| {
                     if let ContentBlock::Text(text) = block {
                         Some(text.to_string())
                     } else {
                         None
                     }
                 })
-                .unwrap()],
+                .collect(),
+            reasoning: message.content().iter().find_map(|block| {
+                if let ContentBlock::ReasoningContent(ReasoningContentBlock::ReasoningText(r)) =
+                    block
+                {
+                    Some(r.text().to_string())
+                } else {
+                    None
+                }
+            }),
+            guardrail: None,
+            cache_point: None,
+        }
+    }
+}
+
+impl SerializableMessage {
+    // Builds the Message with cache points - after the `cache_point` block, and at the very end
+    // if `cache_end` is set (so the conversation so far is cached for the next turn)
+    pub fn to_message_with_cache_points(self, cache_end: bool) -> Message {
+        let last = self.content.len().saturating_sub(1);
+        let mut content = Vec::new();
+        for (i, text) in self.content.into_iter().enumerate() {
+            content.push(ContentBlock::Text(text));
+            if self.cache_point == Some(i) || (cache_end && i == last) {
+                content.push(ContentBlock::CachePoint(cache_point()));
+            }
         }
+        Message::builder()
+            .role(ConversationRole::from(self.role.as_str()))
+            .set_content(Some(content))
+            .build()
+            .unwrap()
     }
 }
+
 // Convert SerializableMessage to Message
+// NOTE: The reasoning is not sent back to the model, it is only kept for the history
 impl From<SerializableMessage> for Message {
     fn from(serializable: SerializableMessage) -> Self {
         // Running the Message::builder pattern to create a brand new message from the
@@ -123,11 +283,18 @@ impl Display for ConversationEntity {
 pub struct Conversation {
     pub role: ConversationEntity,
     pub content: String,
+    pub reasoning: Option<String>,
+    pub guardrail: Option<GuardrailOutcome>,
 }
 
 impl Conversation {
     pub fn new(role: ConversationEntity, content: String) -> Conversation {
-        Conversation { role, content }
+        Conversation {
+            role,
+            content,
+            reasoning: None,
+            guardrail: None,
+        }
     }
 }
 
@@ -144,6 +311,24 @@ pub struct Content {
     pub text: String,
 }
 
+// the random suffix of the saved titles, so two chats about the same thing get their own files
+const TITLE_SUFFIX_LEN: usize = 5;
+
+// The title of a saved chat the way it is shown: rust_lifetimes-Ab3xZ -> Rust Lifetimes
+pub(crate) fn display_title(title: &str) -> String {
+    let title = match title.rsplit_once('-') {
+        Some((name, suffix))
+            if !name.is_empty()
+                && suffix.len() == TITLE_SUFFIX_LEN
+                && suffix.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            name
+        }
+        _ => title,
+    };
+    title.to_case(Case::Title)
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ConversationHistory {
     pub title: Option<String>,
@@ -151,6 +336,9 @@ pub struct ConversationHistory {
     // pub history: Option<String>,
     pub messages: Option<Vec<SerializableMessage>>,
     pub timestamp: String,
+    // the inference parameters in effect when the conversation was saved
+    #[serde(default)]
+    pub inference_params: Option<InferenceParams>,
 }
 
 impl ConversationHistory {
@@ -167,6 +355,7 @@ impl ConversationHistory {
             // history,
             messages,
             timestamp: local.to_string(),
+            inference_params: None,
         }
     }
 
@@ -182,19 +371,41 @@ impl ConversationHistory {
         }
     }
 
-    // Generate HTML from the conversation
-    // TODO: Clean this up and make it dynamic so it saves into a centralized location
-    pub fn save_as_html(&self) -> Result<(), anyhow::Error> {
+    // Generate HTML from the conversation. `path` can be a file or a directory, without it the
+    // export goes to the `dir` of the export configuration (or the current directory)
+    pub fn save_as_html(
+        &self,
+        path: Option<&Path>,
+        config: &ExportConfig,
+    ) -> Result<PathBuf, anyhow::Error> {
+        let render = self.render_html("chat_export.html", constants::HTML_TW_TEMPLATE, config)?;
+        let file = self.export_path(path, config, "html");
+        if let Some(parent) = file.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&file, render)?;
+        Ok(file)
+    }
+
+    // Renders the conversation with a handlebars template - `name` from the template directory,
+    // or the built-in one
+    pub(crate) fn render_html(
+        &self,
+        name: &str,
+        builtin: &str,
+        config: &ExportConfig,
+    ) -> Result<String, anyhow::Error> {
+        let keep_source = config.keep_source;
         let mut handlebars = Handlebars::new();
         // Register a custom helper that handles arrays of strings
         handlebars.register_helper(
             "nl2br_with_code",
             Box::new(
-                |h: &handlebars::Helper,
-                 _: &handlebars::Handlebars,
-                 _: &handlebars::Context,
-                 _: &mut handlebars::RenderContext,
-                 out: &mut dyn handlebars::Output| {
+                move |h: &handlebars::Helper,
+                      _: &handlebars::Handlebars,
+                      _: &handlebars::Context,
+                      _: &mut handlebars::RenderContext,
+                      out: &mut dyn handlebars::Output| {
                     if let Some(value) = h.param(0) {
                         let text = if value.value().is_array() {
                             value
@@ -208,86 +419,15 @@ impl ConversationHistory {
                         } else {
                             value.value().as_str().unwrap_or("").to_string()
                         };
-
-                        // Parse so tha the code block is not really visible during the source code
-                        // shenaningans
-                        // NOTE: Here is, again, the silly edge case
-                        let (p1, p2) = ("<bedrust_be", "gin_source>");
-                        let (p3, p4) = ("</bedrust_en", "d_source>");
-                        let pattern = format!(r"{}{}\s*[\s\S]*?\s*{}{}", 
-                            regex::escape(p1),
-                            regex::escape(p2),
-                            regex::escape(p3),
-                            regex::escape(p4)
-                        );
-                        let source_code_regex = Regex::new(&pattern).unwrap();
-                        // Replace the source code sections with empty string
-                        let text_without_source = source_code_regex.replace_all(&text,
-                            r#"<div class="source-removed"><div class="source-removed-content">ℹ️ <span>The source code has been removed from the export</span></div></div>"#
-                        );
-                        // Check if already wrapped in HTML
-                        if text_without_source.starts_with("<pre><code") && 
-                           text_without_source.ends_with("</code></pre>") {
-                            out.write(&text_without_source)?;
-                            return Ok(());
-                        }
-
-                        // Regex for code blocks with optional language
-                        let mut last_pos = 0;
-                        let mut result = String::new();
-
-                        let code_block_regex = Regex::new(r"```(\w*)\n([\s\S]*?)\n```").unwrap();
-                        let mut positions = Vec::new();
-
-                        // Process each code block match
-                        for cap in code_block_regex.captures_iter(&text_without_source) {
-                            // Add everything before this code block with normal nl2br processing
-                            let start = cap.get(0).unwrap().start();
-                            let end = cap.get(0).unwrap().end();
-                            positions.push((
-                                start,
-                                end,
-                                cap.get(1).unwrap().as_str(),
-                                cap.get(2).unwrap().as_str(),
-                            ));
-                        }
-                        // Test out single code strings
-                        let inline_code_regex = Regex::new(r"`([^`]+)`").unwrap();
-
-                        for (start, end, lang, code) in positions {
-                            // Process any text before this code block, including inline code.
-                            let before_text = &text_without_source[last_pos..start];
-                            // Handle inline code in the text before the code block
-                            let processed_before =
-                                process_inline_code(before_text, &inline_code_regex);
-                            result.push_str(&processed_before.replace("\n", "<br>"));
-
-                            // Add the code block
-                            result.push_str(&format!(
-                                r#"<pre><code class="language-{}">{}</code></pre>"#,
-                                if lang.is_empty() { "plaintext" } else { lang },
-                                html_escape::encode_text(code)
-                            ));
-
-                            last_pos = end;
-                        }
-
-                        if last_pos < text_without_source.len() {
-                            let remaining = &text_without_source[last_pos..];
-                            let processed_remaining =
-                                process_inline_code(remaining, &inline_code_regex);
-                            result.push_str(&processed_remaining.replace("\n", "<br>"));
-                        }
-
-                        out.write(&result)?;
+                        out.write(&message_to_html(&text, keep_source))?;
                     }
                     Ok(())
                 },
             ),
         );
 
-        // Converts titles from hello_to_the_world to Hello To The World
-        // Needs the convert_case crate
+        // Converts titles from hello_to_the_world-Ab3xZ to Hello To The World. The title comes
+        // from the model, and a helper's output is not escaped by handlebars
         handlebars.register_helper(
             "format_title",
             Box::new(
@@ -298,9 +438,7 @@ impl ConversationHistory {
                  out: &mut dyn handlebars::Output| {
                     if let Some(value) = h.param(0) {
                         if let Some(text) = value.value().as_str() {
-                            // Convert from any case to Title Case
-                            let formatted = text.to_case(Case::Title);
-                            out.write(&formatted)?;
+                            out.write(&handlebars::html_escape(&display_title(text)))?;
                         }
                     }
                     Ok(())
@@ -308,27 +446,53 @@ impl ConversationHistory {
             ),
         );
 
-        match handlebars.register_template_string("chat_export", crate::constants::HTML_TW_TEMPLATE)
-        {
-            Ok(_) => {
-                match handlebars.render("chat_export", &self) {
-                    Ok(render) => {
-                        std::fs::write("conversation.html", render)?;
-                        println!("Succesfully saved the conversation to conversation.html");
-                    }
-                    Err(e) => eprintln!(
-                        "Error: Something went wrong with rendering the HTML template: {}",
-                        e
-                    ),
-                };
-            }
-            Err(e) => eprintln!(
-                "Error: Something went wrong with Registering the template: {}",
+        let template = export_template(name, builtin, config)?;
+        handlebars
+            .register_template_string("chat_export", template)
+            .map_err(|e| anyhow!("Something went wrong with registering the template: {}", e))?;
+        handlebars.render("chat_export", &self).map_err(|e| {
+            anyhow!(
+                "Something went wrong with rendering the HTML template: {}",
                 e
-            ),
-        };
+            )
+        })
+    }
 
-        Ok(())
+    // Where an export goes. A directory (or a path ending with `/`) gets the default name:
+    // the title of the chat and the time of the export, e.g. `rust_lifetimes-20241020-153012.html`
+    pub(crate) fn export_path(
+        &self,
+        path: Option<&Path>,
+        config: &ExportConfig,
+        extension: &str,
+    ) -> PathBuf {
+        let default_name = || {
+            let title = self
+                .title
+                .as_deref()
+                .map(|t| {
+                    t.chars()
+                        .map(|c| match c.is_alphanumeric() || c == '-' || c == '_' {
+                            true => c,
+                            false => '_',
+                        })
+                        .collect::<String>()
+                })
+                .filter(|t| !t.is_empty())
+                .unwrap_or_else(|| "conversation".to_string());
+            format!(
+                "{}-{}.{}",
+                title,
+                Local::now().format("%Y%m%d-%H%M%S"),
+                extension
+            )
+        };
+        match path {
+            Some(p) if p.is_dir() || p.to_string_lossy().ends_with('/') => p.join(default_name()),
+            Some(p) if p.extension().is_none() => p.with_extension(extension),
+            Some(p) => p.to_path_buf(),
+            None => config.dir.clone().unwrap_or_default().join(default_name()),
+        }
     }
 
     // Clearing the current chat history - but I feel there is a better way to do this
@@ -339,16 +503,17 @@ impl ConversationHistory {
             summary: None,
             messages: None,
             timestamp: local.to_string(),
+            inference_params: None,
         }
     }
 
     async fn generate_title(
         &self,
         client: &aws_sdk_bedrockruntime::Client,
+        helper: &HelperModel,
     ) -> Result<String, anyhow::Error> {
         let messages_str = &self.to_messages_string();
         let query = constants::CONVERSATION_TITLE_PROMPT.replace("{}", messages_str);
-        let model_id = constants::CONVERSATION_HISTORY_MODEL_ID;
         let content = ContentBlock::Text(query);
         println!("⏳ | Generating a new file name for this conversation... ");
         // === RETRY MECHANISM ===
@@ -357,8 +522,9 @@ impl ConversationHistory {
         while retry_count < max_retries {
             match call_converse(
                 client,
-                model_id.to_string(),
-                constants::CONVERSATION_HISTORY_TITLE_INF_PARAMS.clone(),
+                helper.model_id.clone(),
+                &helper.inference_parameters,
+                &helper.guardrail,
                 content.clone(),
                 None,
                 false,
@@ -370,7 +536,7 @@ impl ConversationHistory {
                     // Generate a random suffix
                     let random_string: String = thread_rng()
                         .sample_iter(Alphanumeric) // These are ASCII u8
-                        .take(5)
+                        .take(TITLE_SUFFIX_LEN)
                         .map(char::from) // Conver the u8 ASCII into chars
                         .collect();
                     let name = format!("{}-{}", response, random_string);
@@ -398,11 +564,11 @@ impl ConversationHistory {
     async fn generate_summary(
         &self,
         client: &aws_sdk_bedrockruntime::Client,
+        helper: &HelperModel,
     ) -> Result<String, anyhow::Error> {
         let messages_str = &self.to_messages_string();
         let query = constants::CONVERSATION_SUMMARY_PROMPT.replace("{}", messages_str);
 
-        let model_id = constants::CONVERSATION_HISTORY_MODEL_ID;
         let content = ContentBlock::Text(query);
         println!("⏳ | Generating a summary for this conversation... ");
         println!();
@@ -412,8 +578,9 @@ impl ConversationHistory {
         while retry_count < max_retries {
             match call_converse(
                 client,
-                model_id.to_string(),
-                constants::CONVERSATION_HISTORY_INF_PARAMS.clone(),
+                helper.model_id.clone(),
+                &helper.inference_parameters,
+                &helper.guardrail,
                 content.clone(),
                 None,
                 false,
@@ -446,6 +613,7 @@ impl ConversationHistory {
 pub async fn save_chat_history(
     filename: Option<&str>,
     client: &aws_sdk_bedrockruntime::Client,
+    helpers: &HelperModels,
     ch: &mut ConversationHistory,
 ) -> Result<String, anyhow::Error> {
     let home_dir = home_dir().expect("Failed to get HOME directory");
@@ -453,7 +621,10 @@ pub async fn save_chat_history(
     fs::create_dir_all(&save_dir)?;
 
     // generate the conversation summary
-    ch.summary = Some(ch.generate_summary(client).await?);
+    ch.summary = Some(
+        ch.generate_summary(client, helpers.get(HelperTask::Summary))
+            .await?,
+    );
 
     // if we pass it Some filename - we keep using that file as history
     let (filename, file_path) = if let Some(existing_filename) = filename {
@@ -462,7 +633,9 @@ pub async fn save_chat_history(
             save_dir.join(existing_filename),
         )
     } else {
-        let title = ch.generate_title(client).await?;
+        let title = ch
+            .generate_title(client, helpers.get(HelperTask::Title))
+            .await?;
         let new_filename = format!("{}.json", title);
         ch.title = Some(title.clone());
         (new_filename.clone(), save_dir.join(&new_filename))
@@ -552,31 +725,313 @@ pub fn list_chat_histories() -> Result<Vec<String>, anyhow::Error> {
     Ok(chat_files)
 }
 
-// Helper function to process inline code - for HTML creation
-fn process_inline_code(text: &str, regex: &Regex) -> String {
-    let mut result = String::new();
+// A template from the template directory of the export configuration, or the built-in one
+pub(crate) fn export_template(
+    name: &str,
+    builtin: &str,
+    config: &ExportConfig,
+) -> Result<String, anyhow::Error> {
+    let custom = config.template_dir().join(name);
+    match custom.is_file() {
+        true => fs::read_to_string(&custom)
+            .map_err(|e| anyhow!("Could not read the template {}: {}", custom.display(), e)),
+        false => Ok(builtin.to_string()),
+    }
+}
+
+// A part of a message: what was written, or the source code sent with it in code chat
+pub(crate) enum MessagePart<'a> {
+    Text(&'a str),
+    Source(&'a str),
+}
+
+pub(crate) fn message_parts(text: &str) -> Vec<MessagePart<'_>> {
+    // Parse so tha the code block is not really visible during the source code
+    // shenaningans
+    // NOTE: Here is, again, the silly edge case
+    let (p1, p2) = ("<bedrust_be", "gin_source>");
+    let (p3, p4) = ("</bedrust_en", "d_source>");
+    let pattern = format!(
+        r"{}{}\s*([\s\S]*?)\s*{}{}",
+        regex::escape(p1),
+        regex::escape(p2),
+        regex::escape(p3),
+        regex::escape(p4)
+    );
+    let source_code_regex = Regex::new(&pattern).unwrap();
+
+    let mut parts = Vec::new();
     let mut last_pos = 0;
+    for cap in source_code_regex.captures_iter(text) {
+        let section = cap.get(0).unwrap();
+        parts.push(MessagePart::Text(&text[last_pos..section.start()]));
+        parts.push(MessagePart::Source(cap.get(1).unwrap().as_str()));
+        last_pos = section.end();
+    }
+    parts.push(MessagePart::Text(&text[last_pos..]));
+    parts
+}
 
-    for cap in regex.captures_iter(text) {
-        let full_match = cap.get(0).unwrap();
-        let code_content = cap.get(1).unwrap();
+// A message of the chat as HTML. The source code sent in code chat is removed, or kept in a
+// collapsed block
+fn message_to_html(text: &str, keep_source: bool) -> String {
+    let mut result = String::new();
+    for part in message_parts(text) {
+        match part {
+            MessagePart::Text(text) => result.push_str(&markdown_to_html(text)),
+            MessagePart::Source(source) if keep_source => result.push_str(&format!(
+                r#"<details class="source-removed"><summary class="source-removed-content">ℹ️ <span>The source code sent to the model</span></summary><pre><code class="language-plaintext">{}</code></pre></details>"#,
+                html_escape::encode_text(source)
+            )),
+            MessagePart::Source(_) => result.push_str(
+                r#"<div class="source-removed"><div class="source-removed-content">ℹ️ <span>The source code has been removed from the export</span></div></div>"#,
+            ),
+        }
+    }
+    result
+}
+
+// A message (CommonMark, with GitHub tables and strikethrough) as HTML. New lines are kept as
+// <br>, like in the terminal, and HTML written by the model is shown as text. The result is
+// sanitized, so links can not run scripts either
+fn markdown_to_html(text: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+    let events = Parser::new_ext(text, options).map(|event| match event {
+        // Prism highlights the blocks by their `language-` class
+        Event::Start(Tag::CodeBlock(kind)) => {
+            let lang = match kind {
+                CodeBlockKind::Fenced(info) => info
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default()
+                    .to_string(),
+                CodeBlockKind::Indented => String::new(),
+            };
+            Event::Html(
+                format!(
+                    r#"<pre><code class="language-{}">"#,
+                    if lang.is_empty() {
+                        "plaintext".to_string()
+                    } else {
+                        html_escape::encode_double_quoted_attribute(&lang).to_string()
+                    }
+                )
+                .into(),
+            )
+        }
+        Event::End(TagEnd::CodeBlock) => Event::Html("</code></pre>".into()),
+        Event::Code(code) => Event::Html(
+            format!(
+                r#"<code class="language-plaintext inline-code px-1 py-0.5 rounded bg-gray-100 text-sm font-mono">{}</code>"#,
+                html_escape::encode_text(&code)
+            )
+            .into(),
+        ),
+        Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+        Event::SoftBreak => Event::HardBreak,
+        event => event,
+    });
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, events);
 
-        // Add text before this inline code
-        result.push_str(&text[last_pos..full_match.start()]);
+    ammonia::Builder::default()
+        .add_tag_attributes("code", &["class"])
+        .clean(&html)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn cache_points_after_source_and_at_the_end() {
+        let message = SerializableMessage {
+            role: "user".into(),
+            content: vec!["<source/>".into(), "<question>hi</question>".into()],
+            reasoning: None,
+            guardrail: None,
+            cache_point: Some(0),
+        };
+        let blocks: Vec<bool> = message
+            .clone()
+            .to_message_with_cache_points(true)
+            .content()
+            .iter()
+            .map(|b| b.is_cache_point())
+            .collect();
+        assert_eq!(blocks, vec![false, true, false, true]);
 
-        // Add the inline code with styling
-        result.push_str(&format!(
-            r#"<code class="language-plaintext inline-code px-1 py-0.5 rounded bg-gray-100 text-sm font-mono">{}</code>"#,
-            html_escape::encode_text(code_content.as_str())
+        // without caching, the message stays as it was saved
+        let plain = Message::from(message);
+        assert_eq!(plain.content().len(), 2);
+    }
+
+    // a chat about some source code that was sent along with the question
+    fn chat_with_source() -> ConversationHistory {
+        let source = format!(
+            "{}{}fn main() {{ if a < b {{}} }}{}{}",
+            "<bedrust_be", "gin_source>", "</bedrust_en", "d_source>"
+        );
+        ConversationHistory {
+            title: Some("rust lifetimes".into()),
+            summary: None,
+            messages: Some(vec![SerializableMessage {
+                role: "user".into(),
+                content: vec![format!("{}\nWhy does `a` fail?", source)],
+                reasoning: None,
+                guardrail: None,
+                cache_point: None,
+            }]),
+            timestamp: Local::now().to_string(),
+            inference_params: None,
+        }
+    }
+
+    #[test]
+    fn html_export_is_named_after_the_title_and_leaves_the_source_out() {
+        let dir = TempDir::new("bedrust-export").unwrap();
+        let config = ExportConfig {
+            dir: Some(dir.path().to_path_buf()),
+            template_dir: Some(dir.path().join("templates")),
+            keep_source: false,
+        };
+
+        // the default name, in the export directory
+        let file = chat_with_source().save_as_html(None, &config).unwrap();
+        assert_eq!(file.parent(), Some(dir.path()));
+        let name = file.file_name().unwrap().to_string_lossy().to_string();
+        assert!(name.starts_with("rust_lifetimes-") && name.ends_with(".html"));
+        let html = fs::read_to_string(&file).unwrap();
+        assert!(html.contains("has been removed from the export"));
+        assert!(!html.contains("fn main()"));
+    }
+
+    #[test]
+    fn html_export_uses_your_own_template_and_keeps_the_source_on_request() {
+        let dir = TempDir::new("bedrust-export").unwrap();
+        let templates = dir.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(
+            templates.join("chat_export.html"),
+            "{{#each messages}}{{{nl2br_with_code content}}}{{/each}}",
+        )
+        .unwrap();
+        let config = ExportConfig {
+            dir: Some(dir.path().to_path_buf()),
+            template_dir: Some(templates),
+            keep_source: true,
+        };
+
+        let file = chat_with_source()
+            .save_as_html(Some(&dir.path().join("mine")), &config)
+            .unwrap();
+        assert_eq!(file, dir.path().join("mine.html"));
+        let html = fs::read_to_string(&file).unwrap();
+        assert!(html.starts_with("<details class=\"source-removed\">"));
+        assert!(html.contains("fn main() { if a &lt; b {} }"));
+        assert!(html.contains("<p>Why does <code class=\"language-plaintext inline-code px-1 py-0.5 rounded bg-gray-100 text-sm font-mono\">a</code> fail?</p>"));
+    }
+
+    #[test]
+    fn code_blocks_keep_their_contents() {
+        // a fence inside a longer fence stays in the code block
+        let nested = "Use this:\n\n````markdown\n```rust\nfn main() {}\n```\n````\n\nDone";
+        let html = markdown_to_html(nested);
+        assert!(html.contains(
+            "<pre><code class=\"language-markdown\">```rust\nfn main() {}\n```\n</code></pre>"
         ));
+        assert!(html.ends_with("<p>Done</p>\n"));
+
+        // a block without a language, and an indented one
+        let html = markdown_to_html("```\nls -la\n```\n\n    cargo build\n");
+        assert_eq!(
+            html.matches("<pre><code class=\"language-plaintext\">")
+                .count(),
+            2
+        );
+        assert!(html.contains("ls -la\n</code></pre>"));
+    }
 
-        last_pos = full_match.end();
+    #[test]
+    fn messages_render_as_sanitized_markdown() {
+        // markdown is rendered, lines are kept, HTML and scripts are not let through
+        let html = markdown_to_html(
+            "**Bold** and a [link](javascript:alert(1))\nnext line\n\n- one\n- two\n\n| a | b |\n|---|---|\n| 1 | 2 |\n\n<script>alert(1)</script>",
+        );
+        assert!(html.contains("<strong>Bold</strong>"));
+        assert!(html.contains("<br>\nnext line"));
+        assert!(html.contains("<li>one</li>"));
+        assert!(html.contains("<td>1</td>"));
+        assert!(!html.contains("javascript:"));
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
     }
 
-    // Add any remaining text
-    if last_pos < text.len() {
-        result.push_str(&text[last_pos..]);
+    #[test]
+    fn titles_are_escaped_in_the_built_in_templates() {
+        // the title is written by a helper too
+        let history = ConversationHistory {
+            title: Some("<script>alert(1)</script>-Ab3xZ".into()),
+            summary: None,
+            messages: None,
+            timestamp: "2024-10-20 15:30".into(),
+            inference_params: None,
+        };
+        // no templates of your own in there
+        let dir = TempDir::new("bedrust-no-templates").unwrap();
+        let config = ExportConfig {
+            template_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        for (name, builtin) in [
+            ("chat_export.html", constants::HTML_TW_TEMPLATE),
+            ("chat_print.html", constants::HTML_PRINT_TEMPLATE),
+        ] {
+            let html = history.render_html(name, builtin, &config).unwrap();
+            assert!(!html.contains("<script>alert"), "{}", name);
+            assert!(html.contains("&lt;"), "{}", name);
+        }
     }
 
-    result
+    #[test]
+    fn guardrail_traces_are_saved_as_json() {
+        use aws_sdk_bedrockruntime::types::{
+            GuardrailTopic, GuardrailTopicPolicyAction, GuardrailTopicPolicyAssessment,
+            GuardrailTopicType,
+        };
+        let topic = GuardrailTopic::builder()
+            .name("Investments")
+            .r#type(GuardrailTopicType::Deny)
+            .action(GuardrailTopicPolicyAction::Blocked)
+            .build()
+            .unwrap();
+        let assessment = GuardrailAssessment::builder()
+            .topic_policy(
+                GuardrailTopicPolicyAssessment::builder()
+                    .topics(topic)
+                    .build()
+                    .unwrap(),
+            )
+            .build();
+        let trace = GuardrailTraceAssessment::builder()
+            .action_reason("Guardrail blocked.")
+            .input_assessment("gr-123", assessment)
+            .build();
+        let outcome = GuardrailOutcome::new(true, Some(&trace));
+        let saved = serde_json::to_value(&outcome).unwrap();
+        assert_eq!(saved["action_reason"], "Guardrail blocked.");
+        assert_eq!(
+            saved["trace"]["inputAssessment"]["gr-123"]["topicPolicy"]["topics"][0],
+            json!({"name": "Investments", "type": "DENY", "action": "BLOCKED", "detected": null})
+        );
+
+        // chats saved with the old text dump still load
+        let old: GuardrailOutcome = serde_json::from_str(
+            r#"{"intervened": true, "action_reason": null, "trace": "GuardrailTraceAssessment { .. }"}"#,
+        )
+        .unwrap();
+        assert!(old.trace.unwrap().is_string());
+    }
 }
@@ -1,26 +1,58 @@
 use crate::constants;
-use crate::models::converse::call_converse;
-use crate::utils::print_warning;
+use crate::index::CodeIndex;
+use crate::models::structured::{call_structured, OutputSchema};
+use crate::models::{HelperModel, HelperModels, HelperTask};
+use crate::utils::{print_warning, ArgModels, CodeChatConfig, IndexConfig};
 use anyhow::anyhow;
-use aws_sdk_bedrockruntime::types::{ContentBlock, InferenceConfiguration};
-use ignore::DirEntry;
+use aws_sdk_bedrockruntime::types::ContentBlock;
+use colored::*;
+use dialoguer::Confirm;
+use dirs::home_dir;
+use ignore::gitignore::GitignoreBuilder;
+use ignore::overrides::{Override, OverrideBuilder};
+use ignore::{DirEntry, Match};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt::Display;
 use std::fs;
-use std::{collections::HashMap, path::PathBuf};
+use std::path::{Path, PathBuf};
 
 // NOTE:
 // A few things to note here:
-// - I am hardcoding the model that guesses the project type, meaning the customer will need to
-// have that model enabled for this to work.
-// - For larger projects we may reach the context size limit quite fast. So it is rather limited.
-// - We need to provide to bits of information before the run commences:
-//   - Size of the files that will be sent over
-//   - Project type we assumed / file extensions being sent over
-
-// This starts a process of the code chat. Moved here instead of being in the main.rs file
+// - The project type is detected locally from manifest files and file extensions. Only if that
+// fails (and `llm_project_guess` is enabled) we ask the `project_guess` helper model.
+// - For larger projects we may reach the context size limit quite fast. So the source code is
+// collected up to a token budget (by default what fits in the context window of the model).
+// - Before anything is sent, we print a report of what is being sent (files, size, tokens and
+// cost) and ask the user to confirm.
+// - If the directory was indexed with `bedrust index`, nothing is sent up front. Instead every
+// question gets the most relevant chunks of the index (see index.rs).
+
+// What code chat sends to the model
+pub enum CodeContext {
+    // the whole source code, sent once with the first question, and the versions of the files
+    // that were sent
+    Source {
+        prompt: String,
+        versions: BTreeMap<PathBuf, String>,
+    },
+    // the embedding index, searched with every question
+    Index(CodeIndex),
+    // a git change to review (see review.rs), sent once like the source code
+    Review(String),
+}
+
+// This starts a process of the code chat. Moved here instead of being in the main.rs file.
+// Returns None if the user decides not to send the source code.
 pub async fn code_chat_process(
     code_path: PathBuf,
     bedrock_runtime_client: &aws_sdk_bedrockruntime::Client,
-) -> Result<String, anyhow::Error> {
+    helpers: &HelperModels,
+    model: &ArgModels,
+    max_tokens: i32,
+    config: &CodeChatConfig,
+    index_config: &IndexConfig,
+) -> Result<Option<CodeContext>, anyhow::Error> {
     println!("----------------------------------------");
     print_warning("⚠ THIS IS A BETA FEATURE ⚠");
     println!("----------------------------------------");
@@ -32,10 +64,50 @@ pub async fn code_chat_process(
             .into_os_string()
     );
     println!("----------------------------------------");
+
+    // === INDEXED SOURCE CODE ===
+    if index_config.enabled {
+        match CodeIndex::load(&code_path)? {
+            Some(index) if index.is_compatible(index_config) => {
+                // only to tell the user if the index is out of date
+                let files = find_source_files(&code_path, config)?.unwrap_or_default();
+                index.print_report(&index.plan_update(&files, config.max_file_size));
+                return Ok(Some(CodeContext::Index(index)));
+            }
+            Some(_) => print_warning(
+                "⚠ The index of this directory was built with other settings, sending the whole source code instead. Run `bedrust index <dir> --rebuild` to fix it",
+            ),
+            None => {}
+        }
+    }
+
     let mut convo = String::new();
     //convo.push_str(constants::CODE_CHAT_PROMPT);
 
-    let code = code_chat(code_path.clone().to_path_buf(), bedrock_runtime_client).await?;
+    let token_budget = default_token_budget(config, model, max_tokens, constants::CODE_CHAT_PROMPT);
+
+    let sources = code_chat(
+        code_path.clone().to_path_buf(),
+        bedrock_runtime_client,
+        helpers,
+        config,
+        token_budget,
+    )
+    .await?;
+
+    sources.print_report(&code_path, model);
+    if sources.files.is_empty() {
+        return Err(anyhow!("🔴 | No source files to send. Sorry!"));
+    }
+    let send = Confirm::new()
+        .with_prompt("Send the source code to the model?")
+        .default(true)
+        .interact()?;
+    if !send {
+        return Ok(None);
+    }
+    let code = sources.to_prompt_string();
+    let versions = sources.versions(&code_path);
 
     // NOTE: Here is something stupid for my edge case
     let (p1, p2) = ("<bedrust_be", "gin_source>");
@@ -49,44 +121,487 @@ pub async fn code_chat_process(
     // Return this conversation
     convo.push_str(query.as_str());
 
-    Ok(convo)
+    Ok(Some(CodeContext::Source {
+        prompt: convo,
+        versions,
+    }))
 }
 
 pub async fn code_chat(
     p: PathBuf,
     client: &aws_sdk_bedrockruntime::Client,
-) -> Result<String, anyhow::Error> {
-    // === DEFAULT INFERENCE PARAMETERS ===
-    // NOTE: Not sure if this is the best way to store this. Maybe also as part of a configuraiton
-    // run
-    // Maybe even have specific parameters for just guessing the code with a low temp value
-    let inference_parameters: InferenceConfiguration = InferenceConfiguration::builder()
-        .max_tokens(2048)
-        .top_p(0.8)
-        .temperature(0.2)
-        .build();
+    helpers: &HelperModels,
+    config: &CodeChatConfig,
+    token_budget: usize,
+) -> Result<SourceCollection, anyhow::Error> {
+    let files = match find_source_files(&p, config)? {
+        Some(files) => files,
+        None if config.llm_project_guess => {
+            println!("🔍 | Could not detect the project type, asking the helper model");
+            let filters = build_overrides(&p, config)?;
+            let all_files = get_all_files(&p, None, config.max_depth, &filters)?;
+            let helper = helpers.get(HelperTask::ProjectGuess);
+            let extn = guess_code_type(all_files, client, helper).await?;
+            println!(
+                "Including the following file extensions in this run: {:?}",
+                extn
+            );
+            get_all_files(&p, Some(extn), config.max_depth, &filters)?
+        }
+        None => {
+            return Err(anyhow!(
+                "🔴 | Could not detect the project type. Use --include to pick the files to send"
+            ))
+        }
+    };
+    Ok(collect_sources(
+        &p,
+        files,
+        config.max_file_size,
+        token_budget,
+    ))
+}
 
+// The source files of a directory - the ones matching the --include globs, or the ones of the
+// detected project type. Returns None if the project type can not be detected.
+pub fn find_source_files(
+    p: &PathBuf,
+    config: &CodeChatConfig,
+) -> Result<Option<Vec<PathBuf>>, anyhow::Error> {
+    let filters = build_overrides(p, config)?;
+    if !config.include.is_empty() {
+        // the user told us what to send, no need to figure out the project
+        println!("🔍 | Including the files matching: {:?}", config.include);
+        return Ok(Some(get_all_files(p, None, config.max_depth, &filters)?));
+    }
     // FIGURE OUT PROJECT
-    // FIX: Seems to return hidden files too
-    let all_files = get_all_files(&p, None, 3)?;
-    let extn = guess_code_type(all_files, client, inference_parameters).await?;
+    let all_files = get_all_files(p, None, config.max_depth, &filters)?;
+    let detected = detect_project_type(p, &all_files);
+    if detected.extensions.is_empty() {
+        return Ok(None);
+    }
+    println!("🔍 | Detected project type: {}", detected.describe().cyan());
+    println!(
+        "Including the following file extensions in this run: {:?}",
+        detected.extensions
+    );
+
+    // get all files with the extensions from above
+    Ok(Some(get_all_files(
+        p,
+        Some(detected.extensions),
+        config.max_depth,
+        &filters,
+    )?))
+}
+
+// ######################################## SOURCE COLLECTION
+// tokens kept free for the questions of the user
+const CODE_CHAT_QUESTION_RESERVE: usize = 1_000;
+
+// The configured token budget, or what is left of the context window after the prompt and the
+// answer
+pub(crate) fn default_token_budget(
+    config: &CodeChatConfig,
+    model: &ArgModels,
+    max_tokens: i32,
+    prompt: &str,
+) -> usize {
+    config.token_budget.unwrap_or_else(|| {
+        model
+            .context_window()
+            .saturating_sub(max_tokens.max(0) as usize)
+            .saturating_sub(estimate_tokens(prompt))
+            .saturating_sub(CODE_CHAT_QUESTION_RESERVE)
+    })
+}
+// how much of a file we look at to decide if it is binary
+const BINARY_SNIFF_LEN: usize = 8 * 1024;
+
+// A rough token estimate - about 4 characters per token for code and English text. We do not
+// have the tokenizers of the models, so this is only good for budgeting.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.len().div_ceil(4)
+}
+
+#[derive(Debug)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    // the file, formatted the way it is sent to the model
+    pub formatted: String,
+    // sha256 of the contents, to tell later if the file changed
+    pub hash: String,
+    pub bytes: u64,
+    pub tokens: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SkipReason {
+    Binary,
+    NotUtf8,
+    TooLarge(u64),
+    OverBudget,
+    Unreadable(String),
+}
+
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::Binary => write!(f, "binary file"),
+            SkipReason::NotUtf8 => write!(f, "not UTF-8"),
+            SkipReason::TooLarge(bytes) => write!(f, "too large ({})", format_bytes(*bytes)),
+            SkipReason::OverBudget => write!(f, "over the token budget"),
+            SkipReason::Unreadable(e) => write!(f, "unreadable ({})", e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SourceCollection {
+    pub files: Vec<SourceFile>,
+    pub skipped: Vec<(PathBuf, SkipReason)>,
+    pub token_budget: usize,
+}
+
+impl SourceCollection {
+    pub fn total_bytes(&self) -> u64 {
+        self.files.iter().map(|f| f.bytes).sum()
+    }
+
+    pub fn total_tokens(&self) -> usize {
+        self.files.iter().map(|f| f.tokens).sum()
+    }
 
-    // get all files with the extensions from above, and go 2 levels deep
-    let files = get_all_files(&p, Some(extn), 3)?;
-    let contents = get_file_contents(files)?;
+    pub fn to_prompt_string(&self) -> String {
+        self.files.iter().map(|f| f.formatted.as_str()).collect()
+    }
+
+    // The hash of every file, keyed by the path relative to the root
+    pub fn versions(&self, root: &Path) -> BTreeMap<PathBuf, String> {
+        self.files
+            .iter()
+            .map(|f| {
+                let path = f.path.strip_prefix(root).unwrap_or(&f.path);
+                (path.to_path_buf(), f.hash.clone())
+            })
+            .collect()
+    }
 
-    let mut formatted_contents = String::new();
-    for (filename, content) in &contents {
-        let string: String = format!(
+    // The pre-flight report, printed before anything is sent to the model
+    pub fn print_report(&self, root: &Path, model: &ArgModels) {
+        let tokens = self.total_tokens();
+        let cost = tokens as f64 / 1000.0 * model.input_price_per_1k();
+        println!("----------------------------------------");
+        println!("📋 | Source code report");
+        println!(
+            "📄 | Files: {} ({})",
+            self.files.len().to_string().cyan(),
+            format_bytes(self.total_bytes()).cyan()
+        );
+        println!(
+            "🔢 | Estimated tokens: {} of a {} token budget ({} context window)",
+            tokens.to_string().cyan(),
+            self.token_budget,
+            model.context_window()
+        );
+        println!(
+            "💰 | Estimated cost: {} per question (input only, without prompt caching)",
+            format!("${:.4}", cost).cyan()
+        );
+        if !self.skipped.is_empty() {
+            println!("⏭️  | Skipped {} file(s):", self.skipped.len());
+            for (path, reason) in &self.skipped {
+                let path = path.strip_prefix(root).unwrap_or(path);
+                println!(
+                    "{}",
+                    format!("     - {} ({})", path.display(), reason).yellow()
+                );
+            }
+        }
+        println!("----------------------------------------");
+    }
+}
+
+// Reads the files and adds them until the token budget is used up. Binary, non UTF-8 and
+// oversized files are skipped (and reported).
+pub fn collect_sources(
+    root: &Path,
+    files: Vec<PathBuf>,
+    max_file_size: u64,
+    token_budget: usize,
+) -> SourceCollection {
+    let mut collection = SourceCollection {
+        files: Vec::new(),
+        skipped: Vec::new(),
+        token_budget,
+    };
+    let mut used = 0;
+    for path in files {
+        let contents = match read_source_file(&path, max_file_size) {
+            Ok(contents) => contents,
+            Err(reason) => {
+                collection.skipped.push((path, reason));
+                continue;
+            }
+        };
+        let name = path.strip_prefix(root).unwrap_or(&path);
+        let formatted = format!(
             "\n<filename>{}</filename>\n<file_contents>{}\n</file_contents>",
-            filename.to_string_lossy(),
-            content
+            name.to_string_lossy(),
+            contents
         );
-        formatted_contents.push_str(string.as_str())
+        let tokens = estimate_tokens(&formatted);
+        if used + tokens > token_budget {
+            collection.skipped.push((path, SkipReason::OverBudget));
+            continue;
+        }
+        used += tokens;
+        collection.files.push(SourceFile {
+            bytes: contents.len() as u64,
+            hash: hash_contents(&contents),
+            path,
+            formatted,
+            tokens,
+        });
+    }
+    collection
+}
+
+pub(crate) fn read_source_file(path: &Path, max_file_size: u64) -> Result<String, SkipReason> {
+    let size = fs::metadata(path)
+        .map_err(|e| SkipReason::Unreadable(e.to_string()))?
+        .len();
+    if size > max_file_size {
+        return Err(SkipReason::TooLarge(size));
+    }
+    let bytes = fs::read(path).map_err(|e| SkipReason::Unreadable(e.to_string()))?;
+    decode_source(bytes)
+}
+
+// Turns the contents of a file into text, unless it is binary or not UTF-8
+pub(crate) fn decode_source(bytes: Vec<u8>) -> Result<String, SkipReason> {
+    // the same check git does - a NUL byte near the start means binary
+    if bytes.iter().take(BINARY_SNIFF_LEN).any(|b| *b == 0) {
+        return Err(SkipReason::Binary);
+    }
+    String::from_utf8(bytes).map_err(|_| SkipReason::NotUtf8)
+}
+
+pub(crate) fn hash_contents(contents: &str) -> String {
+    Sha256::digest(contents.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Where Bedrust keeps what it knows about a source directory (the index, the undo journal ...):
+// ~/.config/bedrust/<kind>/<dir name>-<hash of the full path>.json
+pub(crate) fn project_data_path(root: &Path, kind: &str) -> Result<PathBuf, anyhow::Error> {
+    let root = root
+        .canonicalize()
+        .map_err(|_| anyhow!("🔴 | The specified path does not exist. Sorry!"))?;
+    let name = root
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "root".into());
+    let hash = hash_contents(&root.to_string_lossy());
+    let home_dir = home_dir().expect("Failed to get HOME directory");
+    Ok(home_dir
+        .join(format!(".config/{}/{}", constants::CONFIG_DIR_NAME, kind))
+        .join(format!("{}-{}.json", name, &hash[..16])))
+}
+
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1024 * 1024 => format!("{:.1} MB", b as f64 / (1024.0 * 1024.0)),
+        b if b >= 1024 => format!("{:.1} KB", b as f64 / 1024.0),
+        b => format!("{} B", b),
+    }
+}
+// ######################################## END SOURCE COLLECTION
+
+// ######################################## PROJECT DETECTION
+// Figures out the project type locally - first from the manifest files (Cargo.toml, package.json
+// ...), then from what the directory is made of. No model calls, so the same directory always
+// gives the same files.
+
+// manifests deeper than this are likely vendored code or examples, not the project itself
+const MANIFEST_MAX_DEPTH: usize = 2;
+// an extension needs to be at least this share of the files to be included by the histogram
+const HISTOGRAM_MIN_SHARE: f64 = 0.05;
+
+#[derive(Debug, Default)]
+pub struct ProjectDetection {
+    // the project types and the manifest that gave them away
+    pub project_types: Vec<(String, PathBuf)>,
+    pub extensions: Vec<String>,
+}
+
+impl ProjectDetection {
+    pub fn describe(&self) -> String {
+        if self.project_types.is_empty() {
+            return "unknown (by file extensions)".into();
+        }
+        self.project_types
+            .iter()
+            .map(|(name, manifest)| format!("{} ({})", name, manifest.display()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+pub fn detect_project_type(root: &Path, files: &[PathBuf]) -> ProjectDetection {
+    let mut detection = ProjectDetection::default();
+    let mut extensions: Vec<String> = Vec::new();
+
+    // === MANIFESTS ===
+    for file in files {
+        let relative = file.strip_prefix(root).unwrap_or(file);
+        if relative.components().count() > MANIFEST_MAX_DEPTH {
+            continue;
+        }
+        let Some(name) = relative.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        for (manifest, project_type, exts) in constants::PROJECT_MANIFESTS {
+            let matches = match manifest.strip_prefix("*.") {
+                Some(ext) => name.ends_with(&format!(".{}", ext)),
+                None => name == *manifest,
+            };
+            if !matches {
+                continue;
+            }
+            if !detection
+                .project_types
+                .iter()
+                .any(|(t, _)| t == project_type)
+            {
+                detection
+                    .project_types
+                    .push((project_type.to_string(), relative.to_path_buf()));
+            }
+            extensions.extend(exts.iter().map(|e| e.to_string()));
+        }
+    }
+
+    // === EXTENSION HISTOGRAM ===
+    // picks up the source files the manifests do not cover (scripts, infrastructure ...)
+    let mut histogram: BTreeMap<String, usize> = BTreeMap::new();
+    for file in files {
+        if let Some(ext) = file.extension().and_then(|e| e.to_str()) {
+            let ext = ext.to_lowercase();
+            if constants::SOURCE_EXTENSIONS.contains(&ext.as_str()) {
+                *histogram.entry(ext).or_default() += 1;
+            }
+        }
+    }
+    let min_count = ((files.len() as f64 * HISTOGRAM_MIN_SHARE).ceil() as usize).max(1);
+    for (ext, count) in histogram {
+        if count >= min_count {
+            extensions.push(ext);
+        }
+    }
+
+    extensions.sort();
+    extensions.dedup();
+    detection.extensions = extensions;
+    detection
+}
+
+// The --include and --exclude globs, relative to the source directory
+pub(crate) fn build_overrides(
+    root: &Path,
+    config: &CodeChatConfig,
+) -> Result<Override, anyhow::Error> {
+    let mut builder = OverrideBuilder::new(root);
+    for glob in &config.include {
+        builder.add(glob)?;
+    }
+    for glob in &config.exclude {
+        builder.add(&format!("!{}", glob))?;
+    }
+    Ok(builder.build()?)
+}
+
+// Tells if a single path is one of the files `get_all_files` would walk to - the same
+// .gitignore / .ignore files and --include / --exclude globs - without walking the directory.
+// For the paths we only hear about (file system events, tool calls of the model).
+pub(crate) struct SourceFilter {
+    root: PathBuf,
+    overrides: Override,
+    max_depth: Option<usize>,
+}
+
+impl SourceFilter {
+    pub(crate) fn new(root: &Path, config: &CodeChatConfig) -> Result<Self, anyhow::Error> {
+        Ok(SourceFilter {
+            root: root.to_path_buf(),
+            overrides: build_overrides(root, config)?,
+            max_depth: config.max_depth,
+        })
+    }
+
+    pub(crate) fn contains(&self, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+        let names: Vec<_> = relative.components().map(|c| c.as_os_str()).collect();
+        let Some((file_name, dirs)) = names.split_last() else {
+            return false;
+        };
+        if self.max_depth.is_some_and(|depth| names.len() > depth)
+            || file_name.to_string_lossy().starts_with('.')
+            || dirs
+                .iter()
+                .any(|d| constants::CODE_IGNORE_DIRS.contains(&d.to_string_lossy().as_ref()))
+        {
+            return false;
+        }
+        // a directory left out by --exclude is never walked into
+        let mut dir = self.root.clone();
+        for name in dirs {
+            dir.push(name);
+            if self.overrides.matched(&dir, true).is_ignore() {
+                return false;
+            }
+        }
+        // the globs win over the ignore files, like they do in the walk
+        match self.overrides.matched(path, false) {
+            Match::Whitelist(_) => return true,
+            Match::Ignore(_) => return false,
+            Match::None => {}
+        }
+        !self.is_gitignored(path)
     }
 
-    Ok(formatted_contents)
+    // The ignore files closest to the path decide, and .ignore wins over .gitignore
+    fn is_gitignored(&self, path: &Path) -> bool {
+        let exclude = self.root.join(".git/info/exclude");
+        for dir in path.ancestors().skip(1) {
+            if !dir.starts_with(&self.root) {
+                break;
+            }
+            let mut files = vec![dir.join(".ignore"), dir.join(".gitignore")];
+            if dir == self.root {
+                files.push(exclude.clone());
+            }
+            for file in files.iter().filter(|f| f.is_file()) {
+                let mut builder = GitignoreBuilder::new(dir);
+                builder.add(file);
+                let Ok(gitignore) = builder.build() else {
+                    continue;
+                };
+                match gitignore.matched_path_or_any_parents(path, false) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {}
+                }
+            }
+        }
+        false
+    }
 }
+// ######################################## END PROJECT DETECTION
 
 // a simple function to check if a file name is hidden (has a . in front)
 fn is_hidden(entry: &DirEntry) -> bool {
@@ -97,32 +612,46 @@ fn is_hidden(entry: &DirEntry) -> bool {
         .unwrap_or(false)
 }
 
-// gets all files of a give filename in a given dir up to a certain depth
-fn get_all_files(
+// directories that never contain source code worth sending (build output, dependencies ...)
+fn is_ignored_dir(entry: &DirEntry) -> bool {
+    entry.file_type().is_some_and(|ft| ft.is_dir())
+        && entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| constants::CODE_IGNORE_DIRS.contains(&name))
+}
+
+// gets all files of a give filename in a given dir up to a certain depth. Follows the .gitignore
+// (and .ignore) files, even outside of a git repository.
+pub(crate) fn get_all_files(
     p: &PathBuf,
     ext: Option<Vec<String>>,
-    l: u8,
+    max_depth: Option<usize>,
+    filters: &Override,
 ) -> Result<Vec<PathBuf>, anyhow::Error> {
     if !p.exists() {
         return Err(anyhow!("🔴 | The specified path does not exist. Sorry!"));
     }
 
     let mut builder = ignore::WalkBuilder::new(p);
-    builder.max_depth(Some(l as usize));
+    builder.max_depth(max_depth);
     builder.hidden(false);
+    builder.require_git(false);
+    builder.overrides(filters.clone());
+    builder.filter_entry(|entry| !is_ignored_dir(entry));
 
     let walker = builder.build();
 
-    let files: Vec<_> = walker
+    let mut files: Vec<_> = walker
         .filter_map(Result::ok)
         .filter(|entry| {
-            let is_file = entry.file_type().map_or(false, |ft| ft.is_file());
-            let matches_extension = ext.as_ref().map_or(true, |extensions| {
+            let is_file = entry.file_type().is_some_and(|ft| ft.is_file());
+            let matches_extension = ext.as_ref().is_none_or(|extensions| {
                 entry
                     .path()
                     .extension()
                     .and_then(|ext| ext.to_str())
-                    .map_or(false, |ext| extensions.contains(&ext.to_string()))
+                    .is_some_and(|ext| extensions.contains(&ext.to_string()))
             });
             let is_not_ignored = !is_hidden(entry);
 
@@ -130,6 +659,8 @@ fn get_all_files(
         })
         .map(|e| e.path().to_path_buf())
         .collect();
+    // the walk order depends on the file system, we want the same files every time
+    files.sort();
 
     Ok(files)
 }
@@ -137,71 +668,183 @@ fn get_all_files(
 async fn guess_code_type(
     files: Vec<PathBuf>,
     client: &aws_sdk_bedrockruntime::Client,
-    inf_param: InferenceConfiguration,
+    helper: &HelperModel,
 ) -> Result<Vec<String>, anyhow::Error> {
     // question
     let mut query = String::new();
     query.push_str(constants::PROJECT_GUESS_PROMPT);
     for file in files {
-        query.push_str(file.into_os_string().to_str().unwrap());
-    }
-
-    let model_id = constants::PROJECT_GUESS_MODEL_ID;
-    // let bcall = mk_bedrock_call(&query, None, model_id)?;
-    // FIX: This just prints out the files - as this is how the call_bedrock function works
-    // This println! is here to just make it look nice
-    println!("Including the following file extensions in this run: ");
-    let content = ContentBlock::Text(query);
-    // === RETRY MECHANISM ===
-    let max_retries = 3;
-    let mut retry_count = 0;
-    while retry_count < max_retries {
-        match call_converse(
-            client,
-            model_id.to_string(),
-            inf_param.clone(),
-            content.clone(),
-            None,
-            true,
-        )
-        .await
-        {
-            Ok(response) => {
-                // check if the response is a valid array
-                match serde_json::from_str::<Vec<String>>(&response) {
-                    Ok(extensions) => return Ok(extensions),
-                    Err(_) => {
-                        println!("🔴 | Response from `guess_code_type` is not a valid array. Retrying ...");
-                        retry_count += 1;
-                    }
-                }
-            }
-            Err(e) => {
-                // if an error occurs, print it and retry
-                println!("🔴 | Error: {}", e);
-                retry_count += 1;
-            }
-        }
-        // if we have retried max_retries times, return an error
-        if retry_count >= max_retries {
-            return Err(anyhow!(
-                "Failed to get a response after {} retries",
-                max_retries
-            ));
-        }
-        // sleep for 2^retry_count seconds - exponential backoff
-        tokio::time::sleep(std::time::Duration::from_secs(2u64.pow(retry_count))).await;
-        // === END RETRY MECHANISM ===
+        query.push_str(&file.to_string_lossy());
+        query.push('\n');
     }
-    Err(anyhow!("Unexpected error in guess_code_type"))
+
+    // the extensions come back through a tool, validated against this schema
+    let schema = OutputSchema::new(&serde_json::json!({
+        "type": "array",
+        "items": { "type": "string", "pattern": "^[A-Za-z0-9_+-]+$" },
+        "minItems": 1
+    }))?;
+    let extensions = call_structured(
+        client,
+        &helper.model_id,
+        &helper.inference_parameters,
+        &helper.guardrail,
+        vec![ContentBlock::Text(query)],
+        &schema,
+        3,
+    )
+    .await
+    .map_err(|e| anyhow!("🔴 | Could not guess the project type: {}", e))?;
+    Ok(serde_json::from_value(extensions)?)
 }
 
-fn get_file_contents(files: Vec<PathBuf>) -> Result<HashMap<PathBuf, String>, anyhow::Error> {
-    let mut code = HashMap::new();
-    for file in files {
-        let contents = fs::read_to_string(&file).unwrap();
-        code.insert(file, contents);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+
+    // a project with an ignored file, a build directory, and sources that can not be sent
+    fn mixed_sources() -> TempDir {
+        let dir = TempDir::new("bedrust-code").unwrap();
+        let root = &dir.path().to_path_buf();
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join(".gitignore"), "secret.rs\n").unwrap();
+        fs::write(root.join("src/main.rs"), "fn main() {}\n").unwrap();
+        fs::write(root.join("src/secret.rs"), "const KEY: &str = \"\";\n").unwrap();
+        fs::write(root.join("target/build.rs"), "// output\n").unwrap();
+        fs::write(root.join("src/blob.rs"), [0u8, 159, 146, 150]).unwrap();
+        fs::write(root.join("src/latin1.rs"), [b'/', b'/', 0xe9]).unwrap();
+        fs::write(root.join("src/big.rs"), "a".repeat(2048)).unwrap();
+        dir
     }
 
-    Ok(code)
+    #[test]
+    fn walk_respects_gitignore_and_skips_the_build_directory() {
+        let dir = mixed_sources();
+        let root = &dir.path().to_path_buf();
+        let files = get_all_files(root, Some(vec!["rs".into()]), None, &Override::empty()).unwrap();
+        let names: Vec<_> = files
+            .iter()
+            .map(|f| f.strip_prefix(root).unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["src/big.rs", "src/blob.rs", "src/latin1.rs", "src/main.rs"]
+        );
+    }
+
+    #[test]
+    fn collector_skips_binaries_large_files_and_other_encodings() {
+        let dir = mixed_sources();
+        let root = &dir.path().to_path_buf();
+        let files = get_all_files(root, Some(vec!["rs".into()]), None, &Override::empty()).unwrap();
+        let collection = collect_sources(root, files, 1024, 10_000);
+        assert_eq!(collection.files.len(), 1);
+        assert!(collection.to_prompt_string().contains("src/main.rs"));
+        let reasons: Vec<_> = collection.skipped.iter().map(|(_, r)| r).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                &SkipReason::TooLarge(2048),
+                &SkipReason::Binary,
+                &SkipReason::NotUtf8
+            ]
+        );
+    }
+
+    #[test]
+    fn nothing_fits_in_a_tiny_budget() {
+        let dir = mixed_sources();
+        let root = &dir.path().to_path_buf();
+        let files = get_all_files(root, Some(vec!["rs".into()]), None, &Override::empty()).unwrap();
+        let collection = collect_sources(root, files, 1024, 5);
+        assert!(collection.files.is_empty());
+        assert!(collection
+            .skipped
+            .contains(&(root.join("src/main.rs"), SkipReason::OverBudget)));
+    }
+
+    #[test]
+    fn detects_project_from_manifests_and_extensions() {
+        let root = PathBuf::from("/repo");
+        let files: Vec<PathBuf> = [
+            "Cargo.toml",
+            "src/main.rs",
+            "src/lib.rs",
+            "scripts/deploy.sh",
+            "infra/main.tf",
+            "examples/web/package.json",
+            "img/logo.png",
+        ]
+        .iter()
+        .map(|f| root.join(f))
+        .collect();
+        let detection = detect_project_type(&root, &files);
+        let types: Vec<_> = detection
+            .project_types
+            .iter()
+            .map(|(t, _)| t.as_str())
+            .collect();
+        // the package.json is too deep to count as a manifest
+        assert_eq!(types, vec!["Rust", "Terraform"]);
+        assert_eq!(
+            detection.extensions,
+            vec!["hcl", "json", "md", "rs", "sh", "tf", "tfvars", "toml"]
+        );
+        assert!(detect_project_type(&root, &[root.join("logo.png")])
+            .extensions
+            .is_empty());
+    }
+
+    #[test]
+    fn include_and_exclude_globs() {
+        let dir = TempDir::new("bedrust-globs").unwrap();
+        let root = &dir.path().to_path_buf();
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/main.rs"), "").unwrap();
+        fs::write(root.join("src/main_test.rs"), "").unwrap();
+        fs::write(root.join("README.md"), "").unwrap();
+        let config = CodeChatConfig {
+            include: vec!["src/**".into()],
+            exclude: vec!["*_test.rs".into()],
+            ..Default::default()
+        };
+        let filters = build_overrides(root, &config).unwrap();
+        let files = get_all_files(root, None, None, &filters).unwrap();
+        assert_eq!(files, vec![root.join("src/main.rs")]);
+    }
+
+    #[test]
+    fn source_filter_judges_a_path_the_way_the_walk_does() {
+        let dir = TempDir::new("bedrust-globs").unwrap();
+        let root = &dir.path().to_path_buf();
+        fs::create_dir_all(root.join("src/gen")).unwrap();
+        fs::write(root.join("src/main.rs"), "").unwrap();
+        fs::write(root.join("src/main_test.rs"), "").unwrap();
+        fs::write(root.join("README.md"), "").unwrap();
+        fs::write(root.join("src/gen/out.rs"), "").unwrap();
+        fs::write(root.join("src/.env"), "").unwrap();
+        fs::write(root.join("src/.gitignore"), "gen/\n").unwrap();
+        let config = CodeChatConfig {
+            exclude: vec!["*_test.rs".into()],
+            ..Default::default()
+        };
+        let filters = build_overrides(root, &config).unwrap();
+        let files = get_all_files(root, None, None, &filters).unwrap();
+        assert_eq!(
+            files,
+            vec![root.join("README.md"), root.join("src/main.rs")]
+        );
+
+        let filter = SourceFilter::new(root, &config).unwrap();
+        for name in ["src/main_test.rs", "src/gen/out.rs", "src/.env"] {
+            assert!(!filter.contains(&root.join(name)), "{}", name);
+        }
+        assert!(filter.contains(&root.join("src/main.rs")));
+        // deleted, or not created yet
+        assert!(filter.contains(&root.join("src/lib.rs")));
+        assert!(!filter.contains(&root.join("target/debug/build.rs")));
+        assert!(!filter.contains(Path::new("/etc/passwd")));
+    }
 }
@@ -1,33 +1,45 @@
-use crate::utils::{check_for_config, initialize_config, print_warning};
+use crate::constants;
+use crate::utils::{
+    check_for_config, initialize_config, print_warning, Args, BedrustConfig, ConfigCommands,
+    InferenceOverrides,
+};
+use anyhow::anyhow;
+use chrono::Local;
+use colored::*;
+use dirs::home_dir;
+use regex::Regex;
+use ron::extensions::Extensions;
+use ron::ser::PrettyConfig;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::Display;
 use std::io;
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
 
 pub fn prompt_init_config() -> Result<(), anyhow::Error> {
     match check_for_config() {
         Ok(config) => match config {
             true => {
+                let config_path = global_config_path();
                 print_warning("****************************************");
                 print_warning("WARNING:");
                 println!("You are trying to initialize the Bedrust configuration");
-                println!("This will overwrite your configuration files in $HOME/.config/bedrust/");
+                println!("This will replace your configuration file with the default one.");
+                println!("Your current file will be backed up next to it.");
+                println!("NOTE: Older configuration files are upgraded automatically, there is no need to re-initialize them.");
                 print!("ARE YOU SURE YOU WANT DO TO THIS? Y/N: ");
                 io::stdout().flush()?; // so the answers are typed on the same line as above
 
                 let mut confirmation = String::new();
                 io::stdin().read_line(&mut confirmation)?;
                 if confirmation.trim().eq_ignore_ascii_case("y") {
-                    print_warning("I ask AGAIN");
-                    print!("ARE YOU SURE YOU WANT DO TO THIS? Y/N: ");
-                    io::stdout().flush()?; // so the answers are typed on the same line as above
-
-                    let mut confirmation = String::new();
-                    io::stdin().read_line(&mut confirmation)?;
-
-                    if confirmation.trim().eq_ignore_ascii_case("y") {
-                        println!("----------------------------------------");
-                        println!("📜 | Initializing Bedrust configuration.");
-                        initialize_config()?;
-                    }
+                    let backup = backup_file(&config_path)?;
+                    println!("💾 | Backed up your configuration to: {:?}", backup);
+                    println!("----------------------------------------");
+                    println!("📜 | Initializing Bedrust configuration.");
+                    initialize_config()?;
                 }
             }
             false => {
@@ -41,3 +53,823 @@ pub fn prompt_init_config() -> Result<(), anyhow::Error> {
     print_warning("Bedrust will now exit");
     std::process::exit(0);
 }
+
+fn global_config_path() -> PathBuf {
+    let home_dir = home_dir().expect("Failed to get HOME directory");
+    home_dir
+        .join(format!(".config/{}", constants::CONFIG_DIR_NAME))
+        .join(constants::BEDRUST_CONFIG_FILE_NAME)
+}
+
+// Copies a file to `<file>.<timestamp>.bak` and returns the path of the copy
+fn backup_file(p: &Path) -> Result<PathBuf, anyhow::Error> {
+    let timestamp = Local::now().format("%Y%m%d%H%M%S");
+    let mut backup = p.as_os_str().to_owned();
+    backup.push(format!(".{}.bak", timestamp));
+    let backup = PathBuf::from(backup);
+    fs::copy(p, &backup)?;
+    Ok(backup)
+}
+
+// ######################################## LAYERED CONFIGURATION
+// The configuration is loaded in layers, each one overriding the values of the previous one:
+// 1. built-in defaults
+// 2. the global config file ($HOME/.config/bedrust/bedrust_config.ron)
+// 3. the project config file (.bedrust.ron, looked up from the current directory upwards)
+// 4. BEDRUST_* environment variables (nested fields are separated by `__`)
+// 5. command line flags
+//
+// A layer only overrides the values it actually contains. To be able to tell which values those
+// are, every layer is parsed twice: once as a `ron::Value` to see which keys are present, and
+// once as a `BedrustConfig` to get the typed values (the `ron::Value` loses the enum variant
+// names, so we can not use it for the values themselves).
+
+#[derive(Debug, Clone)]
+pub enum ConfigOrigin {
+    Default,
+    Global(PathBuf),
+    Project(PathBuf),
+    Environment(String),
+    Cli(String),
+}
+
+impl Display for ConfigOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigOrigin::Default => write!(f, "default"),
+            ConfigOrigin::Global(p) => write!(f, "global ({})", p.display()),
+            ConfigOrigin::Project(p) => write!(f, "project ({})", p.display()),
+            ConfigOrigin::Environment(v) => write!(f, "env ({})", v),
+            ConfigOrigin::Cli(flag) => write!(f, "cli ({})", flag),
+        }
+    }
+}
+
+// A value set from a command line flag. The path is the dotted path of the configuration value
+// e.g. `inference_params.temperature`
+pub struct CliOverride {
+    pub path: &'static str,
+    pub flag: &'static str,
+    pub value: Value,
+}
+
+pub struct LayeredConfig {
+    pub config: BedrustConfig,
+    // the dotted path of every value and where it came from
+    pub origins: BTreeMap<String, ConfigOrigin>,
+    values: Value,
+}
+
+impl LayeredConfig {
+    // true if at least one configuration file (global or project) was loaded
+    pub fn has_config_file(&self) -> bool {
+        self.origins
+            .values()
+            .any(|o| matches!(o, ConfigOrigin::Global(_) | ConfigOrigin::Project(_)))
+    }
+}
+
+// Collects the command line flags that override configuration values
+pub fn cli_overrides(args: &Args) -> Result<Vec<CliOverride>, anyhow::Error> {
+    let mut overrides = Vec::new();
+    if let Some(model) = args.model_id {
+        overrides.push(CliOverride {
+            path: "default_model",
+            flag: "--model-id",
+            value: serde_json::to_value(model)?,
+        });
+    }
+    // the globs pick the images when captioning, and the source files otherwise
+    let (include_path, exclude_path) = if args.caption.is_some() {
+        ("caption.include", "caption.exclude")
+    } else {
+        ("code_chat.include", "code_chat.exclude")
+    };
+    if !args.include.is_empty() {
+        overrides.push(CliOverride {
+            path: include_path,
+            flag: "--include",
+            value: serde_json::to_value(&args.include)?,
+        });
+    }
+    if !args.exclude.is_empty() {
+        overrides.push(CliOverride {
+            path: exclude_path,
+            flag: "--exclude",
+            value: serde_json::to_value(&args.exclude)?,
+        });
+    }
+    if args.recursive {
+        overrides.push(CliOverride {
+            path: "caption.recursive",
+            flag: "--recursive",
+            value: Value::Bool(true),
+        });
+    }
+    if args.batch {
+        overrides.push(CliOverride {
+            path: "caption.batch.enabled",
+            flag: "--batch",
+            value: Value::Bool(true),
+        });
+    }
+    if let Some(profile) = &args.profile {
+        overrides.push(CliOverride {
+            path: "caption.profile",
+            flag: "--profile",
+            value: serde_json::to_value(profile)?,
+        });
+    }
+    Ok(overrides)
+}
+
+pub fn load_layered_config(cli: &[CliOverride]) -> Result<LayeredConfig, anyhow::Error> {
+    // === DEFAULTS ===
+    let mut values = serde_json::to_value(BedrustConfig::default())?;
+    let mut origins = BTreeMap::new();
+    let mut leaves = Vec::new();
+    collect_json_paths(&values, "", &mut leaves);
+    for path in leaves {
+        origins.insert(path, ConfigOrigin::Default);
+    }
+
+    // === GLOBAL ===
+    let global_path = global_config_path();
+    if global_path.is_file() {
+        for (path, value) in layer_from_file(&global_path)? {
+            set_json_path(&mut values, &path, value);
+            origins.insert(path, ConfigOrigin::Global(global_path.clone()));
+        }
+    }
+
+    // === PROJECT ===
+    if let Some(project_path) = find_project_config(&env::current_dir()?) {
+        for (path, value) in layer_from_file(&project_path)? {
+            set_json_path(&mut values, &path, value);
+            origins.insert(path, ConfigOrigin::Project(project_path.clone()));
+        }
+    }
+
+    // === ENVIRONMENT ===
+    let mut env_vars: Vec<(String, String)> = env::vars()
+        .filter(|(k, _)| k.starts_with(constants::CONFIG_ENV_PREFIX))
+        .collect();
+    env_vars.sort();
+    for (name, raw) in env_vars {
+        let path = name[constants::CONFIG_ENV_PREFIX.len()..]
+            .to_lowercase()
+            .replace("__", ".");
+        // only consider variables that map to an actual configuration value
+        if !origins.contains_key(&path) {
+            continue;
+        }
+        for (path, value) in layer_from_env(&name, &path, &raw)? {
+            set_json_path(&mut values, &path, value);
+            origins.insert(path, ConfigOrigin::Environment(name.clone()));
+        }
+    }
+
+    // === CLI ===
+    for o in cli {
+        set_json_path(&mut values, o.path, o.value.clone());
+        origins.insert(o.path.to_string(), ConfigOrigin::Cli(o.flag.to_string()));
+    }
+
+    let config: BedrustConfig = serde_json::from_value(values.clone())
+        .map_err(|e| anyhow!("The merged configuration is not valid: {}", e))?;
+
+    Ok(LayeredConfig {
+        config,
+        origins,
+        values,
+    })
+}
+
+// Runs the `bedrust config` subcommands
+pub fn config_command(command: &ConfigCommands, args: &Args) -> Result<(), anyhow::Error> {
+    match command {
+        ConfigCommands::Show { origin } => {
+            let layered = load_layered_config(&cli_overrides(args)?)?;
+            show_config(&layered, *origin)
+        }
+        ConfigCommands::Validate { file } => validate_config_files(file.clone()),
+    }
+}
+
+// Prints out the effective configuration, optionally with the origin of every value
+pub fn show_config(layered: &LayeredConfig, origin: bool) -> Result<(), anyhow::Error> {
+    if !origin {
+        println!(
+            "{}",
+            ron::ser::to_string_pretty(&layered.config, PrettyConfig::new())?
+        );
+        return Ok(());
+    }
+
+    let width = layered.origins.keys().map(|k| k.len()).max().unwrap_or(0);
+    for (path, origin) in &layered.origins {
+        let value = get_json_path(&layered.values, path)
+            .map(|v| match v {
+                // the configuration floats are all f32, print them as such
+                Value::Number(n) if n.is_f64() => {
+                    (n.as_f64().unwrap_or_default() as f32).to_string()
+                }
+                v => v.to_string(),
+            })
+            .unwrap_or_default();
+        println!(
+            "{:width$} = {}  {}",
+            path.cyan(),
+            value,
+            format!("# {}", origin).truecolor(83, 82, 82),
+            width = width
+        );
+    }
+    Ok(())
+}
+
+// Configuration files can skip the `Some(...)` around optional values
+fn ron_options() -> ron::Options {
+    ron::Options::default().with_default_extension(Extensions::IMPLICIT_SOME)
+}
+
+// Looks for the project configuration file in the given directory and all of its parents
+fn find_project_config(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(constants::PROJECT_CONFIG_FILE_NAME))
+        .find(|p| p.is_file())
+}
+
+fn layer_from_file(p: &Path) -> Result<Vec<(String, Value)>, anyhow::Error> {
+    let content = fs::read_to_string(p)?;
+    layer_from_ron(&content).map_err(|e| anyhow!("Unable to parse {}: {}", p.display(), e))
+}
+
+// Environment variables hold just the value, so we wrap it into a RON document with the right
+// nesting. If the value does not parse as-is, we try again treating it as a string, so that
+// `BEDRUST_AWS_PROFILE=work` works without the quotes.
+fn layer_from_env(
+    name: &str,
+    path: &str,
+    raw: &str,
+) -> Result<Vec<(String, Value)>, anyhow::Error> {
+    let wrap = |value: &str| {
+        let segments: Vec<&str> = path.split('.').collect();
+        let mut doc = value.to_string();
+        for segment in segments.iter().rev() {
+            doc = format!("({}: {})", segment, doc);
+        }
+        format!("#![enable(implicit_some)]\n{}", doc)
+    };
+
+    layer_from_ron(&wrap(raw))
+        .or_else(|_| layer_from_ron(&wrap(&ron::to_string(raw)?)))
+        .map_err(|e| anyhow!("Invalid value for {}: {}", name, e))
+}
+
+// Returns the dotted paths and values that are set in a RON document
+fn layer_from_ron(content: &str) -> Result<Vec<(String, Value)>, anyhow::Error> {
+    let typed: BedrustConfig = ron_options().from_str(content)?;
+    let typed = serde_json::to_value(typed)?;
+    let present: ron::Value = ron_options().from_str(content)?;
+
+    let mut paths = Vec::new();
+    collect_ron_paths(&present, "", &mut paths);
+    Ok(paths
+        .into_iter()
+        .filter_map(|p| get_json_path(&typed, &p).map(|v| (p, v.clone())))
+        .collect())
+}
+
+fn join_path(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+fn collect_ron_paths(value: &ron::Value, prefix: &str, paths: &mut Vec<String>) {
+    match value {
+        ron::Value::Map(map) => {
+            for (k, v) in map.iter() {
+                if let ron::Value::String(key) = k {
+                    collect_ron_paths(v, &join_path(prefix, key), paths);
+                }
+            }
+        }
+        _ if !prefix.is_empty() => paths.push(prefix.to_string()),
+        _ => {}
+    }
+}
+
+fn collect_json_paths(value: &Value, prefix: &str, paths: &mut Vec<String>) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map {
+                collect_json_paths(v, &join_path(prefix, k), paths);
+            }
+        }
+        _ if !prefix.is_empty() => paths.push(prefix.to_string()),
+        _ => {}
+    }
+}
+
+fn get_json_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |v, key| v.get(key))
+}
+
+fn set_json_path(value: &mut Value, path: &str, new: Value) {
+    let mut current = value;
+    for key in path.split('.') {
+        if !current.is_object() {
+            *current = Value::Object(serde_json::Map::new());
+        }
+        current = current
+            .as_object_mut()
+            .unwrap()
+            .entry(key)
+            .or_insert(Value::Null);
+    }
+    *current = new;
+}
+// ######################################## END LAYERED CONFIGURATION
+// ######################################## MIGRATION AND VALIDATION
+// Every configuration file has a `version`. Files without one are from before versioning was
+// introduced and are treated as version 1. When the schema changes in a way that the serde
+// defaults can not handle (renamed or restructured fields), bump `CONFIG_VERSION` and add a
+// migration step below. The steps work on the text of the file, so the comments and formatting
+// the user has are kept.
+//
+// Each step upgrades the file from version `index + 1` to `index + 2`
+type MigrationStep = fn(&str) -> Result<String, anyhow::Error>;
+const MIGRATIONS: &[MigrationStep] = &[migrate_v1_to_v2];
+
+// v1 -> v2: no changes to the fields, just add the version
+fn migrate_v1_to_v2(content: &str) -> Result<String, anyhow::Error> {
+    set_version(content, 2)
+}
+
+// Replaces the value of the `version` field, or adds the field if the file has none
+fn set_version(content: &str, version: u32) -> Result<String, anyhow::Error> {
+    match top_level_field(content, "version") {
+        Some(value) => Ok(format!(
+            "{}{}{}",
+            &content[..value.start],
+            version,
+            &content[value.end..]
+        )),
+        None => insert_field(content, &format!("version: {},", version)),
+    }
+}
+
+// Upgrades the global and project configuration files to the latest version, if needed
+pub fn migrate_config_files() -> Result<(), anyhow::Error> {
+    let mut files = vec![global_config_path()];
+    if let Some(project_path) = find_project_config(&env::current_dir()?) {
+        files.push(project_path);
+    }
+    for file in files.iter().filter(|f| f.is_file()) {
+        migrate_config_file(file)?;
+    }
+    Ok(())
+}
+
+fn migrate_config_file(p: &Path) -> Result<(), anyhow::Error> {
+    let content = fs::read_to_string(p)?;
+    let version = config_file_version(&content)
+        .map_err(|e| anyhow!("Unable to parse {}: {}", p.display(), e))?;
+    if version >= constants::CONFIG_VERSION {
+        return Ok(());
+    }
+
+    let mut migrated = content;
+    for step in &MIGRATIONS[(version.max(1) as usize - 1)..] {
+        migrated = step(&migrated)?;
+    }
+    // make sure we did not break anything before touching the file
+    ron_options()
+        .from_str::<BedrustConfig>(&migrated)
+        .map_err(|e| {
+            anyhow!(
+                "Migrating {} to version {} failed: {}",
+                p.display(),
+                constants::CONFIG_VERSION,
+                e
+            )
+        })?;
+
+    let backup = backup_file(p)?;
+    fs::write(p, migrated)?;
+    eprintln!(
+        "📜 | Upgraded {} from version {} to {} (backup: {})",
+        p.display(),
+        version,
+        constants::CONFIG_VERSION,
+        backup.display()
+    );
+    Ok(())
+}
+
+fn config_file_version(content: &str) -> Result<u32, anyhow::Error> {
+    let present: ron::Value = ron_options().from_str(content)?;
+    match present {
+        ron::Value::Map(map) => Ok(map
+            .iter()
+            .find(|(k, _)| matches!(k, ron::Value::String(k) if k == "version"))
+            .and_then(|(_, v)| match v {
+                ron::Value::Number(n) => n.as_i64().map(|n| n as u32),
+                _ => None,
+            })
+            .unwrap_or(1)),
+        _ => Err(anyhow!("The configuration is not a struct")),
+    }
+}
+
+// Where the value of a field of the top level struct is, skipping strings, comments and the
+// fields of the nested structs (e.g. the `version` of the guardrail)
+fn top_level_field(content: &str, name: &str) -> Option<std::ops::Range<usize>> {
+    let bytes = content.as_bytes();
+    let mut depth = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &content[i..];
+        if rest.starts_with("//") {
+            i += rest.find('\n').unwrap_or(rest.len());
+        } else if rest.starts_with("/*") {
+            i += rest.find("*/").map(|e| e + 2).unwrap_or(rest.len());
+        } else if bytes[i] == b'"' {
+            // to the closing quote, over the escaped ones
+            i += 1;
+            while i < bytes.len() && bytes[i] != b'"' {
+                i += if bytes[i] == b'\\' { 2 } else { 1 };
+            }
+            i += 1;
+        } else if matches!(bytes[i], b'(' | b'[' | b'{') {
+            depth += 1;
+            i += 1;
+        } else if matches!(bytes[i], b')' | b']' | b'}') {
+            depth -= 1;
+            i += 1;
+        } else if bytes[i].is_ascii_alphabetic() || bytes[i] == b'_' {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            let after = content[i..].trim_start();
+            if depth == 1 && &content[start..i] == name && after.starts_with(':') {
+                let value_start = content.len() - after.len() + 1;
+                let value = &content[value_start..];
+                let value_end = value_start + value.find([',', ')', '\n']).unwrap_or(value.len());
+                let trimmed = content[value_start..value_end].trim();
+                let offset = value_start + content[value_start..value_end].find(trimmed)?;
+                return Some(offset..offset + trimmed.len());
+            }
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+// Inserts a field right after the opening parenthesis of the top level struct, skipping any
+// comments, `#![enable(...)]` attributes and the optional `BedrustConfig` name before it
+fn insert_field(content: &str, field: &str) -> Result<String, anyhow::Error> {
+    let bytes = content.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &content[i..];
+        if bytes[i].is_ascii_whitespace() {
+            i += 1;
+        } else if rest.starts_with("//") {
+            i += rest.find('\n').unwrap_or(rest.len());
+        } else if rest.starts_with("/*") {
+            i += rest.find("*/").map(|e| e + 2).unwrap_or(rest.len());
+        } else if rest.starts_with("#!") {
+            i += rest.find(']').map(|e| e + 1).unwrap_or(rest.len());
+        } else if bytes[i] == b'(' {
+            return Ok(format!(
+                "{}(\n  {}{}",
+                &content[..i],
+                field,
+                &content[i + 1..]
+            ));
+        } else if bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_' {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    Err(anyhow!(
+        "Unable to find the start of the configuration struct"
+    ))
+}
+
+// the keys inside these are up to the user (model specific fields)
+const FREEFORM_KEYS: &[&str] = &["additional_fields"];
+// maps keyed by the model name, with `InferenceOverrides` as values
+const MODEL_MAP_KEYS: &[&str] = &["model_inference_params"];
+
+#[derive(Debug)]
+pub struct ConfigIssue {
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Display for ConfigIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+// Checks a configuration file for unknown keys and invalid values
+pub fn validate_config_file(p: &Path) -> Result<Vec<ConfigIssue>, anyhow::Error> {
+    let content = fs::read_to_string(p)?;
+    Ok(validate_config(&content))
+}
+
+fn validate_config(content: &str) -> Vec<ConfigIssue> {
+    let mut issues = Vec::new();
+
+    // a syntax error means we can not look any further
+    let present: ron::Value = match ron_options().from_str(content) {
+        Ok(v) => v,
+        Err(e) => {
+            issues.push(ConfigIssue {
+                line: Some(e.position.line),
+                message: e.code.to_string(),
+            });
+            return issues;
+        }
+    };
+
+    // every key the configuration knows about - including the nested structs
+    let defaults = serde_json::to_value(BedrustConfig::default()).unwrap_or_default();
+    let mut known = Vec::new();
+    collect_json_keys(&defaults, "", &mut known);
+    let overrides = serde_json::to_value(InferenceOverrides::default()).unwrap_or_default();
+    for map_key in MODEL_MAP_KEYS {
+        let prefix = format!("{}.*", map_key);
+        collect_json_keys(&overrides, &prefix, &mut known);
+        known.push(prefix);
+    }
+
+    let mut present_keys = Vec::new();
+    collect_ron_keys(&present, "", &mut present_keys);
+    for key in present_keys {
+        let segments: Vec<&str> = key.split('.').collect();
+        if segments[..segments.len() - 1]
+            .iter()
+            .any(|s| FREEFORM_KEYS.contains(s))
+        {
+            continue;
+        }
+        // the model names are not known up front, so match them as `*`
+        let normalized = segments
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                if i > 0 && MODEL_MAP_KEYS.contains(&segments[i - 1]) {
+                    "*"
+                } else {
+                    s
+                }
+            })
+            .collect::<Vec<&str>>()
+            .join(".");
+        if !known.contains(&normalized) {
+            issues.push(ConfigIssue {
+                line: find_key_line(content, &key),
+                message: format!("unknown key `{}`", key),
+            });
+        }
+    }
+
+    match ron_options().from_str::<BedrustConfig>(content) {
+        Ok(config) => {
+            if let Err(e) = config.inference_params.check() {
+                issues.push(ConfigIssue {
+                    line: find_key_line(content, "inference_params.thinking_budget"),
+                    message: format!("inference_params: {}", e),
+                });
+            }
+            for (model, overrides) in &config.model_inference_params {
+                if let Err(e) = config.inference_params.with_overrides(overrides).check() {
+                    issues.push(ConfigIssue {
+                        line: find_key_line(content, &format!("model_inference_params.{}", model)),
+                        message: format!("model_inference_params.{}: {}", model, e),
+                    });
+                }
+            }
+        }
+        Err(e) => issues.push(ConfigIssue {
+            line: Some(e.position.line),
+            message: format!("invalid value: {}", e.code),
+        }),
+    }
+
+    if let Ok(version) = config_file_version(content) {
+        if version > constants::CONFIG_VERSION {
+            issues.push(ConfigIssue {
+                line: find_key_line(content, "version"),
+                message: format!(
+                    "version {} is newer than this Bedrust supports ({})",
+                    version,
+                    constants::CONFIG_VERSION
+                ),
+            });
+        }
+    }
+
+    issues.sort_by_key(|i| i.line);
+    issues
+}
+
+// Finds the line of a (dotted) key, by looking for each segment after the previous one
+fn find_key_line(content: &str, path: &str) -> Option<usize> {
+    let mut pos = 0;
+    for segment in path.split('.') {
+        // map keys are quoted strings
+        let re = Regex::new(&format!(r#"\b{}"?\s*:"#, regex::escape(segment))).ok()?;
+        pos += re.find(&content[pos..])?.start();
+    }
+    Some(content[..pos].matches('\n').count() + 1)
+}
+
+// Like `collect_ron_paths`, but also returns the keys of the nested structs
+fn collect_ron_keys(value: &ron::Value, prefix: &str, keys: &mut Vec<String>) {
+    if let ron::Value::Map(map) = value {
+        for (k, v) in map.iter() {
+            if let ron::Value::String(key) = k {
+                let path = join_path(prefix, key);
+                keys.push(path.clone());
+                collect_ron_keys(v, &path, keys);
+            }
+        }
+    }
+}
+
+fn collect_json_keys(value: &Value, prefix: &str, keys: &mut Vec<String>) {
+    if let Value::Object(map) = value {
+        for (k, v) in map {
+            let path = join_path(prefix, k);
+            keys.push(path.clone());
+            collect_json_keys(v, &path, keys);
+        }
+    }
+}
+
+// Validates the global and project configuration files, or just the one given
+pub fn validate_config_files(file: Option<PathBuf>) -> Result<(), anyhow::Error> {
+    let files = match file {
+        Some(f) => vec![f],
+        None => {
+            let mut files = vec![global_config_path()];
+            if let Some(project_path) = find_project_config(&env::current_dir()?) {
+                files.push(project_path);
+            }
+            files.into_iter().filter(|f| f.is_file()).collect()
+        }
+    };
+    if files.is_empty() {
+        println!("No configuration files found.");
+        return Ok(());
+    }
+
+    let mut total = 0;
+    for file in files {
+        println!("🔎 | Validating {}", file.display());
+        let issues = validate_config_file(&file)?;
+        if issues.is_empty() {
+            println!("✅ | No issues found");
+        }
+        for issue in &issues {
+            println!("🔴 | {}", issue);
+        }
+        total += issues.len();
+    }
+    if total > 0 {
+        return Err(anyhow!("Found {} issue(s) in the configuration", total));
+    }
+    Ok(())
+}
+// ######################################## END MIGRATION AND VALIDATION
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{ArgModels, InferenceParams};
+
+    #[test]
+    fn migrate_adds_version_and_keeps_comments() {
+        let v1 = "// my config\nBedrustConfig(\n  // profile\n  aws_profile: \"work\",\n)\n";
+        assert_eq!(config_file_version(v1).unwrap(), 1);
+        let v2 = migrate_v1_to_v2(v1).unwrap();
+        assert_eq!(config_file_version(&v2).unwrap(), 2);
+        assert!(v2.contains("// profile"));
+        let config: BedrustConfig = ron::from_str(&v2).unwrap();
+        assert_eq!(config.aws_profile, "work");
+
+        // an explicit version 1 is replaced, not added a second time
+        let v1 = "BedrustConfig(\n  version: 1, // the first one\n  guardrail: (id: Some(\"gr\"), version: \"1\"),\n)\n";
+        assert_eq!(config_file_version(v1).unwrap(), 1);
+        let v2 = migrate_v1_to_v2(v1).unwrap();
+        assert_eq!(
+            v2,
+            "BedrustConfig(\n  version: 2, // the first one\n  guardrail: (id: Some(\"gr\"), version: \"1\"),\n)\n"
+        );
+        let config: BedrustConfig = ron_options().from_str(&v2).unwrap();
+        assert_eq!(config.version, 2);
+        assert_eq!(config.guardrail.version, "1");
+    }
+
+    #[test]
+    fn validate_reports_unknown_keys_with_lines() {
+        let content =
+            "(\n  version: 2,\n  inference_params: (\n    topp: 0.2,\n  ),\n  colour: true,\n)";
+        let issues = validate_config(content);
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0].line, Some(4));
+        assert_eq!(issues[0].message, "unknown key `inference_params.topp`");
+        assert_eq!(issues[1].line, Some(6));
+        assert_eq!(issues[1].message, "unknown key `colour`");
+
+        let content = "(\n  inference_params: (additional_fields: {\"top_k\": 50}),\n  model_inference_params: {\n    \"NovaPro\": (temperature: 0.1, topk: 2),\n  },\n)";
+        let issues = validate_config(content);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].line, Some(4));
+        assert_eq!(
+            issues[0].message,
+            "unknown key `model_inference_params.NovaPro.topk`"
+        );
+    }
+
+    #[test]
+    fn partial_layer_only_sets_present_values() {
+        let layer = layer_from_ron(
+            "BedrustConfig(default_model: Some(NovaLite), inference_params: (temperature: 0.1))",
+        )
+        .unwrap();
+        let paths: Vec<&str> = layer.iter().map(|(p, _)| p.as_str()).collect();
+        assert_eq!(paths, vec!["default_model", "inference_params.temperature"]);
+        assert_eq!(layer[0].1, Value::String("NovaLite".into()));
+    }
+
+    #[test]
+    fn model_overrides_are_applied_on_top_of_defaults() {
+        let config: BedrustConfig = ron_options()
+            .from_str("(inference_params: (temperature: 0.5, stop_sequences: [\"END\"], additional_fields: {\"top_k\": 50}), model_inference_params: {\"NovaPro\": (temperature: 0.1, additional_fields: {\"inferenceConfig\": {\"topK\": 20}})})")
+            .unwrap();
+        let params = config.inference_params_for(&ArgModels::NovaPro);
+        assert_eq!(params.temperature, 0.1);
+        assert_eq!(params.stop_sequences, vec!["END".to_string()]);
+        assert_eq!(params.additional_fields.len(), 2);
+        let params = config.inference_params_for(&ArgModels::NovaLite);
+        assert_eq!(params.temperature, 0.5);
+
+        let mut params = InferenceParams::default();
+        params.set("stop_sequences", "END, STOP").unwrap();
+        params.set("field.top_k", "50").unwrap();
+        assert_eq!(params.stop_sequences, vec!["END", "STOP"]);
+        assert_eq!(params.additional_fields["top_k"], serde_json::json!(50));
+        assert!(params.set("temperature", "hot").is_err());
+    }
+
+    #[test]
+    fn validate_reports_a_thinking_budget_without_room() {
+        let issues = validate_config(
+            "(\n  model_inference_params: {\n    \"ClaudeV37Sonnet\": (max_tokens: 2048, thinking_budget: 2048),\n  },\n)",
+        );
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].line, Some(3));
+        assert!(issues[0]
+            .message
+            .contains("has to be larger than thinking_budget"));
+    }
+
+    #[test]
+    fn env_values_with_and_without_quotes() {
+        let layer = layer_from_env("BEDRUST_AWS_PROFILE", "aws_profile", "work").unwrap();
+        assert_eq!(layer[0].1, Value::String("work".into()));
+
+        let layer = layer_from_env(
+            "BEDRUST_HELPER_MODEL__MODEL",
+            "helper_model.model",
+            "NovaMicro",
+        )
+        .unwrap();
+        assert_eq!(layer[0].0, "helper_model.model");
+        assert_eq!(layer[0].1, Value::String("NovaMicro".into()));
+
+        assert!(layer_from_env(
+            "BEDRUST_INFERENCE_PARAMS__TOP_P",
+            "inference_params.top_p",
+            "abc"
+        )
+        .is_err());
+    }
+}
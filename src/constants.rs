@@ -1,6 +1,4 @@
 // This file contains constants (duh)
-use aws_sdk_bedrockruntime::types::InferenceConfiguration;
-use lazy_static::lazy_static;
 
 // PROMPTS
 // TODO: Move this to a Systemprompt
@@ -23,16 +21,65 @@ Here are the files:
 <SOURCE_CODE_BEDRUST>{SOURCE_CODE}</SOURCE_CODE_BEDRUST>
 "#;
 
+// Sent with every question in code chat, when the source directory has an index (see index.rs)
+pub static CODE_RETRIEVAL_PROMPT: &str = r#"
+You are my coding assistant and an expert in all things coding.
+I have a code base that is too large to share with you in full. With every question I will share the parts of it that are the most relevant to that question, enclosed in <SOURCE_CODE_BEDRUST> XML tags. Each part is provided in the following format:
+<chunk file="filename" lines="first-last">code</chunk>
+
+Base your answer on these parts and on our conversation so far. If they are not enough to answer the question, say so, and tell me which files or functions you would need to see.
+
+The question will be inside the <question></question> tags.
+
+Here are the relevant parts:
+<SOURCE_CODE_BEDRUST>{SOURCE_CODE}</SOURCE_CODE_BEDRUST>
+"#;
+
+// Sent with the next question after `/refresh` in code chat, see watch.rs
+pub static CODE_REFRESH_PROMPT: &str = r#"
+Some files of the code base changed since you last saw them. Their new versions are enclosed in <SOURCE_CODE_BEDRUST> XML tags, in the same format as before. Deleted files are listed as <deleted_file>filename</deleted_file>. From now on, use these versions instead of the ones you saw earlier.
+<SOURCE_CODE_BEDRUST>{SOURCE_CODE}</SOURCE_CODE_BEDRUST>
+"#;
+
+// The system prompt of code review (`bedrust code`), see review.rs
+pub static CODE_REVIEW_SYSTEM_PROMPT: &str = r#"You are an experienced software engineer doing a code review. You are reviewing a change, not the whole code base: focus on the lines the diff adds or changes, and use the full files for context only.
+Look for bugs, security issues, missing error handling, performance problems, missing tests and code that is hard to understand.
+Report every finding on its own line, in the following format:
+- `path/to/file:line` **severity** - what is wrong, and how to fix it
+The severity is one of: critical, major, minor, nit. Use the line numbers of the changed files as they are shown to you (the new version of the file). Order the findings by severity, most severe first.
+If you do not find any issues, say so - do not make up findings. After the findings, summarize the change in a short paragraph.
+When answering follow up questions, keep referencing the files and lines."#;
+
+pub static CODE_REVIEW_PROMPT: &str = r#"
+Please review a change to my code base. The change is enclosed in <SOURCE_CODE_BEDRUST> XML tags. It starts with the unified diff inside <diff></diff> tags, followed by the full contents of every changed file (after the change), with line numbers, in the following format:
+
+<filename>filename</filename>
+<file_contents>    1 | filecontents</file_contents>
+
+My question will be inside the <question></question> tags.
+
+Here is the change:
+<SOURCE_CODE_BEDRUST>{SOURCE_CODE}</SOURCE_CODE_BEDRUST>
+"#;
+
+// the question asked when the user starts the review without one
+pub static DEFAULT_REVIEW_QUESTION: &str = "Please review this change.";
+
+pub static CODE_EDIT_SYSTEM_PROMPT: &str = r#"You are an experienced software engineer working on the code base in {ROOT}. You do not see the code up front: use the list_files, read_file and search tools to find and read the code you need, and never guess what a file contains.
+To change the code, use the propose_patch tool, one file at a time. The user sees every patch as a diff and decides if it gets applied, so keep patches small and focused, and tell the user what you are changing and why. Copy the old_text of every edit exactly from the file as you read it (without the line numbers), with enough lines around the change to make it unique.
+If a patch is rejected, ask the user what they would like instead. If the user only asks a question, answer it without proposing patches.
+All paths are relative to the source directory, and you can not access anything outside of it."#;
+
 // NOTE: When using Claude you can use the Agent prompt to just finalize the array - Thank you
 // Thiago <3
 // This means I can start an array and it should finish it for me
 pub static PROJECT_GUESS_PROMPT: &str = r#"
-You are helping me figure out what kind of software development projects people are working on. To figure this out, you will look at a file structure of a directory and return to me an array of important file names related to that project type. You will only respond with that array and nothing else. Only return file types that are text files (do not return file types that are images or binaries)
+You are helping me figure out what kind of software development projects people are working on. To figure this out, you will look at a file structure of a directory and return to me the extensions of the files important to that project type (without the dot). Only return file types that are text files (do not return file types that are images or binaries)
 
-Here is the example of such an array:
+Here is an example of such a list of extensions:
 ["rs","toml","md","ron"]
 
-Give me an array of important files for a project type that has the following directory items:
+Give me the extensions of the important files for a project type that has the following directory items:
 "#;
 
 pub static CONVERSATION_TITLE_PROMPT: &str = r#"This is a conversation history between a human user and a large language model. Generate only a concise 4-6 word title for the following conversation history. The history is enclosed in the <CONVERSATON_HISTORY> tags. The title should use underscores instead of spaces, and be all in lowercase. Only characters allowed are text characters, numbers and underscore (_). Do not provide any additional text or explanation.
@@ -51,29 +98,73 @@ pub static CONVERSATION_SUMMARY_PROMPT: &str = r#"This is a conversation history
 
 Summary:"#;
 
-// INFERENCE CONSTANTS
-lazy_static! {
-    pub static ref CONVERSATION_HISTORY_INF_PARAMS: InferenceConfiguration =
-        InferenceConfiguration::builder()
-            .max_tokens(256)
-            .top_p(0.8)
-            .temperature(0.2)
-            .build();
-}
-
-lazy_static! {
-    pub static ref CONVERSATION_HISTORY_TITLE_INF_PARAMS: InferenceConfiguration =
-        InferenceConfiguration::builder()
-            .max_tokens(32)
-            .top_p(0.8)
-            .temperature(0.2)
-            .build();
-}
-
 // HELPER CONSTANTS
-// FIX: the model id is hardcoded, we need to make this configurable
-pub static PROJECT_GUESS_MODEL_ID: &str = "anthropic.claude-3-haiku-20240307-v1:0";
-pub static CONVERSATION_HISTORY_MODEL_ID: &str = "anthropic.claude-3-haiku-20240307-v1:0";
+// Files that tell us what kind of project we are looking at, and the extensions of the files that
+// matter for that kind of project. Names starting with `*.` match by extension.
+pub static PROJECT_MANIFESTS: &[(&str, &str, &[&str])] = &[
+    ("Cargo.toml", "Rust", &["rs", "toml", "md"]),
+    (
+        "package.json",
+        "JavaScript/TypeScript",
+        &[
+            "js", "jsx", "mjs", "cjs", "ts", "tsx", "json", "css", "scss", "html", "vue", "svelte",
+            "md",
+        ],
+    ),
+    ("deno.json", "Deno", &["ts", "tsx", "js", "json", "md"]),
+    (
+        "pyproject.toml",
+        "Python",
+        &["py", "pyi", "toml", "cfg", "md"],
+    ),
+    ("setup.py", "Python", &["py", "pyi", "cfg", "md"]),
+    ("requirements.txt", "Python", &["py", "pyi", "txt", "md"]),
+    ("Pipfile", "Python", &["py", "pyi", "md"]),
+    ("go.mod", "Go", &["go", "mod", "md"]),
+    (
+        "pom.xml",
+        "Java (Maven)",
+        &["java", "kt", "xml", "properties", "md"],
+    ),
+    (
+        "build.gradle",
+        "Java (Gradle)",
+        &["java", "kt", "gradle", "properties", "md"],
+    ),
+    (
+        "build.gradle.kts",
+        "Kotlin (Gradle)",
+        &["kt", "kts", "java", "properties", "md"],
+    ),
+    ("*.tf", "Terraform", &["tf", "tfvars", "hcl", "md"]),
+    ("Gemfile", "Ruby", &["rb", "erb", "rake", "gemspec", "md"]),
+    ("composer.json", "PHP", &["php", "json", "md"]),
+    (
+        "CMakeLists.txt",
+        "C/C++ (CMake)",
+        &["c", "h", "cc", "cpp", "hpp", "cmake", "md"],
+    ),
+    ("*.csproj", ".NET", &["cs", "csproj", "json", "md"]),
+    ("*.sln", ".NET", &["cs", "csproj", "json", "md"]),
+    ("mix.exs", "Elixir", &["ex", "exs", "md"]),
+    ("Package.swift", "Swift", &["swift", "md"]),
+    ("pubspec.yaml", "Dart/Flutter", &["dart", "yaml", "md"]),
+    ("cdk.json", "AWS CDK", &["ts", "py", "json", "md"]),
+    (
+        "template.yaml",
+        "AWS SAM",
+        &["yaml", "py", "js", "ts", "md"],
+    ),
+];
+
+// Extensions of text/source files, used when counting what a directory is made of
+pub static SOURCE_EXTENSIONS: &[&str] = &[
+    "rs", "py", "js", "jsx", "ts", "tsx", "go", "java", "kt", "kts", "c", "h", "cc", "cpp", "hpp",
+    "cs", "rb", "php", "swift", "scala", "ex", "exs", "erl", "hs", "ml", "clj", "dart", "lua", "r",
+    "sh", "bash", "zsh", "ps1", "sql", "tf", "hcl", "vue", "svelte", "html", "css", "scss", "toml",
+    "yaml", "yml", "json", "xml", "md", "ron", "zig", "nim", "pl",
+];
+
 pub static CODE_IGNORE_DIRS: &[&str] = &[
     // Rust
     "target",
@@ -119,9 +210,22 @@ pub static CODE_IGNORE_DIRS: &[&str] = &[
 pub static CONFIG_DIR_NAME: &str = "bedrust";
 pub static MODEL_CONFIG_FILE_NAME: &str = "model_config.ron";
 pub static BEDRUST_CONFIG_FILE_NAME: &str = "bedrust_config.ron";
+// current version of the configuration schema, see the migrations in `config.rs`
+pub static CONFIG_VERSION: u32 = 2;
+// per-project configuration, looked up from the current directory upwards
+pub static PROJECT_CONFIG_FILE_NAME: &str = ".bedrust.ron";
+// prefix for configuration overrides through environment variables
+// e.g. BEDRUST_INFERENCE_PARAMS__TEMPERATURE=0.2
+pub static CONFIG_ENV_PREFIX: &str = "BEDRUST_";
 
-// UPDATED: 2024-08-02
+pub static DEFAULT_CAPTION_PROFILE_PROMPT: &str = "Describe the following image, for the sake of accessibility and search. Fill in every field, and keep it clean.";
+pub static DEFAULT_VIDEO_FRAME_PROMPT: &str = "This is a frame from a video. Describe what is happening in it, for an audio description of the video for people who can not see it. Return just the description, in one or two sentences, and nothing else.";
+pub static DEFAULT_CAPTION_PROMPT: &str = "Please caption the following image for the sake of accessibility. Return just the caption, and nothing else. Keep it clean, and under 100 words.";
+
+// UPDATED: 2026-10-18
 pub static BEDRUST_CONFIG_FILE: &str = r#"BedrustConfig(
+  // version of this configuration file - used to upgrade older files
+  version: 2,
   // define what AWS profile to use
   aws_profile: "default",
   // what image extensions do you wish to support when running captioning
@@ -130,10 +234,160 @@ pub static BEDRUST_CONFIG_FILE: &str = r#"BedrustConfig(
   caption_prompt: "Please caption the following image for the sake of accessibility. Return just the caption, and nothing else. Keep it clean, and under 100 words.",
   // choose to show the big ASCII banner on startup or not
   show_banner: true,
+  // show the reasoning of thinking models while they answer (toggle it in chat with /thinking)
+  show_reasoning: true,
   inference_params: (
     temperature: 0.5,
     max_tokens: 2048,
     top_p: 0.8, 
+    // the model stops generating when it produces one of these
+    stop_sequences: [],
+    // cache the source code and long conversations, on the models that support prompt caching
+    prompt_caching: true,
+    // model specific fields, sent as `additionalModelRequestFields`, e.g. {"top_k": 50}
+    additional_fields: {},
+  ),
+  // per model overrides of the inference parameters above, keyed by model name
+  // e.g. {"NovaPro": (temperature: 0.7, additional_fields: {"inferenceConfig": {"topK": 20}})}
+  // or enable extended thinking for a model: {"ClaudeV37Sonnet": (thinking_budget: 4096, max_tokens: 8192)}
+  model_inference_params: {},
+  // an Amazon Bedrock Guardrail applied to every model call, e.g. id: Some("abc123xyz")
+  // trace can be Disabled, Enabled or EnabledFull - the trace is stored in saved chats
+  guardrail: (
+    id: None,
+    version: "DRAFT",
+    trace: Enabled,
+  ),
+  // limits for the source code sent in code chat (--source)
+  code_chat: (
+    // how deep to look into the directory, None means no limit
+    max_depth: None,
+    // skip files larger than this (in bytes)
+    max_file_size: 262144,
+    // the most tokens of source code to send, None means what fits in the context window
+    token_budget: None,
+    // globs of the files to send (e.g. ["src/**/*.rs"]), otherwise the project type is detected
+    include: [],
+    // globs of the files to never send (e.g. ["**/*_test.go"])
+    exclude: [],
+    // ask the helper model for the project type, if it can not be detected from the files
+    llm_project_guess: false,
+  ),
+  // the embedding index of the source code, built with `bedrust index <dir>`. When the
+  // directory given to --source has an index, only the chunks relevant to each question are sent
+  index: (
+    // TitanEmbedTextV2, TitanEmbedTextV1, CohereEmbedEnglish or CohereEmbedMultilingual
+    embedding_model: TitanEmbedTextV2,
+    // lines of code in a chunk, and how many lines neighbouring chunks share
+    chunk_lines: 60,
+    chunk_overlap: 10,
+    // how many chunks are sent with every question
+    top_k: 8,
+    // set to false to always send the whole source code
+    enabled: true,
+  ),
+  // image captioning (--caption). Throttled requests are retried with an exponential backoff
+  caption: (
+    // how many images are captioned at the same time
+    concurrency: 4,
+    // how many times an image is retried when the model is throttled or not ready
+    max_retries: 5,
+    // larger images are downscaled to fit in this many pixels (at most 8000). Claude downscales
+    // anything over 1568 pixels on its own, so sending more only makes the upload slower
+    max_dimension: 1568,
+    // look for images in the subdirectories too, same as `--recursive`
+    recursive: false,
+    // globs of the images to caption (e.g. ["products/**"]), matched case insensitively
+    include: [],
+    // globs of the images to never caption (e.g. ["**/thumbnails/**"])
+    exclude: [],
+    // captioning from s3:// talks to this S3 compatible endpoint instead of Amazon S3, e.g.
+    // Some("http://localhost:9000") for MinIO
+    s3_endpoint: None,
+    // caption with one of the profiles below (or use --profile), e.g. Some("cms")
+    profile: None,
+    // every profile extracts several fields from an image in one call. The kind of a field is
+    // Text, List (of strings) or Flag (true or false). The first field is also the caption that
+    // goes into sidecars, the image metadata and the gallery
+    profiles: {
+      "cms": (
+        prompt: "Describe the following image, for the sake of accessibility and search. Fill in every field, and keep it clean.",
+        fields: [
+          (name: "alt_text", kind: Text, description: "Short alt text for the image, under 125 characters"),
+          (name: "description", kind: Text, description: "A detailed description of the image, in a few sentences"),
+          (name: "tags", kind: List, description: "Keywords for searching the image, lowercase"),
+          (name: "text", kind: Text, description: "All the text visible in the image, word for word. Empty if there is none"),
+          (name: "sensitive", kind: Flag, description: "true if the image shows nudity, violence, gore or anything else not safe for work"),
+        ],
+      ),
+    },
+    // captioning with a Bedrock batch inference job (--batch), for large sets of images. Half the
+    // price of captioning them one by one, but it can take up to a day and needs 100 images or more
+    batch: (
+      // caption every run with a batch job
+      enabled: false,
+      // where the model inputs and outputs go, e.g. Some("s3://my-bucket/bedrust-batch/")
+      s3_uri: None,
+      // the service role Bedrock uses to read and write `s3_uri`
+      // e.g. Some("arn:aws:iam::123456789012:role/BedrockBatchRole")
+      role_arn: None,
+      // how often (in seconds) to check on the job
+      poll_seconds: 60,
+      // how long the job can run before it is stopped (24 to 168 hours)
+      timeout_hours: None,
+    ),
+    // describing videos (bedrust video), the frames are sampled with ffmpeg (animated GIF, WebP
+    // and PNG files work without it)
+    video: (
+      // the prompt every sampled frame is described with
+      prompt: "This is a frame from a video. Describe what is happening in it, for an audio description of the video for people who can not see it. Return just the description, in one or two sentences, and nothing else.",
+      // seconds between the sampled frames
+      interval: 10.0,
+      // how much the picture has to change to count as a new scene (0 to 1), with --scenes
+      scene_threshold: 0.3,
+      // stop sampling after this many frames
+      max_frames: 500,
+      // the ffmpeg and ffprobe commands, or their full paths
+      ffmpeg: "ffmpeg",
+      ffprobe: "ffprobe",
+    ),
+  ),
+  // exporting the chat with `/h` (`/h <path>` writes somewhere else for one export)
+  export: (
+    // the directory the exports go to, the current directory when None (e.g. Some("/home/me/chats"))
+    dir: None,
+    // a `chat_export.html` handlebars template in this directory is used instead of the built-in
+    // one. None is ~/.config/bedrust/templates
+    template_dir: None,
+    // keep the source code sent in code chat (collapsed), instead of removing it (`/h --source`)
+    keep_source: false,
+  ),
+  // the model used for generating chat titles, summaries and guessing project types.
+  // each task can use its own `model`, otherwise the one below is used. If the model
+  // is not accessible, Bedrust falls back to the model you are chatting with.
+  helper_model: (
+    model: Some(ClaudeV3Haiku),
+    title: (
+      inference_params: (
+        temperature: 0.2,
+        max_tokens: 32,
+        top_p: 0.8,
+      ),
+    ),
+    summary: (
+      inference_params: (
+        temperature: 0.2,
+        max_tokens: 256,
+        top_p: 0.8,
+      ),
+    ),
+    project_guess: (
+      inference_params: (
+        temperature: 0.2,
+        max_tokens: 2048,
+        top_p: 0.8,
+      ),
+    ),
   ),
 )
 "#;
@@ -143,3 +397,7 @@ pub const FIGLET_FONT: &str = include_str!("../resources/ansishadow.flf");
 
 // HTML TEMPLATE FOR EXPORT - load from resources
 pub static HTML_TW_TEMPLATE: &str = include_str!("../resources/html/export_template.html");
+// the print friendly export (`/export print`), for saving as PDF
+pub static HTML_PRINT_TEMPLATE: &str = include_str!("../resources/html/print_template.html");
+// the gallery of captioned images (`--output captions.html`)
+pub static CAPTION_GALLERY_TEMPLATE: &str = include_str!("../resources/html/caption_gallery.html");
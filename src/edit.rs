@@ -0,0 +1,756 @@
+use crate::code::{
+    build_overrides, get_all_files, hash_contents, project_data_path, read_source_file,
+    SourceFilter,
+};
+use crate::constants;
+use crate::models::converse::BedrockConverseError;
+use crate::utils::{
+    document_to_json, json_to_document, print_warning, CodeChatConfig, GuardrailConfig,
+    InferenceParams,
+};
+use anyhow::anyhow;
+use aws_sdk_bedrockruntime::types::{
+    ContentBlock, ConversationRole, Message, StopReason, SystemContentBlock, Tool,
+    ToolConfiguration, ToolInputSchema, ToolResultBlock, ToolResultContentBlock, ToolResultStatus,
+    ToolSpecification, ToolUseBlock,
+};
+use chrono::Local;
+use colored::*;
+use dialoguer::Confirm;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use similar::{ChangeTag, TextDiff};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// NOTE:
+// The code-edit mode (`--source <dir> --edit`). Instead of sending the source code up front, the
+// model gets tools to list, read and search the files under the source directory, and a tool to
+// propose a patch. Every patch is shown as a colored diff and only applied if the user says so.
+// Applied patches are written to an undo journal, and `/undo` reverts the last one.
+// The model can never touch anything outside of the source directory.
+
+// how many tool calls a single question can take, so the model can not loop forever
+const MAX_TOOL_ROUNDS: usize = 25;
+// limits on what the tools return to the model
+const MAX_READ_CHARS: usize = 100_000;
+const MAX_SEARCH_MATCHES: usize = 200;
+
+pub struct EditSession {
+    root: PathBuf,
+    config: CodeChatConfig,
+    // the model only gets to read the files code chat would send
+    filter: SourceFilter,
+    // the conversation with the tool calls and results, which the chat history can not hold
+    messages: Vec<Message>,
+    journal: UndoJournal,
+}
+
+impl EditSession {
+    pub fn new(root: &Path, config: &CodeChatConfig) -> Result<Self, anyhow::Error> {
+        let root = root
+            .canonicalize()
+            .map_err(|_| anyhow!("🔴 | The specified path does not exist. Sorry!"))?;
+        let journal = UndoJournal::load(&root)?;
+        Ok(EditSession {
+            filter: SourceFilter::new(&root, config)?,
+            root,
+            config: config.clone(),
+            messages: Vec::new(),
+            journal,
+        })
+    }
+
+    pub fn clear(&mut self) {
+        self.messages.clear();
+    }
+
+    // Asks the model, running the tools it calls until it is done. Returns its final answer.
+    pub async fn ask(
+        &mut self,
+        client: &aws_sdk_bedrockruntime::Client,
+        model_id: &str,
+        inference_parameters: &InferenceParams,
+        guardrail: &GuardrailConfig,
+        question: &str,
+    ) -> Result<String, anyhow::Error> {
+        let start = self.messages.len();
+        let result = self
+            .run(client, model_id, inference_parameters, guardrail, question)
+            .await;
+        // a failed turn would leave the conversation without an answer to the question
+        if result.is_err() {
+            self.messages.truncate(start);
+        }
+        result
+    }
+
+    async fn run(
+        &mut self,
+        client: &aws_sdk_bedrockruntime::Client,
+        model_id: &str,
+        inference_parameters: &InferenceParams,
+        guardrail: &GuardrailConfig,
+        question: &str,
+    ) -> Result<String, anyhow::Error> {
+        self.messages.push(
+            Message::builder()
+                .role(ConversationRole::User)
+                .content(ContentBlock::Text(question.to_string()))
+                .build()?,
+        );
+        let system = SystemContentBlock::Text(
+            constants::CODE_EDIT_SYSTEM_PROMPT.replace("{ROOT}", &self.root.to_string_lossy()),
+        );
+        let mut answer = String::new();
+
+        for _ in 0..MAX_TOOL_ROUNDS {
+            let response = client
+                .converse()
+                .model_id(model_id)
+                .system(system.clone())
+                .set_messages(Some(self.messages.clone()))
+                .tool_config(tool_configuration()?)
+                .inference_config(inference_parameters.to_inference_configuration())
+                .set_additional_model_request_fields(
+                    inference_parameters.additional_model_request_fields(),
+                )
+                .set_guardrail_config(guardrail.to_guardrail_configuration())
+                .send()
+                .await
+                .map_err(|e| match e.as_service_error() {
+                    Some(e) => anyhow!(BedrockConverseError::from(e)),
+                    None => anyhow!("Unknown service error"),
+                })?;
+
+            let stop_reason = response.stop_reason().clone();
+            let message = response
+                .output()
+                .and_then(|o| o.as_message().ok())
+                .cloned()
+                .ok_or_else(|| anyhow!("🔴 | The model did not return a message"))?;
+
+            let mut results = Vec::new();
+            for block in message.content() {
+                match block {
+                    ContentBlock::Text(text) => {
+                        println!("{}", text);
+                        answer.push_str(text);
+                        answer.push('\n');
+                    }
+                    ContentBlock::ToolUse(tool_use) => results.push(self.run_tool(tool_use)?),
+                    _ => {}
+                }
+            }
+            self.messages.push(message);
+
+            if stop_reason != StopReason::ToolUse || results.is_empty() {
+                return Ok(answer.trim_end().to_string());
+            }
+            self.messages.push(
+                Message::builder()
+                    .role(ConversationRole::User)
+                    .set_content(Some(results))
+                    .build()?,
+            );
+        }
+        Err(anyhow!(
+            "🔴 | The model used more than {} rounds of tools, stopping here",
+            MAX_TOOL_ROUNDS
+        ))
+    }
+
+    fn run_tool(&mut self, tool_use: &ToolUseBlock) -> Result<ContentBlock, anyhow::Error> {
+        let input = document_to_json(tool_use.input());
+        let result = match tool_use.name() {
+            "list_files" => self.list_files(),
+            "read_file" => self.read_file(&input),
+            "search" => self.search(&input),
+            "propose_patch" => self.propose_patch(&input),
+            name => Err(format!("There is no tool called {}", name)),
+        };
+        let (text, status) = match result {
+            Ok(text) => (text, ToolResultStatus::Success),
+            Err(e) => (e, ToolResultStatus::Error),
+        };
+        Ok(ContentBlock::ToolResult(
+            ToolResultBlock::builder()
+                .tool_use_id(tool_use.tool_use_id())
+                .content(ToolResultContentBlock::Text(text))
+                .status(status)
+                .build()?,
+        ))
+    }
+
+    // ######################################## TOOLS
+    // The tools return the text for the model, or an error the model gets to see (and fix)
+
+    fn source_files(&self) -> Result<Vec<PathBuf>, String> {
+        let filters = build_overrides(&self.root, &self.config).map_err(|e| e.to_string())?;
+        get_all_files(&self.root, None, self.config.max_depth, &filters).map_err(|e| e.to_string())
+    }
+
+    fn list_files(&self) -> Result<String, String> {
+        println!("{}", "🔧 | Listing the files".dimmed());
+        Ok(self
+            .source_files()?
+            .iter()
+            .map(|f| f.strip_prefix(&self.root).unwrap_or(f).to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    fn read_file(&self, input: &Value) -> Result<String, String> {
+        let name = string_field(input, "path")?;
+        println!("{}", format!("🔧 | Reading {}", name).dimmed());
+        let path = resolve_path(&self.root, name)?;
+        if !path.is_file() || !self.filter.contains(&path) {
+            return Err(format!(
+                "{} is not one of the source files, use list_files to see them",
+                name
+            ));
+        }
+        let contents = read_source_file(&path, self.config.max_file_size)
+            .map_err(|reason| format!("Can not read {}: {}", name, reason))?;
+        let start = input["start_line"].as_u64().unwrap_or(1).max(1) as usize;
+        let end = input["end_line"].as_u64().map(|e| e as usize);
+        let mut text = String::new();
+        for (i, line) in contents.lines().enumerate().skip(start - 1) {
+            if end.is_some_and(|end| i + 1 > end) || text.len() > MAX_READ_CHARS {
+                text.push_str("... (use start_line and end_line to read the rest)\n");
+                break;
+            }
+            text.push_str(&format!("{:>5} | {}\n", i + 1, line));
+        }
+        Ok(text)
+    }
+
+    fn search(&self, input: &Value) -> Result<String, String> {
+        let pattern = string_field(input, "pattern")?;
+        println!("{}", format!("🔧 | Searching for {}", pattern).dimmed());
+        let regex = Regex::new(pattern).map_err(|e| format!("Invalid regex: {}", e))?;
+        let mut matches = Vec::new();
+        for file in self.source_files()? {
+            // binary, non UTF-8 and oversized files are skipped
+            let Ok(contents) = read_source_file(&file, self.config.max_file_size) else {
+                continue;
+            };
+            let name = file.strip_prefix(&self.root).unwrap_or(&file);
+            for (i, line) in contents.lines().enumerate() {
+                if regex.is_match(line) {
+                    matches.push(format!("{}:{}: {}", name.display(), i + 1, line.trim()));
+                }
+            }
+        }
+        if matches.is_empty() {
+            return Ok("No matches".into());
+        }
+        let total = matches.len();
+        matches.truncate(MAX_SEARCH_MATCHES);
+        if total > MAX_SEARCH_MATCHES {
+            matches.push(format!(
+                "... and {} more matches",
+                total - MAX_SEARCH_MATCHES
+            ));
+        }
+        Ok(matches.join("\n"))
+    }
+
+    fn propose_patch(&mut self, input: &Value) -> Result<String, String> {
+        let patch: Patch =
+            serde_json::from_value(input.clone()).map_err(|e| format!("Invalid patch: {}", e))?;
+        let path = resolve_path(&self.root, &patch.path)?;
+        // the model can only patch (and create) the files it could read, and never the
+        // repository itself - a failed edit would tell it what is in the file otherwise
+        if Path::new(&patch.path)
+            .components()
+            .any(|c| c.as_os_str() == ".git")
+        {
+            return Err(format!(
+                "{} is inside .git, it can not be patched",
+                patch.path
+            ));
+        }
+        if path.is_dir() || !self.filter.contains(&path) {
+            return Err(format!(
+                "{} is not one of the source files, it can not be patched",
+                patch.path
+            ));
+        }
+        let before = if path.exists() {
+            Some(
+                read_source_file(&path, self.config.max_file_size)
+                    .map_err(|reason| format!("Can not read {}: {}", patch.path, reason))?,
+            )
+        } else {
+            None
+        };
+        let after = apply_edits(before.as_deref(), &patch.edits)?;
+
+        println!("----------------------------------------");
+        println!("📝 | {} - {}", patch.path.cyan(), patch.description);
+        print_diff(before.as_deref().unwrap_or_default(), &after);
+        let apply = Confirm::new()
+            .with_prompt("Apply this patch?")
+            .default(false)
+            .interact()
+            .unwrap_or(false);
+        if !apply {
+            return Ok("The user rejected this patch, it was not applied.".into());
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        fs::write(&path, &after).map_err(|e| format!("Can not write {}: {}", patch.path, e))?;
+        self.journal
+            .record(JournalEntry {
+                path: PathBuf::from(&patch.path),
+                description: patch.description,
+                before,
+                after_hash: hash_contents(&after),
+                applied: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            })
+            .map_err(|e| e.to_string())?;
+        println!("✅ | Patched {} (use /undo to revert it)", patch.path);
+        Ok("The patch was applied.".into())
+    }
+    // ######################################## END TOOLS
+
+    // Reverts the last applied patch
+    pub fn undo(&mut self) -> Result<(), anyhow::Error> {
+        let Some(entry) = self.journal.entries.last() else {
+            println!("↩️  | There is nothing to undo");
+            return Ok(());
+        };
+        let path =
+            resolve_path(&self.root, &entry.path.to_string_lossy()).map_err(|e| anyhow!(e))?;
+        let current = fs::read_to_string(&path).ok();
+        if current.as_deref().map(hash_contents) != Some(entry.after_hash.clone()) {
+            print_warning(&format!(
+                "{} changed since the patch was applied, undoing it loses those changes",
+                entry.path.display()
+            ));
+            if !Confirm::new()
+                .with_prompt("Undo anyway?")
+                .default(false)
+                .interact()?
+            {
+                return Ok(());
+            }
+        }
+        match &entry.before {
+            Some(before) => fs::write(&path, before)?,
+            // the patch created the file
+            None => fs::remove_file(&path)?,
+        }
+        println!(
+            "↩️  | Reverted {} ({})",
+            entry.path.display().to_string().cyan(),
+            entry.description
+        );
+        self.journal.entries.pop();
+        self.journal.save()?;
+        Ok(())
+    }
+}
+
+// ######################################## PATCHES
+#[derive(Debug, Deserialize)]
+struct Patch {
+    path: String,
+    description: String,
+    edits: Vec<Edit>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Edit {
+    old_text: String,
+    new_text: String,
+}
+
+// Applies search and replace edits. Every `old_text` has to be found exactly once, and an empty
+// `old_text` creates a new file.
+fn apply_edits(original: Option<&str>, edits: &[Edit]) -> Result<String, String> {
+    if edits.is_empty() {
+        return Err("The patch has no edits".into());
+    }
+    let mut text =
+        match original {
+            Some(text) => text.to_string(),
+            None if edits.len() == 1 && edits[0].old_text.is_empty() => {
+                return Ok(edits[0].new_text.clone());
+            }
+            None => return Err(
+                "The file does not exist, to create it use a single edit with an empty old_text"
+                    .into(),
+            ),
+        };
+    for (i, edit) in edits.iter().enumerate() {
+        if edit.old_text.is_empty() {
+            return Err(format!(
+                "Edit {} has an empty old_text, but the file exists",
+                i + 1
+            ));
+        }
+        match text.matches(edit.old_text.as_str()).count() {
+            0 => {
+                return Err(format!(
+                    "The old_text of edit {} was not found in the file, read the file again and copy it exactly",
+                    i + 1
+                ))
+            }
+            1 => text = text.replacen(edit.old_text.as_str(), &edit.new_text, 1),
+            n => {
+                return Err(format!(
+                    "The old_text of edit {} was found {} times, include more lines to make it unique",
+                    i + 1,
+                    n
+                ))
+            }
+        }
+    }
+    Ok(text)
+}
+
+// Resolves a path given by the model, refusing anything outside of the source directory
+fn resolve_path(root: &Path, name: &str) -> Result<PathBuf, String> {
+    let relative = Path::new(name);
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(format!(
+            "{} is not allowed, use a path relative to the source directory, without ..",
+            name
+        ));
+    }
+    let path = root.join(relative);
+    // follow symbolic links of what exists already, they could point outside
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .and_then(|p| p.canonicalize().ok());
+    if !existing.is_some_and(|p| p.starts_with(root)) {
+        return Err(format!("{} is outside of the source directory", name));
+    }
+    Ok(path)
+}
+
+fn print_diff(before: &str, after: &str) {
+    let diff = TextDiff::from_lines(before, after);
+    for hunk in diff.unified_diff().context_radius(3).iter_hunks() {
+        println!("{}", hunk.header().to_string().cyan());
+        for change in hunk.iter_changes() {
+            let line = change.to_string_lossy();
+            let line = line.trim_end_matches('\n');
+            match change.tag() {
+                ChangeTag::Delete => println!("{}", format!("-{}", line).red()),
+                ChangeTag::Insert => println!("{}", format!("+{}", line).green()),
+                ChangeTag::Equal => println!(" {}", line.dimmed()),
+            }
+        }
+    }
+}
+
+fn string_field<'a>(input: &'a Value, field: &str) -> Result<&'a str, String> {
+    input[field]
+        .as_str()
+        .ok_or_else(|| format!("The {} field is missing", field))
+}
+
+fn tool_configuration() -> Result<ToolConfiguration, anyhow::Error> {
+    let tools = [
+        (
+            "list_files",
+            "Lists the source files (relative to the source directory).",
+            json!({"type": "object", "properties": {}}),
+        ),
+        (
+            "read_file",
+            "Reads a file, with line numbers. Use start_line and end_line for large files.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "path": {"type": "string", "description": "Relative to the source directory"},
+                    "start_line": {"type": "integer"},
+                    "end_line": {"type": "integer"}
+                },
+                "required": ["path"]
+            }),
+        ),
+        (
+            "search",
+            "Searches the source files for a regular expression (Rust regex syntax), line by line. Returns file:line: text for every match.",
+            json!({
+                "type": "object",
+                "properties": {"pattern": {"type": "string"}},
+                "required": ["pattern"]
+            }),
+        ),
+        (
+            "propose_patch",
+            "Proposes a change to a single file. The user sees the diff and decides if it gets applied. Every old_text has to match the current file exactly (including whitespace) and only once. To create a new file, use a single edit with an empty old_text.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "path": {"type": "string", "description": "Relative to the source directory"},
+                    "description": {"type": "string", "description": "What the patch does, in one sentence"},
+                    "edits": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "old_text": {"type": "string"},
+                                "new_text": {"type": "string"}
+                            },
+                            "required": ["old_text", "new_text"]
+                        }
+                    }
+                },
+                "required": ["path", "description", "edits"]
+            }),
+        ),
+    ];
+    let tools = tools
+        .into_iter()
+        .map(|(name, description, schema)| {
+            Ok(Tool::ToolSpec(
+                ToolSpecification::builder()
+                    .name(name)
+                    .description(description)
+                    .input_schema(ToolInputSchema::Json(json_to_document(&schema)))
+                    .build()?,
+            ))
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    Ok(ToolConfiguration::builder()
+        .set_tools(Some(tools))
+        .build()?)
+}
+// ######################################## END PATCHES
+
+// ######################################## UNDO JOURNAL
+// Every applied patch, with the contents of the file before it. Stored next to the index:
+// ~/.config/bedrust/edits/<dir name>-<hash>.json
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JournalEntry {
+    // relative to the source directory
+    pub path: PathBuf,
+    pub description: String,
+    // None if the patch created the file
+    pub before: Option<String>,
+    // to tell if the file was changed after the patch
+    pub after_hash: String,
+    pub applied: String,
+}
+
+struct UndoJournal {
+    path: PathBuf,
+    entries: Vec<JournalEntry>,
+}
+
+impl UndoJournal {
+    fn load(root: &Path) -> Result<Self, anyhow::Error> {
+        UndoJournal::open(project_data_path(root, "edits")?)
+    }
+
+    fn open(path: PathBuf) -> Result<Self, anyhow::Error> {
+        let entries = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(_) => Vec::new(),
+        };
+        Ok(UndoJournal { path, entries })
+    }
+
+    fn record(&mut self, entry: JournalEntry) -> Result<(), anyhow::Error> {
+        self.entries.push(entry);
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), anyhow::Error> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(&self.entries)?)?;
+        Ok(())
+    }
+}
+// ######################################## END UNDO JOURNAL
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+
+    fn edit(old_text: &str, new_text: &str) -> Edit {
+        Edit {
+            old_text: old_text.into(),
+            new_text: new_text.into(),
+        }
+    }
+
+    fn session(root: &Path, config: CodeChatConfig, journal: PathBuf) -> EditSession {
+        EditSession {
+            root: root.to_path_buf(),
+            filter: SourceFilter::new(root, &config).unwrap(),
+            config,
+            messages: Vec::new(),
+            journal: UndoJournal::open(journal).unwrap(),
+        }
+    }
+
+    // src/ with one source file, and next to it a secret, a binary and an ignored file
+    fn project() -> (TempDir, PathBuf) {
+        let dir = TempDir::new("bedrust-edit").unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        let root = dir.path().join("src").canonicalize().unwrap();
+        fs::write(root.join("main.rs"), "fn main() {}\n").unwrap();
+        fs::write(root.join(".env"), "SECRET=1\n").unwrap();
+        fs::write(root.join("data.rs"), b"\0\x01binary").unwrap();
+        fs::write(root.join(".gitignore"), "generated.rs\n").unwrap();
+        fs::write(root.join("generated.rs"), "// generated\n").unwrap();
+        (dir, root)
+    }
+
+    #[test]
+    fn edits_must_match_exactly_once() {
+        let original = "fn a() {}\nfn b() {}\nfn b() {}\n";
+        assert_eq!(
+            apply_edits(Some(original), &[edit("fn a() {}", "fn a() { 1 }")]).unwrap(),
+            "fn a() { 1 }\nfn b() {}\nfn b() {}\n"
+        );
+        assert!(apply_edits(Some(original), &[edit("fn b() {}", "")])
+            .unwrap_err()
+            .contains("found 2 times"));
+        assert!(apply_edits(Some(original), &[edit("fn c", "")]).is_err());
+        assert_eq!(apply_edits(None, &[edit("", "new\n")]).unwrap(), "new\n");
+        assert!(apply_edits(None, &[edit("x", "y")]).is_err());
+    }
+
+    #[test]
+    fn paths_stay_in_the_root() {
+        let (_dir, root) = project();
+        assert_eq!(
+            resolve_path(&root, "lib/new.rs").unwrap(),
+            root.join("lib/new.rs")
+        );
+        assert!(resolve_path(&root, "../secret").is_err());
+        assert!(resolve_path(&root, "/etc/passwd").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symbolic_links_can_not_lead_outside_the_root() {
+        let (dir, root) = project();
+        let outside = dir.path().join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("linked")).unwrap();
+        std::os::unix::fs::symlink(outside.join("secret.txt"), root.join("secret.txt")).unwrap();
+
+        assert!(resolve_path(&root, "linked/secret.txt").is_err());
+        // not even to create a file in there
+        assert!(resolve_path(&root, "linked/new.rs").is_err());
+        assert!(resolve_path(&root, "secret.txt").is_err());
+        assert!(resolve_path(&root, "./lib/../../outside/secret.txt").is_err());
+    }
+
+    #[test]
+    fn patches_stay_within_the_source_files() {
+        let (dir, root) = project();
+        fs::create_dir_all(root.join(".git/hooks")).unwrap();
+        let config = CodeChatConfig {
+            exclude: vec!["*_test.rs".into()],
+            ..Default::default()
+        };
+        let mut session = session(&root, config, dir.path().join("journal.json"));
+        for path in [
+            ".git/hooks/pre-commit",
+            ".env",
+            "generated.rs",
+            "lib_test.rs",
+            ".git",
+        ] {
+            let patch = json!({
+                "path": path,
+                "description": "probe",
+                "edits": [{ "old_text": "SECRET", "new_text": "" }],
+            });
+            let refused = session.propose_patch(&patch).unwrap_err();
+            assert!(
+                refused.contains("can not be patched"),
+                "{}: {}",
+                path,
+                refused
+            );
+        }
+        assert_eq!(fs::read_to_string(root.join(".env")).unwrap(), "SECRET=1\n");
+    }
+
+    #[test]
+    fn tools_read_only_the_source_files() {
+        let (dir, root) = project();
+        let session = session(
+            &root,
+            CodeChatConfig::default(),
+            dir.path().join("journal.json"),
+        );
+        let read = |path: &str| session.read_file(&json!({ "path": path }));
+        assert!(read("main.rs").unwrap().contains("fn main() {}"));
+        for path in [".env", "generated.rs", "missing.rs"] {
+            assert!(read(path)
+                .unwrap_err()
+                .contains("is not one of the source files"));
+        }
+        assert!(read("data.rs").unwrap_err().contains("binary"));
+        let found = session.search(&json!({ "pattern": "SECRET|generated|fn" }));
+        assert_eq!(found.unwrap(), "main.rs:1: fn main() {}");
+    }
+
+    #[test]
+    fn undo_reverts_the_patches_newest_first() {
+        let (dir, root) = project();
+        let journal = dir.path().join("journal.json");
+        let mut session = session(&root, CodeChatConfig::default(), journal.clone());
+
+        // a patch that changed main.rs and one that created lib.rs, as `propose_patch` records them
+        fs::write(root.join("main.rs"), "fn main() { run() }\n").unwrap();
+        fs::write(root.join("lib.rs"), "pub fn run() {}\n").unwrap();
+        for (path, before, after) in [
+            ("main.rs", Some("fn main() {}\n"), "fn main() { run() }\n"),
+            ("lib.rs", None, "pub fn run() {}\n"),
+        ] {
+            session
+                .journal
+                .record(JournalEntry {
+                    path: PathBuf::from(path),
+                    description: format!("patch {}", path),
+                    before: before.map(String::from),
+                    after_hash: hash_contents(after),
+                    applied: "2024-10-20 15:30:00".into(),
+                })
+                .unwrap();
+        }
+        let recorded = UndoJournal::open(journal.clone()).unwrap();
+        assert_eq!(recorded.entries.len(), 2);
+        assert_eq!(
+            recorded.entries[0].before.as_deref(),
+            Some("fn main() {}\n")
+        );
+        assert_eq!(
+            recorded.entries[1].after_hash,
+            hash_contents("pub fn run() {}\n")
+        );
+
+        session.undo().unwrap();
+        assert!(!root.join("lib.rs").exists());
+        session.undo().unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("main.rs")).unwrap(),
+            "fn main() {}\n"
+        );
+        assert!(UndoJournal::open(journal).unwrap().entries.is_empty());
+    }
+}
@@ -0,0 +1,407 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use dirs::home_dir;
+use serde_json::json;
+
+use crate::chat::list_chat_histories;
+use crate::chat::{display_title, message_parts, ConversationHistory, MessagePart};
+use crate::constants;
+use crate::utils::{ExportConfig, HistoryCommands};
+
+// NOTE:
+// The formats a conversation can be exported to - `/export <format>` in chat (`/h` is the HTML
+// one), and `bedrust history export` for the saved chats. The source code sent in code chat is
+// left out of every format, unless `keep_source` is set.
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ExportFormat {
+    // the same page as `/h`
+    Html,
+    // plain HTML made for printing, or saving as PDF from the browser
+    Print,
+    // the roles as headings, code blocks kept as they are
+    Markdown,
+    Text,
+    // one conversation per line, in the message shape of the Bedrock Converse API (the format of
+    // Bedrock fine-tuning and evaluation datasets)
+    Jsonl,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Html => "html",
+            ExportFormat::Print => "print.html",
+            ExportFormat::Markdown => "md",
+            ExportFormat::Text => "txt",
+            ExportFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+pub fn history_command(
+    command: &HistoryCommands,
+    config: &ExportConfig,
+) -> Result<(), anyhow::Error> {
+    match command {
+        HistoryCommands::Export {
+            names,
+            all,
+            format,
+            output,
+            source,
+        } => {
+            let names = match all {
+                true => list_chat_histories()?,
+                false => names.clone(),
+            };
+            let histories = names
+                .iter()
+                .map(|name| load_saved_chat(name))
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut config = config.clone();
+            config.keep_source |= source;
+            let files = export_histories(&histories, *format, output.as_deref(), &config)?;
+            for file in &files {
+                println!("💾 | {}", file.display());
+            }
+            println!("✅ | Exported {} chat(s) as {:?}", histories.len(), format);
+            Ok(())
+        }
+    }
+}
+
+// Exports one conversation, `path` can be a file or a directory (see `export_path`)
+pub fn export_history(
+    history: &ConversationHistory,
+    format: ExportFormat,
+    path: Option<&Path>,
+    config: &ExportConfig,
+) -> Result<PathBuf, anyhow::Error> {
+    let render = match format {
+        ExportFormat::Html => {
+            history.render_html("chat_export.html", constants::HTML_TW_TEMPLATE, config)?
+        }
+        // the kept source code is folded away on the page, but printed open
+        ExportFormat::Print => history
+            .render_html("chat_print.html", constants::HTML_PRINT_TEMPLATE, config)?
+            .replace(
+                r#"<details class="source-removed">"#,
+                r#"<details class="source-removed" open>"#,
+            ),
+        ExportFormat::Markdown => to_markdown(history, config.keep_source),
+        ExportFormat::Text => to_text(history, config.keep_source),
+        ExportFormat::Jsonl => format!("{}\n", to_converse_json(history, config.keep_source)),
+    };
+    let file = history.export_path(path, config, format.extension());
+    write_export(&file, &render)?;
+    Ok(file)
+}
+
+// Exports several conversations. JSONL goes into a single file (one line per conversation),
+// unless `path` is a directory. The other formats get a file per conversation in `path`
+pub fn export_histories(
+    histories: &[ConversationHistory],
+    format: ExportFormat,
+    path: Option<&Path>,
+    config: &ExportConfig,
+) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let is_dir = |p: &Path| p.is_dir() || p.to_string_lossy().ends_with('/');
+    match (format, path) {
+        (ExportFormat::Jsonl, Some(file)) if !is_dir(file) => {
+            let lines = histories
+                .iter()
+                .map(|h| format!("{}\n", to_converse_json(h, config.keep_source)))
+                .collect::<String>();
+            write_export(file, &lines)?;
+            Ok(vec![file.to_path_buf()])
+        }
+        (_, Some(file)) if histories.len() > 1 && !is_dir(file) => Err(anyhow!(
+            "🔴 | {} is not a directory, exporting several chats as {:?} writes a file for each",
+            file.display(),
+            format
+        )),
+        _ => histories
+            .iter()
+            .map(|h| export_history(h, format, path, config))
+            .collect(),
+    }
+}
+
+// A saved chat - a path to its file, or the name of a file in ~/.config/bedrust/chats (the
+// `.json` can be left out)
+pub fn load_saved_chat(name: &str) -> Result<ConversationHistory, anyhow::Error> {
+    let chat_dir = home_dir()
+        .unwrap_or_default()
+        .join(format!(".config/{}/chats", constants::CONFIG_DIR_NAME));
+    let file = [
+        PathBuf::from(name),
+        chat_dir.join(name),
+        chat_dir.join(format!("{}.json", name)),
+    ]
+    .into_iter()
+    .find(|p| p.is_file())
+    .ok_or_else(|| anyhow!("🔴 | There is no saved chat called {}", name))?;
+    let content = fs::read_to_string(&file)?;
+    serde_json::from_str(&content)
+        .map_err(|e| anyhow!("🔴 | {} is not a saved chat: {}", file.display(), e))
+}
+
+fn write_export(file: &Path, content: &str) -> Result<(), anyhow::Error> {
+    if let Some(parent) = file.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(file, content)?;
+    Ok(())
+}
+
+fn title(history: &ConversationHistory) -> String {
+    history
+        .title
+        .as_deref()
+        .map(display_title)
+        .unwrap_or_else(|| "Conversation".to_string())
+}
+
+fn role_heading(role: &str) -> &'static str {
+    match role {
+        "user" => "User",
+        _ => "Assistant",
+    }
+}
+
+fn to_markdown(history: &ConversationHistory, keep_source: bool) -> String {
+    let mut md = format!("# {}\n\n_{}_\n\n", title(history), history.timestamp);
+    if let Some(summary) = &history.summary {
+        md.push_str(&format!("> {}\n\n", summary.trim().replace('\n', "\n> ")));
+    }
+    for message in history.messages.iter().flatten() {
+        md.push_str(&format!("## {}\n\n", role_heading(&message.role)));
+        if let Some(reasoning) = &message.reasoning {
+            md.push_str(&format!(
+                "<details>\n<summary>Reasoning</summary>\n\n{}\n\n</details>\n\n",
+                reasoning.trim()
+            ));
+        }
+        for part in message_parts(&message.content.join("\n")) {
+            match part {
+                MessagePart::Text(text) if text.trim().is_empty() => {}
+                MessagePart::Text(text) => md.push_str(&format!("{}\n\n", text.trim())),
+                MessagePart::Source(source) if keep_source => {
+                    // a fence longer than any run of backticks in the source
+                    let fence = "`".repeat(longest_backtick_run(source).max(2) + 1);
+                    md.push_str(&format!("{}\n{}\n{}\n\n", fence, source, fence));
+                }
+                MessagePart::Source(_) => {
+                    md.push_str("_The source code has been removed from the export_\n\n")
+                }
+            }
+        }
+    }
+    md
+}
+
+fn longest_backtick_run(text: &str) -> usize {
+    text.split(|c| c != '`').map(str::len).max().unwrap_or(0)
+}
+
+fn to_text(history: &ConversationHistory, keep_source: bool) -> String {
+    let title = title(history);
+    let mut txt = format!(
+        "{}\n{}\n{}\n\n",
+        title,
+        "=".repeat(title.len()),
+        history.timestamp
+    );
+    if let Some(summary) = &history.summary {
+        txt.push_str(&format!("{}\n\n", summary.trim()));
+    }
+    for message in history.messages.iter().flatten() {
+        txt.push_str(&format!(
+            "[{}]\n",
+            role_heading(&message.role).to_uppercase()
+        ));
+        for part in message_parts(&message.content.join("\n")) {
+            match part {
+                MessagePart::Text(text) if text.trim().is_empty() => {}
+                MessagePart::Text(text) => txt.push_str(&format!("{}\n", text.trim())),
+                MessagePart::Source(source) if keep_source => {
+                    txt.push_str(&format!("{}\n", source))
+                }
+                MessagePart::Source(_) => {
+                    txt.push_str("(the source code has been removed from the export)\n")
+                }
+            }
+        }
+        txt.push('\n');
+    }
+    txt
+}
+
+// {"schemaVersion": "bedrock-conversation-2024", "messages": [{"role": "user", "content":
+// [{"text": "..."}]}, ...]} - the reasoning is left out. A message that held nothing but source
+// code is left out too, and so the roles keep alternating, what is left of the same role is joined
+fn to_converse_json(history: &ConversationHistory, keep_source: bool) -> serde_json::Value {
+    let mut messages: Vec<(&str, Vec<serde_json::Value>)> = Vec::new();
+    for message in history.messages.iter().flatten() {
+        let content = message
+            .content
+            .iter()
+            .filter_map(|block| {
+                let text = match keep_source {
+                    true => block.clone(),
+                    false => message_parts(block)
+                        .into_iter()
+                        .filter_map(|part| match part {
+                            MessagePart::Text(text) => Some(text.trim()),
+                            MessagePart::Source(_) => None,
+                        })
+                        .filter(|text| !text.is_empty())
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                };
+                match text.trim().is_empty() {
+                    true => None,
+                    false => Some(json!({ "text": text })),
+                }
+            })
+            .collect::<Vec<_>>();
+        match messages.last_mut() {
+            _ if content.is_empty() => {}
+            Some((role, previous)) if *role == message.role => previous.extend(content),
+            _ => messages.push((&message.role, content)),
+        }
+    }
+    let messages = messages
+        .into_iter()
+        .map(|(role, content)| json!({ "role": role, "content": content }))
+        .collect::<Vec<_>>();
+    json!({
+        "schemaVersion": "bedrock-conversation-2024",
+        "messages": messages,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::SerializableMessage;
+    use crate::utils::TempDir;
+
+    fn message(role: &str, content: Vec<String>) -> SerializableMessage {
+        SerializableMessage {
+            role: role.into(),
+            content,
+            reasoning: None,
+            guardrail: None,
+            cache_point: None,
+        }
+    }
+
+    // source code as it is sent along with a question
+    fn source() -> String {
+        format!(
+            "{}{}let a = \"```\";{}{}",
+            "<bedrust_be", "gin_source>", "</bedrust_en", "d_source>"
+        )
+    }
+
+    fn history() -> ConversationHistory {
+        ConversationHistory {
+            title: Some("rust_lifetimes-Ab3xZ".into()),
+            summary: Some("A chat about lifetimes.".into()),
+            messages: Some(vec![
+                message("user", vec![source(), "Why?".into()]),
+                message(
+                    "assistant",
+                    vec!["Because:\n\n```rust\nfn f<'a>() {}\n```".into()],
+                ),
+            ]),
+            timestamp: "2024-10-20 15:30".into(),
+            inference_params: None,
+        }
+    }
+
+    #[test]
+    fn conversations_export_to_markdown() {
+        let history = history();
+        let md = to_markdown(&history, false);
+        assert!(md.starts_with("# Rust Lifetimes\n\n_2024-10-20 15:30_\n\n> A chat about"));
+        assert!(md.contains(
+            "## User\n\n_The source code has been removed from the export_\n\nWhy?\n\n## Assistant"
+        ));
+        assert!(md.contains("```rust\nfn f<'a>() {}\n```"));
+        // the kept source gets a fence longer than the backticks in it
+        assert!(to_markdown(&history, true).contains("````\nlet a = \"```\";\n````"));
+    }
+
+    #[test]
+    fn conversations_export_to_text() {
+        let txt = to_text(&history(), false);
+        assert!(txt.contains("[USER]\n(the source code has been removed from the export)\nWhy?\n"));
+    }
+
+    #[test]
+    fn conversations_export_to_a_converse_line() {
+        let line = to_converse_json(&history(), false);
+        assert_eq!(line["schemaVersion"], "bedrock-conversation-2024");
+        assert_eq!(
+            line["messages"][0],
+            json!({"role": "user", "content": [{"text": "Why?"}]})
+        );
+        assert_eq!(line["messages"][1]["role"], "assistant");
+        assert!(!line.to_string().contains('\n'));
+    }
+
+    #[test]
+    fn roles_still_alternate_without_the_source_only_messages() {
+        // a message with only source code in it goes, and the answers on both sides of it become
+        // one
+        let mut followup = history();
+        followup.messages.as_mut().unwrap().extend([
+            message("user", vec![source()]),
+            message("assistant", vec!["It compiles now.".into()]),
+            message("user", vec!["And now?".into()]),
+            message("assistant", vec!["Now it works.".into()]),
+        ]);
+        let line = to_converse_json(&followup, false);
+        let roles: Vec<_> = line["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["role"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(roles, vec!["user", "assistant", "user", "assistant"]);
+        assert_eq!(
+            line["messages"][1]["content"][1]["text"],
+            "It compiles now."
+        );
+    }
+
+    #[test]
+    fn several_chats_go_into_one_jsonl_file() {
+        let dir = TempDir::new("bedrust-history").unwrap();
+        let file = dir.path().join("dataset.jsonl");
+        let config = ExportConfig::default();
+        let written = export_histories(
+            &[history(), history()],
+            ExportFormat::Jsonl,
+            Some(&file),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(written, vec![file.clone()]);
+        assert_eq!(fs::read_to_string(&file).unwrap().lines().count(), 2);
+        // the other formats are a file per chat
+        assert!(export_histories(
+            &[history(), history()],
+            ExportFormat::Markdown,
+            Some(&file),
+            &config
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,605 @@
+use crate::chat::SerializableMessage;
+use crate::code::{
+    estimate_tokens, find_source_files, format_bytes, hash_contents, project_data_path,
+    read_source_file, SkipReason,
+};
+use crate::constants;
+use crate::models::embeddings::{embed, EmbeddingInput};
+use crate::utils::{print_warning, BedrustConfig, CodeChatConfig, EmbeddingModel, IndexConfig};
+use anyhow::anyhow;
+use chrono::Local;
+use colored::*;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+// NOTE:
+// Sending the whole source code does not scale past small projects. So `bedrust index <dir>`
+// splits the source files into chunks of lines, and embeds each chunk with a Bedrock embedding
+// model. The vectors are stored in a JSON file in the Bedrust config directory (one per source
+// directory). Running it again only embeds the files whose modification time AND contents changed.
+// In code chat, the question is embedded as well, and the `top_k` most similar chunks are sent
+// along with it.
+
+// version of the index file format, older indexes get rebuilt
+const INDEX_VERSION: u32 = 1;
+// the embedding models take a few thousand tokens at most, longer chunks (minified files ...)
+// are cut
+const MAX_CHUNK_CHARS: usize = 20_000;
+// how many files get embedded between two saves of the index
+const SAVE_EVERY_FILES: usize = 25;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CodeIndex {
+    pub version: u32,
+    pub root: PathBuf,
+    pub embedding_model: EmbeddingModel,
+    pub chunk_lines: usize,
+    pub chunk_overlap: usize,
+    pub updated: String,
+    // keyed by the path relative to the root
+    pub files: BTreeMap<String, IndexedFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IndexedFile {
+    // modification time in milliseconds since the epoch
+    pub mtime: u64,
+    // sha256 of the contents
+    pub hash: String,
+    pub chunks: Vec<Chunk>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Chunk {
+    // first and last line of the chunk, starting at 1
+    pub start_line: usize,
+    pub end_line: usize,
+    pub text: String,
+    pub embedding: Vec<f32>,
+}
+
+// A file that needs to be embedded (again)
+#[derive(Debug)]
+pub struct PendingFile {
+    pub name: String,
+    pub mtime: u64,
+    pub hash: String,
+    pub contents: String,
+}
+
+// What an update of the index has to do
+#[derive(Debug, Default)]
+pub struct IndexPlan {
+    pub changed: Vec<PendingFile>,
+    // files with a new modification time, but the same contents
+    pub touched: Vec<(String, u64)>,
+    pub unchanged: usize,
+    pub removed: Vec<String>,
+    pub skipped: Vec<(PathBuf, SkipReason)>,
+}
+
+impl IndexPlan {
+    pub fn is_up_to_date(&self) -> bool {
+        self.changed.is_empty() && self.touched.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl CodeIndex {
+    pub fn new(root: &Path, config: &IndexConfig) -> Self {
+        CodeIndex {
+            version: INDEX_VERSION,
+            root: root.to_path_buf(),
+            embedding_model: config.embedding_model,
+            chunk_lines: config.chunk_lines,
+            chunk_overlap: config.chunk_overlap,
+            updated: String::new(),
+            files: BTreeMap::new(),
+        }
+    }
+
+    // Loads the index of a source directory, if there is one
+    pub fn load(root: &Path) -> Result<Option<CodeIndex>, anyhow::Error> {
+        let path = index_path(root)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let index = serde_json::from_str(&fs::read_to_string(&path)?)
+            .map_err(|e| anyhow!("🔴 | The index {} is broken: {}", path.display(), e))?;
+        Ok(Some(index))
+    }
+
+    pub fn save(&mut self) -> Result<PathBuf, anyhow::Error> {
+        let path = index_path(&self.root)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        self.updated = Local::now().format("%Y-%m-%d %H:%M").to_string();
+        fs::write(&path, serde_json::to_string(self)?)?;
+        Ok(path)
+    }
+
+    // An index built with another model or other chunk sizes can not be updated, only rebuilt
+    pub fn is_compatible(&self, config: &IndexConfig) -> bool {
+        self.version == INDEX_VERSION
+            && self.embedding_model == config.embedding_model
+            && self.chunk_lines == config.chunk_lines
+            && self.chunk_overlap == config.chunk_overlap
+    }
+
+    // The hash of every indexed file, keyed by the path relative to the root
+    pub fn versions(&self) -> BTreeMap<PathBuf, String> {
+        self.files
+            .iter()
+            .map(|(name, file)| (PathBuf::from(name), file.hash.clone()))
+            .collect()
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.files.values().map(|f| f.chunks.len()).sum()
+    }
+
+    // Compares the source files with the index. Files are only read (and hashed) when their
+    // modification time changed.
+    pub fn plan_update(&self, files: &[PathBuf], max_file_size: u64) -> IndexPlan {
+        let mut plan = IndexPlan::default();
+        let mut seen = BTreeSet::new();
+        for path in files {
+            let name = path
+                .strip_prefix(&self.root)
+                .unwrap_or(path)
+                .to_string_lossy()
+                .to_string();
+            seen.insert(name.clone());
+            let mtime = file_mtime(path);
+            let indexed = self.files.get(&name);
+            if indexed.is_some_and(|f| f.mtime == mtime) {
+                plan.unchanged += 1;
+                continue;
+            }
+            let contents = match read_source_file(path, max_file_size) {
+                Ok(contents) => contents,
+                Err(reason) => {
+                    plan.skipped.push((path.clone(), reason));
+                    continue;
+                }
+            };
+            let hash = hash_contents(&contents);
+            if indexed.is_some_and(|f| f.hash == hash) {
+                plan.touched.push((name, mtime));
+                continue;
+            }
+            plan.changed.push(PendingFile {
+                name,
+                mtime,
+                hash,
+                contents,
+            });
+        }
+        plan.removed = self
+            .files
+            .keys()
+            .filter(|name| !seen.contains(*name))
+            .cloned()
+            .collect();
+        // skipped files (e.g. they got too large) do not stay in the index either
+        plan.removed
+            .extend(plan.skipped.iter().filter_map(|(path, _)| {
+                let name = path.strip_prefix(&self.root).unwrap_or(path);
+                let name = name.to_string_lossy().to_string();
+                self.files.contains_key(&name).then_some(name)
+            }));
+        plan
+    }
+
+    // Applies the plan, embedding the changed files
+    pub async fn update(
+        &mut self,
+        client: &aws_sdk_bedrockruntime::Client,
+        plan: IndexPlan,
+    ) -> Result<(), anyhow::Error> {
+        for name in &plan.removed {
+            self.files.remove(name);
+        }
+        for (name, mtime) in plan.touched {
+            if let Some(file) = self.files.get_mut(&name) {
+                file.mtime = mtime;
+            }
+        }
+
+        // progress bar shenanigans
+        let progress_bar = ProgressBar::new(plan.changed.len().try_into()?);
+        progress_bar.set_style(
+            ProgressStyle::with_template(
+                "{spinner:.green} [{wide_bar:.cyan/blue}] {msg} ({pos}/{len})",
+            )
+            .unwrap()
+            .progress_chars("#>-"),
+        );
+        progress_bar.enable_steady_tick(std::time::Duration::from_millis(100));
+
+        for (i, file) in plan.changed.into_iter().enumerate() {
+            progress_bar.set_message(file.name.clone());
+            let pieces = split_into_chunks(&file.contents, self.chunk_lines, self.chunk_overlap);
+            // the file name and lines are embedded too, so questions about a file find it
+            let texts: Vec<String> = pieces
+                .iter()
+                .map(|(start, end, text)| format!("{}:{}-{}\n{}", file.name, start, end, text))
+                .collect();
+            let vectors = embed(
+                client,
+                self.embedding_model,
+                &texts,
+                EmbeddingInput::Document,
+            )
+            .await?;
+            let chunks = pieces
+                .into_iter()
+                .zip(vectors)
+                .map(|((start_line, end_line, text), embedding)| Chunk {
+                    start_line,
+                    end_line,
+                    text,
+                    embedding,
+                })
+                .collect();
+            self.files.insert(
+                file.name,
+                IndexedFile {
+                    mtime: file.mtime,
+                    hash: file.hash,
+                    chunks,
+                },
+            );
+            progress_bar.inc(1);
+            // NOTE: saving every now and then, so an interrupted run does not start from scratch
+            if (i + 1) % SAVE_EVERY_FILES == 0 {
+                self.save()?;
+            }
+        }
+        progress_bar.finish();
+        self.save()?;
+        Ok(())
+    }
+
+    // The `top_k` chunks most similar to the query vector, the best one first
+    pub fn search(&self, query: &[f32], top_k: usize) -> Vec<(&str, &Chunk, f32)> {
+        let mut scored: Vec<(&str, &Chunk, f32)> = self
+            .files
+            .iter()
+            .flat_map(|(name, file)| {
+                file.chunks
+                    .iter()
+                    .map(move |c| (name.as_str(), c, cosine_similarity(query, &c.embedding)))
+            })
+            .collect();
+        scored.sort_by(|a, b| b.2.total_cmp(&a.2));
+        scored.truncate(top_k);
+        scored
+    }
+
+    // Embeds the question and formats the most relevant chunks the way they are sent to the model
+    pub async fn retrieve_context(
+        &self,
+        client: &aws_sdk_bedrockruntime::Client,
+        question: &str,
+        top_k: usize,
+    ) -> Result<String, anyhow::Error> {
+        let query = embed(
+            client,
+            self.embedding_model,
+            &[question.to_string()],
+            EmbeddingInput::Query,
+        )
+        .await?;
+        let results = self.search(&query[0], top_k);
+        let mut code = String::new();
+        for (name, chunk, score) in &results {
+            println!(
+                "{}",
+                format!(
+                    "🔍 | {}:{}-{} ({:.2})",
+                    name, chunk.start_line, chunk.end_line, score
+                )
+                .dimmed()
+            );
+            code.push_str(&format!(
+                "\n<chunk file=\"{}\" lines=\"{}-{}\">\n{}\n</chunk>",
+                name, chunk.start_line, chunk.end_line, chunk.text
+            ));
+        }
+        // NOTE: Here is something stupid for my edge case - see code.rs
+        let (p1, p2) = ("<bedrust_be", "gin_source>");
+        let (p3, p4) = ("</bedrust_en", "d_source>");
+        let wrapped_code = format!("{}{}{}{}{}", p1, p2, code, p3, p4);
+        Ok(constants::CODE_RETRIEVAL_PROMPT.replace("{SOURCE_CODE}", wrapped_code.as_str()))
+    }
+
+    // Only the latest question carries its chunks. Earlier ones are dropped from the user turns
+    // before the next question is sent, so the conversation does not grow with every retrieval
+    pub fn drop_retrieved_context(messages: &mut [SerializableMessage]) {
+        let prompt = constants::CODE_RETRIEVAL_PROMPT
+            .split("{SOURCE_CODE}")
+            .next()
+            .unwrap_or_default();
+        for message in messages.iter_mut().filter(|m| m.role == "user") {
+            let before = message.content.len();
+            message.content.retain(|block| !block.starts_with(prompt));
+            if message.content.len() != before {
+                message.cache_point = None;
+            }
+        }
+    }
+
+    // Tells the user what is in the index, and how much of the source code changed since
+    pub fn print_report(&self, plan: &IndexPlan) {
+        println!(
+            "📚 | Using the index from {}: {} files, {} chunks ({})",
+            self.updated.cyan(),
+            self.files.len().to_string().cyan(),
+            self.chunk_count().to_string().cyan(),
+            self.embedding_model.to_str()
+        );
+        if !plan.is_up_to_date() {
+            print_warning(&format!(
+                "⚠ {} file(s) changed since the index was updated, run `bedrust index {}` to update it",
+                plan.changed.len() + plan.touched.len() + plan.removed.len(),
+                self.root.display()
+            ));
+        }
+    }
+}
+
+// Splits the text into chunks of `chunk_lines` lines, with `overlap` lines shared between
+// neighbours. Returns the first line, the last line and the text of every chunk.
+pub fn split_into_chunks(
+    text: &str,
+    chunk_lines: usize,
+    overlap: usize,
+) -> Vec<(usize, usize, String)> {
+    let lines: Vec<&str> = text.lines().collect();
+    let size = chunk_lines.max(1);
+    let step = size.saturating_sub(overlap).max(1);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < lines.len() {
+        let end = (start + size).min(lines.len());
+        let mut chunk = lines[start..end].join("\n");
+        if chunk.len() > MAX_CHUNK_CHARS {
+            let mut cut = MAX_CHUNK_CHARS;
+            while !chunk.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            chunk.truncate(cut);
+        }
+        if !chunk.trim().is_empty() {
+            chunks.push((start + 1, end, chunk));
+        }
+        if end == lines.len() {
+            break;
+        }
+        start += step;
+    }
+    chunks
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b: f32 = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+fn file_mtime(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// Where the index of a source directory is stored:
+// ~/.config/bedrust/indexes/<dir name>-<hash of the full path>.json
+pub fn index_path(root: &Path) -> Result<PathBuf, anyhow::Error> {
+    project_data_path(root, "indexes")
+}
+
+// `bedrust index <dir>` - builds the index, or updates it with the files that changed
+pub async fn index_command(
+    dir: &Path,
+    include: &[String],
+    exclude: &[String],
+    rebuild: bool,
+    client: &aws_sdk_bedrockruntime::Client,
+    config: &BedrustConfig,
+) -> Result<(), anyhow::Error> {
+    let root = dir
+        .canonicalize()
+        .map_err(|_| anyhow!("🔴 | The specified path does not exist. Sorry!"))?;
+    let code_chat = CodeChatConfig {
+        include: [config.code_chat.include.as_slice(), include].concat(),
+        exclude: [config.code_chat.exclude.as_slice(), exclude].concat(),
+        ..config.code_chat.clone()
+    };
+    println!("----------------------------------------");
+    println!("📚 | Indexing: {}", root.display().to_string().cyan());
+    let files = find_source_files(&root, &code_chat)?.ok_or_else(|| {
+        anyhow!("🔴 | Could not detect the project type. Use --include to pick the files to index")
+    })?;
+
+    let mut index = match CodeIndex::load(&root)? {
+        Some(index) if !rebuild && index.is_compatible(&config.index) => index,
+        Some(_) if !rebuild => {
+            print_warning("The index was built with other settings, rebuilding it");
+            CodeIndex::new(&root, &config.index)
+        }
+        _ => CodeIndex::new(&root, &config.index),
+    };
+
+    let plan = index.plan_update(&files, code_chat.max_file_size);
+    let bytes: usize = plan.changed.iter().map(|f| f.contents.len()).sum();
+    let tokens: usize = plan
+        .changed
+        .iter()
+        .map(|f| estimate_tokens(&f.contents))
+        .sum();
+    println!(
+        "📄 | Files: {} unchanged, {} to embed ({}), {} removed",
+        plan.unchanged + plan.touched.len(),
+        plan.changed.len().to_string().cyan(),
+        format_bytes(bytes as u64),
+        plan.removed.len()
+    );
+    println!(
+        "💰 | Estimated cost: {} ({} with about {} tokens)",
+        format!(
+            "${:.4}",
+            tokens as f64 / 1000.0 * index.embedding_model.input_price_per_1k()
+        )
+        .cyan(),
+        index.embedding_model.to_str(),
+        tokens
+    );
+    for (path, reason) in &plan.skipped {
+        let path = path.strip_prefix(&root).unwrap_or(path);
+        println!(
+            "{}",
+            format!("     - skipped {} ({})", path.display(), reason).yellow()
+        );
+    }
+
+    index.update(client, plan).await?;
+    let path = index.save()?;
+    println!(
+        "✅ | Indexed {} files ({} chunks) into {}",
+        index.files.len(),
+        index.chunk_count(),
+        path.display().to_string().cyan()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn chunks_overlap() {
+        let text = (1..=10)
+            .map(|i| format!("line {}", i))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let chunks = split_into_chunks(&text, 4, 1);
+        let ranges: Vec<_> = chunks.iter().map(|(s, e, _)| (*s, *e)).collect();
+        assert_eq!(ranges, vec![(1, 4), (4, 7), (7, 10)]);
+        assert!(chunks[1].2.starts_with("line 4"));
+        assert!(split_into_chunks("\n\n", 4, 1).is_empty());
+    }
+
+    #[test]
+    fn search_ranks_by_similarity() {
+        let mut index = CodeIndex::new(Path::new("/repo"), &IndexConfig::default());
+        let chunk = |embedding: Vec<f32>| Chunk {
+            start_line: 1,
+            end_line: 1,
+            text: String::new(),
+            embedding,
+        };
+        index.files.insert(
+            "a.rs".into(),
+            IndexedFile {
+                mtime: 0,
+                hash: String::new(),
+                chunks: vec![chunk(vec![1.0, 0.0]), chunk(vec![0.0, 1.0])],
+            },
+        );
+        index.files.insert(
+            "b.rs".into(),
+            IndexedFile {
+                mtime: 0,
+                hash: String::new(),
+                chunks: vec![chunk(vec![0.7, 0.7])],
+            },
+        );
+        let results = index.search(&[0.0, 2.0], 2);
+        let found: Vec<_> = results
+            .iter()
+            .map(|(n, c, _)| (*n, c.embedding[0]))
+            .collect();
+        assert_eq!(found, vec![("a.rs", 0.0), ("b.rs", 0.7)]);
+    }
+
+    #[test]
+    fn plan_only_embeds_changed_files() {
+        let tmp = TempDir::new("bedrust-index").unwrap();
+        let dir = tmp.path();
+        for name in ["same.rs", "touched.rs", "changed.rs", "new.rs"] {
+            fs::write(dir.join(name), format!("// {}\n", name)).unwrap();
+        }
+        let files: Vec<PathBuf> = ["changed.rs", "new.rs", "same.rs", "touched.rs"]
+            .iter()
+            .map(|n| dir.join(n))
+            .collect();
+
+        let mut index = CodeIndex::new(dir, &IndexConfig::default());
+        let indexed = |name: &str, mtime: u64, contents: &str| {
+            (
+                name.to_string(),
+                IndexedFile {
+                    mtime,
+                    hash: hash_contents(contents),
+                    chunks: Vec::new(),
+                },
+            )
+        };
+        index.files.extend([
+            indexed("same.rs", file_mtime(&dir.join("same.rs")), "// same.rs\n"),
+            indexed("touched.rs", 1, "// touched.rs\n"),
+            indexed("changed.rs", 1, "// old\n"),
+            indexed("deleted.rs", 1, "// deleted.rs\n"),
+        ]);
+
+        let plan = index.plan_update(&files, 1024);
+        let changed: Vec<_> = plan.changed.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(changed, vec!["changed.rs", "new.rs"]);
+        assert_eq!(plan.touched.len(), 1);
+        assert_eq!(plan.touched[0].0, "touched.rs");
+        assert_eq!(plan.unchanged, 1);
+        assert_eq!(plan.removed, vec!["deleted.rs".to_string()]);
+        assert!(!plan.is_up_to_date());
+    }
+
+    #[test]
+    fn only_the_latest_question_keeps_its_chunks() {
+        let context = constants::CODE_RETRIEVAL_PROMPT.replace("{SOURCE_CODE}", "fn a() {}");
+        let message = |role: &str, content: Vec<String>| SerializableMessage {
+            role: role.into(),
+            content,
+            reasoning: None,
+            guardrail: None,
+            cache_point: Some(0),
+        };
+        let mut messages = vec![
+            message(
+                "user",
+                vec![context.clone(), "<question>What is a?</question>".into()],
+            ),
+            message("assistant", vec![context.clone()]),
+            message("user", vec!["<question>And b?</question>".into()]),
+        ];
+        CodeIndex::drop_retrieved_context(&mut messages);
+        assert_eq!(messages[0].content, vec!["<question>What is a?</question>"]);
+        assert_eq!(messages[0].cache_point, None);
+        // the answers, and the turns without retrieved chunks, are left as they are
+        assert_eq!(messages[1].content, vec![context]);
+        assert_eq!(messages[2].cache_point, Some(0));
+    }
+}
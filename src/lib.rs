@@ -1,10 +1,20 @@
+pub mod ask;
+pub mod caption_batch;
+pub mod caption_output;
+pub mod caption_source;
 pub mod captioner;
 pub mod chat;
 pub mod code;
 pub mod config;
 pub mod constants;
+pub mod edit;
+pub mod export;
+pub mod index;
 pub mod models;
+pub mod review;
 pub mod utils;
+pub mod video;
+pub mod watch;
 
 use aws_config::environment::credentials::EnvironmentVariableCredentialsProvider;
 use aws_config::imds::credentials::ImdsCredentialsProvider;
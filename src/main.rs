@@ -4,8 +4,8 @@ use std::io::Write;
 use anyhow::Result;
 use aws_sdk_bedrockruntime::types::ContentBlock;
 use aws_sdk_bedrockruntime::types::ConversationRole;
-use aws_sdk_bedrockruntime::types::InferenceConfiguration;
 use aws_sdk_bedrockruntime::types::Message;
+use aws_sdk_bedrockruntime::types::SystemContentBlock;
 use bedrust::config;
 use bedrust::utils;
 use colored::*;
@@ -14,16 +14,27 @@ use dialoguer::{theme::ColorfulTheme, FuzzySelect};
 use bedrust::configure_aws;
 use bedrust::utils::prompt_for_model_selection;
 
+use bedrust::ask::ask_command;
+use bedrust::caption_output::CaptionOutput;
+use bedrust::caption_source::s3_client;
 use bedrust::captioner::caption_process;
 use bedrust::chat::{
     list_chat_histories, load_chat_history, print_conversation_history, save_chat_history,
-    ConversationHistory,
+    ConversationHistory, SerializableMessage,
 };
-use bedrust::utils::{check_for_config, print_warning};
-use clap::Parser;
+use bedrust::utils::{check_for_config, print_warning, Commands, InferenceParams};
+use clap::{Parser, ValueEnum};
 
-use bedrust::code::code_chat_process;
+use bedrust::code::{code_chat_process, CodeContext};
+use bedrust::constants;
+use bedrust::edit::EditSession;
+use bedrust::export::{export_history, history_command, ExportFormat};
+use bedrust::index::{index_command, CodeIndex};
 use bedrust::models::converse_stream::call_converse_stream;
+use bedrust::models::HelperModels;
+use bedrust::review::{review_process, ReviewTarget};
+use bedrust::video::{video_command, Sampling, VideoJob};
+use bedrust::watch::{SourceUpdate, SourceWatcher};
 
 // TODO:
 // So far I've implemented the converse API for general purpose chat and the code chat.
@@ -49,19 +60,28 @@ async fn main() -> Result<()> {
     if arguments.init {
         config::prompt_init_config()?;
     }
+    if let Some(Commands::Config { command }) = &arguments.command {
+        return config::config_command(command, &arguments);
+    }
+    // upgrade older configuration files before loading them
+    config::migrate_config_files()?;
+    // load bedrust config from all the layers (defaults, global, project, env and cli)
+    let layered_config = config::load_layered_config(&config::cli_overrides(&arguments)?)?;
+
     // checking if the configuration files exist
-    if !check_for_config()? {
+    // NOTE: `ask` only prints the answer to stdout, so it can be piped
+    let asking = matches!(arguments.command, Some(Commands::Ask { .. }));
+    if !asking && !check_for_config()? && !layered_config.has_config_file() {
         print_warning("****************************************");
         print_warning("WARNING:");
-        println!("Your Bedrust configuration files are not set up correctly.");
-        println!("To use Bedrust you need the appropriate `bedrust_config.ron and `model_config.ron` in your $HOME/.config/bedrust/ directory.");
+        println!("No Bedrust configuration file was found, running with the built-in defaults.");
         println!("You can configure the application by running `bedrust --init`");
         print_warning("****************************************");
-        print_warning("Bedrust will now exit");
-        std::process::exit(1);
     }
-    // load bedrust config file
-    let bedrust_config = utils::load_bedrust_config()?;
+    let bedrust_config = layered_config.config;
+    if let Some(Commands::History { command }) = &arguments.command {
+        return history_command(command, &bedrust_config.export);
+    }
 
     // configuring the SDK
     let config = configure_aws(String::from("us-east-1"), &bedrust_config.aws_profile).await;
@@ -70,50 +90,243 @@ async fn main() -> Result<()> {
     // setup the bedrock client
     let bedrock_client = aws_sdk_bedrock::Client::new(&config);
 
+    //  === INDEXING RUN ===
+    // does not need a chat model, so it runs before we ask for one
+    if let Some(Commands::Index {
+        dir,
+        include,
+        exclude,
+        rebuild,
+    }) = &arguments.command
+    {
+        return index_command(
+            dir,
+            include,
+            exclude,
+            *rebuild,
+            &bedrock_runtime_client,
+            &bedrust_config,
+        )
+        .await;
+    }
+
     //let question = "Which songs are listed in the youtube video 'evolution of dance'?";
-    let model_id = arguments.model_id.or(bedrust_config.default_model);
-    let model_id = match model_id {
-        Some(model_id) => model_id,
+    // NOTE: the `--model-id` flag is already merged into `default_model`
+    let model = match bedrust_config.default_model {
+        Some(model) => model,
         None => prompt_for_model_selection()?,
+    };
+    let model_id = model.to_str();
+
+    //  === STRUCTURED OUTPUT RUN ===
+    if let Some(Commands::Ask {
+        prompt,
+        schema,
+        attempts,
+    }) = &arguments.command
+    {
+        return ask_command(
+            prompt.as_ref(),
+            schema,
+            *attempts,
+            &bedrock_runtime_client,
+            &model,
+            &bedrust_config,
+        )
+        .await;
+    }
+
+    //  === VIDEO DESCRIPTION RUN ===
+    if let Some(Commands::Video {
+        file,
+        interval,
+        scenes,
+        format,
+        output,
+    }) = &arguments.command
+    {
+        let video = &bedrust_config.caption.video;
+        let sampling = if *scenes {
+            Sampling::Scenes(video.scene_threshold)
+        } else {
+            Sampling::Interval(interval.unwrap_or(video.interval))
+        };
+        let job = VideoJob {
+            file: file.clone(),
+            sampling,
+            format: *format,
+            output: output.clone(),
+        };
+        return video_command(
+            job,
+            model_id,
+            &bedrock_client,
+            &bedrock_runtime_client,
+            &bedrust_config,
+        )
+        .await;
     }
-    .to_str();
 
     // === DEFAULT INFERENCE PARAMETERS ===
-    let inference_parameters = InferenceConfiguration::builder()
-        .max_tokens(bedrust_config.inference_params.max_tokens)
-        .top_p(bedrust_config.inference_params.top_p)
-        .temperature(bedrust_config.inference_params.temperature)
-        .build();
+    // can be changed during the chat with `/set`
+    let mut inference_parameters = bedrust_config.inference_params_for(&model);
+    inference_parameters.check().map_err(|e| {
+        anyhow::anyhow!("🔴 | Invalid inference parameters for {}: {}", model_id, e)
+    })?;
+    // can be toggled during the chat with `/thinking`
+    let mut show_reasoning = bedrust_config.show_reasoning;
 
     //  === CAPTIONING RUN ===
     if arguments.caption.is_some() {
+        // NOTE: `-x` writes an XML file, thanks StellyUK <3
+        let output = CaptionOutput::new(
+            arguments.output.clone(),
+            arguments.xml,
+            arguments.sidecar,
+            arguments.embed,
+            arguments.manifest.clone(),
+        )?;
+        let s3_client = s3_client(&config, &bedrust_config.caption);
         caption_process(
             model_id,
             &bedrock_client,
             &bedrock_runtime_client,
+            &s3_client,
             arguments.caption,
             &bedrust_config,
-            arguments.xml,
+            &output,
         )
         .await?;
     } else {
         // default run
-        utils::hello_header("Bedrust")?;
+        utils::hello_header("Bedrust", bedrust_config.show_banner)?;
 
         let mut message_count = 0;
         let mut conversation_history = ConversationHistory::new(None, None, None);
         let mut current_file: Option<String> = None;
 
+        // === HELPER MODELS ===
+        // checked before the chat starts, falling back to the chat model if a helper is not
+        // accessible
+        let helpers = HelperModels::resolve(
+            &bedrust_config.helper_model,
+            &bedrust_config.guardrail,
+            model_id,
+            &bedrock_runtime_client,
+        )
+        .await;
+
+        if let Some(id) = &bedrust_config.guardrail.id {
+            println!(
+                "🛡️  | Using guardrail {} (version {})",
+                id.cyan(),
+                bedrust_config.guardrail.version.cyan()
+            );
+        }
+
+        //  === BETA: CODE EDITING ===
+        // the model reads the source code through tools, nothing is sent up front
+        let mut edit_session = match (&arguments.source, arguments.edit) {
+            (Some(source_path), true) => {
+                let session = EditSession::new(source_path, &bedrust_config.code_chat)?;
+                println!("----------------------------------------");
+                print_warning("⚠ THIS IS A BETA FEATURE ⚠");
+                println!(
+                    "📝 | Editing the code in {}. Every patch is shown before it is applied, /undo reverts the last one",
+                    source_path.display().to_string().cyan()
+                );
+                Some(session)
+            }
+            _ => None,
+        };
+
         //  === BETA: SOURCE CODE CHAT ===
-        let code: Option<String> = match arguments.source {
-            Some(ref source_path) => {
-                Some(code_chat_process(source_path.to_path_buf(), &bedrock_runtime_client).await?)
+        let code: Option<CodeContext> = match (&arguments.command, &arguments.source) {
+            // reviewing a git change
+            (
+                Some(Commands::Code {
+                    dir,
+                    diff,
+                    staged,
+                    commit,
+                }),
+                _,
+            ) => {
+                let target = ReviewTarget::from_args(diff.as_ref(), *staged, commit.as_ref())
+                    .expect("clap requires one of --diff, --staged or --commit");
+                review_process(
+                    dir,
+                    &target,
+                    &model,
+                    inference_parameters.max_tokens,
+                    &bedrust_config.code_chat,
+                )
+                .await?
             }
-            None => None,
+            (_, Some(source_path)) if edit_session.is_none() => {
+                code_chat_process(
+                    source_path.to_path_buf(),
+                    &bedrock_runtime_client,
+                    &helpers,
+                    &model,
+                    inference_parameters.max_tokens,
+                    &bedrust_config.code_chat,
+                    &bedrust_config.index,
+                )
+                .await?
+            }
+            _ => None,
+        };
+        // the user decided not to send the source code
+        if code.is_none()
+            && edit_session.is_none()
+            && (arguments.source.is_some()
+                || matches!(arguments.command, Some(Commands::Code { .. })))
+        {
+            println!("Bye!");
+            return Ok(());
+        }
+        // a review gets its own system prompt
+        let system_prompt = match code {
+            Some(CodeContext::Review(_)) => Some(vec![SystemContentBlock::Text(
+                constants::CODE_REVIEW_SYSTEM_PROMPT.to_string(),
+            )]),
+            _ => None,
         };
+        // === WATCHING THE SOURCE CODE ===
+        // so `/refresh` can send the files that changed during the chat
+        let mut watcher = match (&code, &arguments.source) {
+            (Some(CodeContext::Source { versions, .. }), Some(source_path)) => Some(
+                SourceWatcher::new(source_path, versions.clone(), &bedrust_config.code_chat),
+            ),
+            (Some(CodeContext::Index(index)), Some(source_path)) => Some(SourceWatcher::new(
+                source_path,
+                index.versions(),
+                &bedrust_config.code_chat,
+            )),
+            _ => None,
+        }
+        .and_then(|w| {
+            w.inspect_err(|e| print_warning(&format!("Not watching the source code: {}", e)))
+                .ok()
+        });
+        let mut pending_update: Option<SourceUpdate> = None;
         // get user input
         loop {
             println!("----------------------------------------");
+            if let Some(w) = watcher.as_ref().filter(|_| pending_update.is_none()) {
+                let changed = w.changes().len();
+                if changed > 0 {
+                    println!(
+                        "{}",
+                        format!(
+                            "📝 | {} file(s) changed since the model has seen them, use /refresh to send them",
+                            changed
+                        )
+                        .dimmed()
+                    );
+                }
+            }
             println!("🤖 | What would you like to know today?");
             print!("😎 | Human: ");
             io::stdout().flush()?; // so the question is typed on the same line as above
@@ -122,7 +335,14 @@ async fn main() -> Result<()> {
             io::stdin().read_line(&mut question)?;
             message_count += 1;
 
-            let question = question.trim();
+            let mut question = question.trim();
+            // the review starts without a question
+            if question.is_empty()
+                && message_count == 1
+                && matches!(code, Some(CodeContext::Review(_)))
+            {
+                question = constants::DEFAULT_REVIEW_QUESTION;
+            }
             if question.is_empty() {
                 println!("Please enter a question.");
                 continue;
@@ -130,24 +350,114 @@ async fn main() -> Result<()> {
             if question == "/q" {
                 println!("Bye!");
                 break;
-            } else if question == "/h" {
-                conversation_history.save_as_html()?;
+            } else if question == "/h"
+                || question.starts_with("/h ")
+                || question.starts_with("/export")
+            {
+                // /h [--source] [path] is /export html [--source] [path]
+                let args = match question.strip_prefix("/h") {
+                    Some(rest) => format!("html {}", rest),
+                    None => question["/export".len()..].to_string(),
+                };
+                let mut args = args.trim().splitn(2, ' ');
+                let format = match args.next().map(|f| ExportFormat::from_str(f, true)) {
+                    Some(Ok(format)) => format,
+                    _ => {
+                        utils::print_warning(
+                            "Usage: /export <html|print|markdown|text|jsonl> [--source] [path]",
+                        );
+                        continue;
+                    }
+                };
+                let mut export = bedrust_config.export.clone();
+                let mut path = args.next().unwrap_or_default().trim();
+                if let Some(rest) = path.strip_prefix("--source") {
+                    export.keep_source = true;
+                    path = rest.trim();
+                }
+                let path = match path.strip_prefix("~/") {
+                    Some(rest) => dirs::home_dir().unwrap_or_default().join(rest),
+                    None => std::path::PathBuf::from(path),
+                };
+                let path = Some(path.as_path()).filter(|p| !p.as_os_str().is_empty());
+                match export_history(&conversation_history, format, path, &export) {
+                    Ok(file) => println!("💾 | Saved the conversation to {}", file.display()),
+                    Err(e) => eprintln!("🔴 | Could not export the conversation: {}", e),
+                }
                 continue;
             } else if question == "/c" {
                 println!("Clearing current chat history");
                 conversation_history.clear();
                 current_file = None;
+                if let Some(session) = edit_session.as_mut() {
+                    session.clear();
+                }
                 continue;
             // SAVING CHAT HISTORY
             // TODO: Implement a feature that will distinguish between user input and LLM output
             // this will likely need to be handled in the way I handle conversation_history as I
             // just store everything as raw there.
+            } else if question == "/set" || question.starts_with("/set ") {
+                match InferenceParams::parse_set_command(question) {
+                    Some((key, value)) => match inference_parameters.set(key, value) {
+                        Ok(_) => println!("⚙️  | {} set to {}", key.cyan(), value.cyan()),
+                        Err(e) => eprintln!("🔴 | {}", e),
+                    },
+                    None => {
+                        utils::print_warning("Usage: /set <parameter> <value>");
+                        println!("Current inference parameters:");
+                        println!("{}", inference_parameters);
+                    }
+                }
+                continue;
+            } else if question == "/refresh" {
+                match watcher.as_ref() {
+                    Some(w) => {
+                        let changes = w.changes();
+                        if changes.is_empty() {
+                            println!("📝 | The model has seen the latest version of every file");
+                        } else {
+                            for change in &changes {
+                                let state = if change.contents.is_some() {
+                                    "changed"
+                                } else {
+                                    "deleted"
+                                };
+                                println!("📝 | {} ({})", change.path.display(), state);
+                            }
+                            pending_update = Some(w.prepare_update(changes));
+                            println!("📝 | These files will be sent with your next question");
+                        }
+                    }
+                    None => print_warning("/refresh only works in code chat (--source)"),
+                }
+                continue;
+            } else if question == "/undo" {
+                match edit_session.as_mut() {
+                    Some(session) => {
+                        if let Err(e) = session.undo() {
+                            eprintln!("🔴 | Could not undo the patch: {}", e);
+                        }
+                    }
+                    None => print_warning("/undo only works when editing code (--edit)"),
+                }
+                continue;
+            } else if question == "/thinking" {
+                show_reasoning = !show_reasoning;
+                println!(
+                    "💭 | Reasoning will be {}",
+                    if show_reasoning { "shown" } else { "hidden" }.cyan()
+                );
+                continue;
             } else if question == "/s" {
+                // store the parameters the conversation was had with
+                conversation_history.inference_params = Some(inference_parameters.clone());
                 // if there is a current_file set we keep writing to that file
                 let filename = if let Some(ref file) = current_file {
                     save_chat_history(
                         Some(file),
                         &bedrock_runtime_client,
+                        &helpers,
                         &mut conversation_history,
                     )
                     .await?
@@ -155,6 +465,7 @@ async fn main() -> Result<()> {
                     match save_chat_history(
                         None,
                         &bedrock_runtime_client,
+                        &helpers,
                         &mut conversation_history,
                     )
                     .await
@@ -213,34 +524,126 @@ async fn main() -> Result<()> {
                 utils::print_warning("----------------------------------------");
                 utils::print_warning("Currently supported chat commands: ");
                 utils::print_warning("/c\t \t - Clear current chat history");
+                utils::print_warning(
+                    "/set\t \t - Show or change inference parameters (/set temperature 0.2)",
+                );
+                utils::print_warning("/thinking\t - Show or hide the reasoning of the model");
+                utils::print_warning("/refresh\t - Send the source files that changed (code chat)");
+                utils::print_warning("/undo\t \t - Revert the last applied patch (--edit)");
                 utils::print_warning("/s\t \t - (BETA) Save chat history");
                 utils::print_warning("/r\t \t - (BETA) Recall and load a chat history");
                 utils::print_warning(
-                    "/h\t \t - (BETA) Export history as HTML(saves in current dir)",
+                    "/h [path]\t - (BETA) Export history as HTML (--source keeps the source code)",
+                );
+                utils::print_warning(
+                    "/export <format>\t - Export history as html, print, markdown, text or jsonl",
                 );
                 utils::print_warning("/q\t \t - Quit");
                 continue;
             }
+            // when editing, the session keeps the tool calls, the history only gets the question
+            // and the answer (so /s and /h still work)
+            if let Some(session) = edit_session.as_mut() {
+                println!("----------------------------------------");
+                println!("☎️  | Calling Model: {}", &model_id);
+                println!("----------------------------------------");
+                let answer = match session
+                    .ask(
+                        &bedrock_runtime_client,
+                        model_id,
+                        &inference_parameters,
+                        &bedrust_config.guardrail,
+                        question,
+                    )
+                    .await
+                {
+                    Ok(answer) => answer,
+                    Err(e) => {
+                        eprintln!("🔴 | {}", e);
+                        continue;
+                    }
+                };
+                let mut messages = conversation_history.messages.unwrap_or_default();
+                for (role, text) in [
+                    (ConversationRole::User, question.to_string()),
+                    (ConversationRole::Assistant, answer),
+                ] {
+                    messages.push(
+                        Message::builder()
+                            .role(role)
+                            .content(ContentBlock::Text(text))
+                            .build()?
+                            .into(),
+                    );
+                }
+                conversation_history.messages = Some(messages);
+                continue;
+            }
             // If we are looking at code - I need to include the user question in the first
             // message. Otherwise Bedrock keeps complaining about alternate messages between user
-            // and assistant
-            let message = if arguments.source.is_some() && message_count == 1 {
-                let question_with_code = code
-                    .as_ref()
-                    .map(|src_code| format!("{}\n<question>{}</question>", src_code, question))
-                    .unwrap_or_else(|| question.to_string());
-                Message::builder()
-                    .set_role(Some(ConversationRole::User))
-                    .set_content(Some(vec![ContentBlock::Text(question_with_code)]))
-                    .build()?
-            } else {
-                Message::builder()
+            // and assistant. The source code goes in its own content block, so it can be cached on
+            // the models that support prompt caching
+            let message: SerializableMessage = match code.as_ref() {
+                Some(
+                    CodeContext::Source {
+                        prompt: src_code, ..
+                    }
+                    | CodeContext::Review(src_code),
+                ) if message_count == 1 => {
+                    let mut message: SerializableMessage = Message::builder()
+                        .set_role(Some(ConversationRole::User))
+                        .set_content(Some(vec![
+                            ContentBlock::Text(src_code.clone()),
+                            ContentBlock::Text(format!("<question>{}</question>", question)),
+                        ]))
+                        .build()?
+                        .into();
+                    message.cache_point = Some(0);
+                    message
+                }
+                // with an index, every question gets the chunks that are relevant to it
+                Some(CodeContext::Index(index)) => {
+                    let context = match index
+                        .retrieve_context(
+                            &bedrock_runtime_client,
+                            question,
+                            bedrust_config.index.top_k,
+                        )
+                        .await
+                    {
+                        Ok(context) => context,
+                        Err(e) => {
+                            eprintln!("🔴 | Could not search the index: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Some(messages) = conversation_history.messages.as_mut() {
+                        CodeIndex::drop_retrieved_context(messages);
+                    }
+                    Message::builder()
+                        .set_role(Some(ConversationRole::User))
+                        .set_content(Some(vec![
+                            ContentBlock::Text(context),
+                            ContentBlock::Text(format!("<question>{}</question>", question)),
+                        ]))
+                        .build()?
+                        .into()
+                }
+                _ => Message::builder()
                     .set_role(Some(ConversationRole::User))
                     .set_content(Some(vec![ContentBlock::Text(question.to_string())]))
                     .build()?
+                    .into(),
             };
+            // the files changed since the model has seen them go right before the question
+            let mut message = message;
+            let sent_update = pending_update.take().filter(|_| watcher.is_some());
+            if let Some(update) = &sent_update {
+                let at = message.content.len().saturating_sub(1);
+                message.content.insert(at, update.prompt.clone());
+            }
             let mut messages = conversation_history.messages.unwrap_or_default().clone();
-            messages.push(message.into());
+            messages.push(message);
             conversation_history.messages = Some(messages);
 
             println!("----------------------------------------");
@@ -251,17 +654,41 @@ async fn main() -> Result<()> {
                 &bedrock_runtime_client,
                 model_id.to_string(),
                 &conversation_history,
-                inference_parameters.clone(),
+                system_prompt.clone(),
+                &inference_parameters,
+                &bedrust_config.guardrail,
+                show_reasoning,
             )
-            .await?;
+            .await;
+            // a failed turn does not end the chat - the question is taken back, so the next one
+            // follows the last answer, and the changed files go with it
+            let streamresp = match streamresp {
+                Ok(streamresp) => streamresp,
+                Err(e) => {
+                    eprintln!("🔴 | {}", e);
+                    if let Some(messages) = conversation_history.messages.as_mut() {
+                        messages.pop();
+                    }
+                    pending_update = sent_update;
+                    continue;
+                }
+            };
+            if let (Some(update), Some(w)) = (&sent_update, watcher.as_mut()) {
+                w.mark_seen(update);
+            }
 
             // TODO: This can be a function
             let message = Message::builder()
                 .set_role(Some(ConversationRole::Assistant))
                 .set_content(Some(vec![ContentBlock::Text(streamresp.to_string())]))
                 .build()?;
+            // the reasoning and the guardrail outcome are kept next to the answer, so they end up
+            // in the saved history
+            let mut message: SerializableMessage = message.into();
+            message.reasoning = streamresp.reasoning;
+            message.guardrail = streamresp.guardrail;
             let mut messages = conversation_history.messages.unwrap();
-            messages.push(message.into());
+            messages.push(message);
             conversation_history.messages = Some(messages);
         }
     }
@@ -1,49 +1,109 @@
 use aws_sdk_bedrockruntime::{
+    error::ProvideErrorMetadata,
     operation::converse::{ConverseError, ConverseOutput},
-    types::{ContentBlock, ConversationRole, InferenceConfiguration, Message, SystemContentBlock},
+    types::{ContentBlock, ConversationRole, Message, StopReason, SystemContentBlock},
 };
 
+use crate::models::{cache_point, print_guardrail_intervention, prompt_caching_enabled};
+use crate::utils::{GuardrailConfig, InferenceParams};
+
 // Converse Error type
 //
 #[derive(Debug)]
-pub struct BedrockConverseError(String);
+pub struct BedrockConverseError {
+    pub kind: ConverseErrorKind,
+    message: String,
+}
+// What went wrong, so callers can decide what to do without looking at the message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConverseErrorKind {
+    ModelTimeout,
+    ModelNotReady,
+    Throttled,
+    // the model is not enabled in the account, or the role is not allowed to invoke it
+    AccessDenied,
+    // the model (or the guardrail) does not exist in this region
+    NotFound,
+    Other,
+}
 impl std::fmt::Display for BedrockConverseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         // FIX:
         // Figure out how to have this
         // write!(f, "Can't invoke '{}'. Reason: {}", MODEL_ID, self.0)
-        write!(f, "Can't invoke. Reason: {}", self.0)
+        write!(f, "Can't invoke. Reason: {}", self.message)
     }
 }
 impl std::error::Error for BedrockConverseError {}
 impl From<&str> for BedrockConverseError {
     fn from(value: &str) -> Self {
-        BedrockConverseError(value.to_string())
+        BedrockConverseError::new(ConverseErrorKind::Other, value)
+    }
+}
+impl BedrockConverseError {
+    pub fn new(kind: ConverseErrorKind, message: &str) -> Self {
+        BedrockConverseError {
+            kind,
+            message: message.to_string(),
+        }
+    }
+    // Throttling and models that are not ready go away if we wait a bit, the other errors do not
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.kind,
+            ConverseErrorKind::ModelTimeout
+                | ConverseErrorKind::ModelNotReady
+                | ConverseErrorKind::Throttled
+        )
     }
 }
 impl From<&ConverseError> for BedrockConverseError {
     fn from(value: &ConverseError) -> Self {
-        BedrockConverseError::from(match value {
-            ConverseError::ModelTimeoutException(_) => "Model took too long",
-            ConverseError::ModelNotReadyException(_) => "Model is not ready",
-            _ => "Unknown",
-        })
+        use ConverseErrorKind::*;
+        match value {
+            ConverseError::ModelTimeoutException(_) => {
+                BedrockConverseError::new(ModelTimeout, "Model took too long")
+            }
+            ConverseError::ModelNotReadyException(_) => {
+                BedrockConverseError::new(ModelNotReady, "Model is not ready")
+            }
+            ConverseError::ThrottlingException(_) => BedrockConverseError::new(
+                Throttled,
+                "Your request was throttled, please check your service quotas",
+            ),
+            value => {
+                let kind = match value {
+                    ConverseError::AccessDeniedException(_) => AccessDenied,
+                    ConverseError::ResourceNotFoundException(_) => NotFound,
+                    _ => Other,
+                };
+                BedrockConverseError::new(kind, value.message().unwrap_or("Unknown"))
+            }
+        }
     }
 }
 // === Main functions ===
 
 // Function to get the output text
 fn get_converse_output_text(output: ConverseOutput) -> Result<String, BedrockConverseError> {
+    if output.stop_reason() == &StopReason::GuardrailIntervened {
+        print_guardrail_intervention(
+            output
+                .trace()
+                .and_then(|t| t.guardrail())
+                .and_then(|g| g.action_reason()),
+        );
+    }
     let text = output
         .output()
         .ok_or("no output")?
         .as_message()
         .map_err(|_| "output not a message")?
         .content()
-        .first()
-        .ok_or("no content in message")?
-        .as_text()
-        .map_err(|_| "content is not text")?
+        .iter()
+        // skip over the reasoning blocks of thinking models
+        .find_map(|block| block.as_text().ok())
+        .ok_or("no text content in message")?
         .to_string();
     Ok(text)
 }
@@ -51,11 +111,19 @@ fn get_converse_output_text(output: ConverseOutput) -> Result<String, BedrockCon
 pub async fn call_converse(
     bc: &aws_sdk_bedrockruntime::Client,
     model_id: String,
-    inference_parameters: InferenceConfiguration,
+    inference_parameters: &InferenceParams,
+    guardrail: &GuardrailConfig,
     content: ContentBlock,
     system: Option<Vec<SystemContentBlock>>,
     echo: bool,
 ) -> Result<String, BedrockConverseError> {
+    // cache the system prompt, as it is the same for every call (e.g. when captioning)
+    let system = system.map(|mut blocks| {
+        if prompt_caching_enabled(&model_id, inference_parameters) {
+            blocks.push(SystemContentBlock::CachePoint(cache_point()));
+        }
+        blocks
+    });
     let response = bc
         .converse()
         .model_id(model_id)
@@ -68,7 +136,9 @@ pub async fn call_converse(
                 .build()
                 .map_err(|_| "Failed to build message")?,
         )
-        .inference_config(inference_parameters)
+        .inference_config(inference_parameters.to_inference_configuration())
+        .set_additional_model_request_fields(inference_parameters.additional_model_request_fields())
+        .set_guardrail_config(guardrail.to_guardrail_configuration())
         .send()
         .await;
 
@@ -83,6 +153,47 @@ pub async fn call_converse(
         Err(e) => Err(e
             .as_service_error()
             .map(BedrockConverseError::from)
-            .unwrap_or_else(|| BedrockConverseError("Unknown service error".into()))),
+            .unwrap_or_else(|| "Unknown service error".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_bedrockruntime::error::ErrorMetadata;
+    use aws_sdk_bedrockruntime::types::error::{
+        AccessDeniedException, ThrottlingException, ValidationException,
+    };
+
+    #[test]
+    fn retries_follow_the_kind_of_error() {
+        let throttled = ConverseError::ThrottlingException(
+            ThrottlingException::builder().message("Slow down").build(),
+        );
+        let e = BedrockConverseError::from(&throttled);
+        assert_eq!(e.kind, ConverseErrorKind::Throttled);
+        assert!(e.is_retryable());
+
+        let denied = ConverseError::AccessDeniedException(
+            AccessDeniedException::builder()
+                .meta(
+                    ErrorMetadata::builder()
+                        .message("You don't have access to the model")
+                        .build(),
+                )
+                .build(),
+        );
+        let e = BedrockConverseError::from(&denied);
+        assert_eq!(e.kind, ConverseErrorKind::AccessDenied);
+        assert!(!e.is_retryable());
+        assert!(e.to_string().contains("You don't have access"));
+
+        let invalid = ConverseError::ValidationException(
+            ValidationException::builder()
+                .message("Bad request")
+                .build(),
+        );
+        assert!(!BedrockConverseError::from(&invalid).is_retryable());
+        assert!(!BedrockConverseError::from("no output").is_retryable());
     }
 }
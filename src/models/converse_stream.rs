@@ -1,12 +1,17 @@
-use crate::chat::{Conversation, ConversationEntity, ConversationHistory};
+use crate::chat::{Conversation, ConversationEntity, ConversationHistory, GuardrailOutcome};
+use crate::models::{cache_point, print_guardrail_intervention, prompt_caching_enabled};
+use crate::utils::{GuardrailConfig, InferenceParams};
 use aws_sdk_bedrockruntime::{
     error::ProvideErrorMetadata,
     operation::converse_stream::ConverseStreamError,
     types::{
-        error::ConverseStreamOutputError, ConverseStreamOutput as ConverseStreamOutputType,
-        InferenceConfiguration, Message,
+        error::ConverseStreamOutputError, ContentBlockDelta,
+        ConverseStreamOutput as ConverseStreamOutputType, GuardrailTraceAssessment, Message,
+        ReasoningContentBlockDelta, StopReason, SystemContentBlock, TokenUsage,
     },
 };
+use colored::*;
+use std::io::{self, Write};
 
 // Converse Error type
 //
@@ -61,16 +66,44 @@ impl From<&ConverseStreamOutputError> for BedrockConverseStreamError {
 
 // === Main functions ===
 
+// A piece of the streamed response - the answer, the reasoning of the model, or the details of
+// how the response ended
+enum OutputDelta {
+    Text(String),
+    Reasoning(String),
+    Stop(StopReason),
+    Metadata {
+        usage: Option<TokenUsage>,
+        guardrail_trace: Option<Box<GuardrailTraceAssessment>>,
+    },
+}
+
 // Function to get the output text
 fn get_converse_output_text(
     output: ConverseStreamOutputType,
-) -> Result<String, BedrockConverseStreamError> {
+) -> Result<Option<OutputDelta>, BedrockConverseStreamError> {
     Ok(match output {
         ConverseStreamOutputType::ContentBlockDelta(event) => match event.delta() {
-            Some(delta) => delta.as_text().cloned().unwrap_or_else(|_| "".into()),
-            None => "".into(),
+            Some(ContentBlockDelta::Text(text)) => Some(OutputDelta::Text(text.clone())),
+            // NOTE: the signature and redacted content are only needed when sending the
+            // reasoning back to the model, which we do not do
+            Some(ContentBlockDelta::ReasoningContent(ReasoningContentBlockDelta::Text(text))) => {
+                Some(OutputDelta::Reasoning(text.clone()))
+            }
+            _ => None,
         },
-        _ => "".into(),
+        ConverseStreamOutputType::MessageStop(event) => {
+            Some(OutputDelta::Stop(event.stop_reason().clone()))
+        }
+        // the guardrail trace and the token usage are sent with the metadata, at the very end
+        ConverseStreamOutputType::Metadata(event) => Some(OutputDelta::Metadata {
+            usage: event.usage().cloned(),
+            guardrail_trace: event
+                .trace()
+                .and_then(|t| t.guardrail())
+                .map(|g| Box::new(g.clone())),
+        }),
+        _ => None,
     })
 }
 
@@ -78,22 +111,40 @@ pub async fn call_converse_stream(
     bc: &aws_sdk_bedrockruntime::Client,
     model_id: String,
     conversation_history: &ConversationHistory,
-    inference_parameters: InferenceConfiguration,
-    //) -> Result<String, BedrockConverseStreamError> {
+    system: Option<Vec<SystemContentBlock>>,
+    inference_parameters: &InferenceParams,
+    guardrail: &GuardrailConfig,
+    show_reasoning: bool,
 ) -> Result<Conversation, BedrockConverseStreamError> {
-    let msg: Vec<Message> = conversation_history
-        .messages
-        .clone()
-        .unwrap()
+    let caching = prompt_caching_enabled(&model_id, inference_parameters);
+    let system = system.map(|mut blocks| {
+        if caching {
+            blocks.push(SystemContentBlock::CachePoint(cache_point()));
+        }
+        blocks
+    });
+    let messages = conversation_history.messages.clone().unwrap();
+    let last = messages.len().saturating_sub(1);
+    let msg: Vec<Message> = messages
         .into_iter()
-        .map(Message::from)
+        .enumerate()
+        .map(|(i, m)| {
+            if caching {
+                m.to_message_with_cache_points(i == last)
+            } else {
+                Message::from(m)
+            }
+        })
         .collect();
 
     let response = bc
         .converse_stream()
         .model_id(model_id)
+        .set_system(system)
         .set_messages(Some(msg))
-        .inference_config(inference_parameters)
+        .inference_config(inference_parameters.to_inference_configuration())
+        .set_additional_model_request_fields(inference_parameters.additional_model_request_fields())
+        .set_guardrail_config(guardrail.to_guardrail_stream_configuration())
         .send()
         .await;
 
@@ -106,6 +157,12 @@ pub async fn call_converse_stream(
 
     // A string that response the message back
     let mut output = String::new();
+    // The reasoning of the model, kept apart from the answer
+    let mut reasoning = String::new();
+    // How the response ended, and what the guardrail did with it
+    let mut stop_reason: Option<StopReason> = None;
+    let mut guardrail_trace: Option<Box<GuardrailTraceAssessment>> = None;
+    let mut usage: Option<TokenUsage> = None;
 
     // return the conversation
     let mut convo = Conversation::new(ConversationEntity::Assistant, String::new());
@@ -114,14 +171,52 @@ pub async fn call_converse_stream(
     loop {
         let token = stream.recv().await;
         match token {
-            Ok(Some(text)) => {
-                let next = get_converse_output_text(text)?;
-                print!("{}", next);
-                output.push_str(&next);
+            Ok(Some(event)) => {
+                match get_converse_output_text(event)? {
+                    Some(OutputDelta::Reasoning(next)) => {
+                        if reasoning.is_empty() {
+                            print!("💭 | Thinking...");
+                            if show_reasoning {
+                                println!();
+                            }
+                        }
+                        if show_reasoning {
+                            print!("{}", next.dimmed());
+                        }
+                        reasoning.push_str(&next);
+                    }
+                    Some(OutputDelta::Text(next)) => {
+                        // the reasoning is done, so we close (or collapse) its section
+                        if output.is_empty() && !reasoning.is_empty() {
+                            end_reasoning_section(&reasoning, show_reasoning);
+                        }
+                        print!("{}", next);
+                        output.push_str(&next);
+                    }
+                    Some(OutputDelta::Stop(reason)) => stop_reason = Some(reason),
+                    Some(OutputDelta::Metadata {
+                        usage: u,
+                        guardrail_trace: trace,
+                    }) => {
+                        usage = u;
+                        guardrail_trace = trace;
+                    }
+                    None => {}
+                }
+                io::stdout().flush().ok();
                 Ok(())
             }
             Ok(None) => {
                 convo.content.push_str(&output);
+                if !reasoning.is_empty() {
+                    convo.reasoning = Some(reasoning);
+                }
+                if guardrail.is_enabled() {
+                    convo.guardrail = Some(GuardrailOutcome::new(
+                        stop_reason == Some(StopReason::GuardrailIntervened),
+                        guardrail_trace.as_deref(),
+                    ));
+                }
                 break;
             }
             Err(e) => Err(e
@@ -134,6 +229,45 @@ pub async fn call_converse_stream(
     }
 
     println!();
+    // with prompt caching, show how much of the input came from (or went into) the cache
+    if let Some(usage) = usage.as_ref().filter(|_| caching) {
+        print_cache_usage(usage);
+    }
+    if let Some(outcome) = convo.guardrail.as_ref().filter(|g| g.intervened) {
+        print_guardrail_intervention(outcome.action_reason.as_deref());
+    }
 
     Ok(convo)
 }
+
+// Separates the reasoning from the answer. When the reasoning is hidden, the `Thinking...` line
+// is replaced with a short note instead.
+fn end_reasoning_section(reasoning: &str, show_reasoning: bool) {
+    if show_reasoning {
+        println!();
+        println!("{}", "----------------------------------------".dimmed());
+    } else {
+        println!(
+            "\r💭 | {}",
+            format!(
+                "Thought for {} words (hidden - use /thinking to show it)",
+                reasoning.split_whitespace().count()
+            )
+            .dimmed()
+        );
+    }
+}
+
+fn print_cache_usage(usage: &TokenUsage) {
+    println!(
+        "{}",
+        format!(
+            "📊 | Tokens - input: {}, output: {}, cache read: {}, cache write: {}",
+            usage.input_tokens(),
+            usage.output_tokens(),
+            usage.cache_read_input_tokens().unwrap_or_default(),
+            usage.cache_write_input_tokens().unwrap_or_default()
+        )
+        .dimmed()
+    );
+}
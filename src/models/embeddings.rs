@@ -0,0 +1,115 @@
+use anyhow::{anyhow, Result};
+use aws_sdk_bedrockruntime::{error::ProvideErrorMetadata, primitives::Blob};
+use serde_json::{json, Value};
+
+use crate::utils::EmbeddingModel;
+
+// NOTE: The embedding models are not supported by the Converse API, so we use `invoke_model` with
+// the request body every model family expects.
+
+// Cohere Embed takes at most this many texts in one request
+const COHERE_MAX_TEXTS: usize = 96;
+
+// Cohere embeds documents and search queries differently, Titan does not care
+#[derive(Debug, Clone, Copy)]
+pub enum EmbeddingInput {
+    Document,
+    Query,
+}
+
+impl EmbeddingInput {
+    fn cohere_input_type(&self) -> &'static str {
+        match self {
+            EmbeddingInput::Document => "search_document",
+            EmbeddingInput::Query => "search_query",
+        }
+    }
+}
+
+// Embeds the texts, returning one vector per text (in the same order)
+pub async fn embed(
+    client: &aws_sdk_bedrockruntime::Client,
+    model: EmbeddingModel,
+    texts: &[String],
+    input: EmbeddingInput,
+) -> Result<Vec<Vec<f32>>> {
+    let mut vectors = Vec::with_capacity(texts.len());
+    match model {
+        // Titan embeds one text per request
+        EmbeddingModel::TitanEmbedTextV2 | EmbeddingModel::TitanEmbedTextV1 => {
+            for text in texts {
+                let body = match model {
+                    EmbeddingModel::TitanEmbedTextV2 => {
+                        json!({ "inputText": text, "normalize": true })
+                    }
+                    _ => json!({ "inputText": text }),
+                };
+                let response = invoke(client, model, body).await?;
+                vectors.push(parse_vector(&response["embedding"])?);
+            }
+        }
+        EmbeddingModel::CohereEmbedEnglish | EmbeddingModel::CohereEmbedMultilingual => {
+            for batch in texts.chunks(COHERE_MAX_TEXTS) {
+                let body = json!({
+                    "texts": batch,
+                    "input_type": input.cohere_input_type(),
+                    "truncate": "END",
+                });
+                let response = invoke(client, model, body).await?;
+                let embeddings = response["embeddings"].as_array().ok_or_else(|| {
+                    anyhow!("The response of {} has no embeddings", model.to_str())
+                })?;
+                for embedding in embeddings {
+                    vectors.push(parse_vector(embedding)?);
+                }
+            }
+        }
+    }
+    if vectors.len() != texts.len() {
+        return Err(anyhow!(
+            "Expected {} embeddings from {}, got {}",
+            texts.len(),
+            model.to_str(),
+            vectors.len()
+        ));
+    }
+    Ok(vectors)
+}
+
+async fn invoke(
+    client: &aws_sdk_bedrockruntime::Client,
+    model: EmbeddingModel,
+    body: Value,
+) -> Result<Value> {
+    let response = client
+        .invoke_model()
+        .model_id(model.to_str())
+        .content_type("application/json")
+        .accept("application/json")
+        .body(Blob::new(serde_json::to_vec(&body)?))
+        .send()
+        .await
+        .map_err(|e| {
+            anyhow!(
+                "Can't invoke {}. Reason: {}",
+                model.to_str(),
+                e.as_service_error()
+                    .and_then(|e| e.message())
+                    .unwrap_or("Unknown")
+            )
+        })?;
+    Ok(serde_json::from_slice(response.body().as_ref())?)
+}
+
+fn parse_vector(value: &Value) -> Result<Vec<f32>> {
+    value
+        .as_array()
+        .ok_or_else(|| anyhow!("The embedding is not an array"))?
+        .iter()
+        .map(|v| {
+            v.as_f64()
+                .map(|f| f as f32)
+                .ok_or_else(|| anyhow!("The embedding contains a non number"))
+        })
+        .collect()
+}
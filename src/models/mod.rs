@@ -1,11 +1,20 @@
 pub mod converse;
 pub mod converse_stream;
+pub mod embeddings;
+pub mod structured;
 
 use anyhow::{anyhow, Result};
 use aws_sdk_bedrock::{
     self,
     types::{FoundationModelDetails, ModelModality},
 };
+use aws_sdk_bedrockruntime::types::{CachePointBlock, CachePointType, ContentBlock};
+use colored::*;
+
+use crate::models::converse::{call_converse, BedrockConverseError, ConverseErrorKind};
+use crate::utils::{
+    ArgModels, GuardrailConfig, HelperModelConfig, HelperTaskConfig, InferenceParams,
+};
 
 pub enum ModelFeatures {
     Streaming,
@@ -70,3 +79,230 @@ pub async fn check_model_features(
         },
     }
 }
+
+// A helper model ready to be called - model id and inference parameters resolved from the
+// `helper_model` section of the configuration
+#[derive(Debug, Clone)]
+pub struct HelperModel {
+    pub model_id: String,
+    pub inference_parameters: InferenceParams,
+    // helper calls go through the same guardrail as the chat
+    pub guardrail: GuardrailConfig,
+}
+
+// The helper tasks, each with its own entry in the `helper_model` section of the configuration
+#[derive(Debug, Clone, Copy)]
+pub enum HelperTask {
+    Title,
+    Summary,
+    ProjectGuess,
+}
+
+// The helper models, checked once at startup. If a helper model is not accessible (not enabled
+// in the account, not available in the region ...) we fall back to the main chat model so that
+// saving chats and code chat keep working.
+pub struct HelperModels {
+    title: HelperModel,
+    summary: HelperModel,
+    project_guess: HelperModel,
+}
+
+impl HelperModels {
+    pub async fn resolve(
+        config: &HelperModelConfig,
+        guardrail: &GuardrailConfig,
+        fallback_model_id: &str,
+        client: &aws_sdk_bedrockruntime::Client,
+    ) -> HelperModels {
+        // most of the time all the tasks use the same model, so it is only checked once
+        let mut checked: Vec<(String, bool)> = Vec::new();
+        for task in [&config.title, &config.summary, &config.project_guess] {
+            let model_id = helper_model_id(task, config, fallback_model_id);
+            // the chat model works, or we would not be here
+            if model_id == fallback_model_id || checked.iter().any(|(m, _)| *m == model_id) {
+                continue;
+            }
+            let accessible = match check_model_access(&model_id, guardrail, client).await {
+                Ok(_) => true,
+                Err(e) if falls_back(e.kind) => {
+                    println!(
+                        "{}",
+                        format!(
+                            "⚠️ | Helper model {} is not accessible ({}), falling back to {}",
+                            model_id, e, fallback_model_id
+                        )
+                        .yellow()
+                    );
+                    false
+                }
+                // throttling and network errors say nothing about the access to the model, the
+                // task itself retries (or reports) them
+                Err(_) => true,
+            };
+            checked.push((model_id, accessible));
+        }
+        HelperModels::with_access(config, guardrail, fallback_model_id, |model_id| {
+            checked
+                .iter()
+                .find(|(m, _)| m == model_id)
+                .is_none_or(|(_, accessible)| *accessible)
+        })
+    }
+
+    // The model of every task, given which of the models are accessible
+    fn with_access(
+        config: &HelperModelConfig,
+        guardrail: &GuardrailConfig,
+        fallback_model_id: &str,
+        accessible: impl Fn(&str) -> bool,
+    ) -> HelperModels {
+        let helper = |task: &HelperTaskConfig| {
+            let model_id = helper_model_id(task, config, fallback_model_id);
+            HelperModel {
+                model_id: match accessible(&model_id) {
+                    true => model_id,
+                    false => fallback_model_id.to_string(),
+                },
+                inference_parameters: task.inference_params.clone(),
+                guardrail: guardrail.clone(),
+            }
+        };
+        HelperModels {
+            title: helper(&config.title),
+            summary: helper(&config.summary),
+            project_guess: helper(&config.project_guess),
+        }
+    }
+
+    pub fn get(&self, task: HelperTask) -> &HelperModel {
+        match task {
+            HelperTask::Title => &self.title,
+            HelperTask::Summary => &self.summary,
+            HelperTask::ProjectGuess => &self.project_guess,
+        }
+    }
+}
+
+// The model of the task, the helper model, or the chat model - in that order
+fn helper_model_id(
+    task: &HelperTaskConfig,
+    config: &HelperModelConfig,
+    fallback_model_id: &str,
+) -> String {
+    task.model
+        .or(config.model)
+        .map(|m| m.to_str().to_string())
+        .unwrap_or_else(|| fallback_model_id.to_string())
+}
+
+// Only a model we can not use is replaced by the chat model
+fn falls_back(kind: ConverseErrorKind) -> bool {
+    matches!(
+        kind,
+        ConverseErrorKind::AccessDenied | ConverseErrorKind::NotFound
+    )
+}
+
+// Checks if a model can be invoked by sending it the smallest possible request
+pub async fn check_model_access(
+    model_id: &str,
+    guardrail: &GuardrailConfig,
+    client: &aws_sdk_bedrockruntime::Client,
+) -> Result<(), BedrockConverseError> {
+    call_converse(
+        client,
+        model_id.to_string(),
+        &InferenceParams {
+            max_tokens: 1,
+            ..Default::default()
+        },
+        guardrail,
+        ContentBlock::Text("Hi".into()),
+        None,
+        false,
+    )
+    .await
+    .map(|_| ())
+}
+
+// Makes it obvious that the guardrail blocked (or changed) the request or the response
+pub fn print_guardrail_intervention(action_reason: Option<&str>) {
+    println!(
+        "{}",
+        "🛡️  | The guardrail intervened, this response was blocked or modified"
+            .red()
+            .bold()
+    );
+    if let Some(reason) = action_reason {
+        println!("{}", format!("🛡️  | Reason: {}", reason).red());
+    }
+}
+
+// === PROMPT CACHING ===
+// Everything before a cache point is cached by Bedrock for a few minutes, so sending it again (the
+// source code in code chat, long conversations) is cheaper and faster.
+pub fn prompt_caching_enabled(model_id: &str, inference_parameters: &InferenceParams) -> bool {
+    inference_parameters.prompt_caching
+        && ArgModels::from_model_id(model_id).is_some_and(|m| m.supports_prompt_caching())
+}
+
+pub fn cache_point() -> CachePointBlock {
+    CachePointBlock::builder()
+        .r#type(CachePointType::Default)
+        .build()
+        .expect("cache point type is set")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::HelperTaskConfig;
+
+    #[test]
+    fn helper_models_fall_back_to_the_chat_model() {
+        let chat_model = "us.amazon.nova-pro-v1:0";
+        let mut config = HelperModelConfig {
+            model: None,
+            ..Default::default()
+        };
+        config.summary.model = Some(ArgModels::ClaudeV35Haiku);
+
+        // the task model, then the helper model, then the chat model
+        assert_eq!(
+            helper_model_id(&config.summary, &config, chat_model),
+            ArgModels::ClaudeV35Haiku.to_str()
+        );
+        assert_eq!(
+            helper_model_id(&config.title, &config, chat_model),
+            chat_model
+        );
+        config.model = Some(ArgModels::ClaudeV3Haiku);
+        assert_eq!(
+            helper_model_id(&config.title, &config, chat_model),
+            ArgModels::ClaudeV3Haiku.to_str()
+        );
+        assert_eq!(
+            helper_model_id(&HelperTaskConfig::default_title(), &config, chat_model),
+            ArgModels::ClaudeV3Haiku.to_str()
+        );
+
+        // only a model we can not use falls back, not a throttled or failing one
+        assert!(falls_back(ConverseErrorKind::AccessDenied));
+        assert!(falls_back(ConverseErrorKind::NotFound));
+        assert!(!falls_back(ConverseErrorKind::Throttled));
+        assert!(!falls_back(ConverseErrorKind::Other));
+
+        // the tasks whose model is not accessible get the chat model, with their own parameters
+        let helpers = HelperModels::with_access(
+            &config,
+            &GuardrailConfig::default(),
+            chat_model,
+            |model_id| model_id != ArgModels::ClaudeV3Haiku.to_str(),
+        );
+        let title = helpers.get(HelperTask::Title);
+        assert_eq!(title.model_id, chat_model);
+        assert_eq!(title.inference_parameters.max_tokens, 32);
+        let summary = helpers.get(HelperTask::Summary);
+        assert_eq!(summary.model_id, ArgModels::ClaudeV35Haiku.to_str());
+    }
+}
@@ -0,0 +1,227 @@
+use anyhow::{anyhow, Result};
+use aws_sdk_bedrockruntime::types::{
+    AnyToolChoice, ContentBlock, ConversationRole, Message, Tool, ToolChoice, ToolConfiguration,
+    ToolInputSchema, ToolResultBlock, ToolResultContentBlock, ToolResultStatus, ToolSpecification,
+};
+use serde_json::{json, Value};
+
+use crate::models::converse::BedrockConverseError;
+use crate::utils::{document_to_json, json_to_document, GuardrailConfig, InferenceParams};
+
+// NOTE:
+// Structured output. Instead of asking for JSON and hoping the answer parses, the model gets a
+// single tool whose input schema is the JSON Schema we want, and has to call it. The tool input is
+// the result. Models do not always respect the schema, so it is validated locally, and on a
+// mismatch the validation errors are sent back as the tool result and the model gets another try.
+
+// the name of the tool the model has to call
+pub(crate) const OUTPUT_TOOL: &str = "respond";
+// also used for the tool in the batch job requests (see caption_batch.rs)
+pub(crate) const OUTPUT_TOOL_DESCRIPTION: &str =
+    "Returns the answer. Always use this tool to answer.";
+// the key holding the result when the schema is not an object (tool inputs are always objects)
+const WRAPPED_KEY: &str = "value";
+
+// A JSON Schema, compiled and ready to validate the output of the model
+pub struct OutputSchema {
+    // what the model sees as the input schema of the tool
+    tool_schema: Value,
+    validator: jsonschema::Validator,
+    wrapped: bool,
+}
+
+impl OutputSchema {
+    pub fn new(schema: &Value) -> Result<Self> {
+        let validator =
+            jsonschema::validator_for(schema).map_err(|e| anyhow!("Invalid JSON Schema: {}", e))?;
+        let wrapped = schema["type"] != "object";
+        let tool_schema = if wrapped {
+            json!({
+                "type": "object",
+                "properties": { WRAPPED_KEY: schema },
+                "required": [WRAPPED_KEY],
+            })
+        } else {
+            schema.clone()
+        };
+        Ok(OutputSchema {
+            tool_schema,
+            validator,
+            wrapped,
+        })
+    }
+
+    pub(crate) fn tool_schema(&self) -> &Value {
+        &self.tool_schema
+    }
+
+    // Unwraps the tool input and validates it, returning every validation error
+    pub(crate) fn check(&self, input: Value) -> Result<Value, Vec<String>> {
+        let value = if self.wrapped {
+            match input {
+                Value::Object(mut map) if map.contains_key(WRAPPED_KEY) => {
+                    map.remove(WRAPPED_KEY).unwrap_or_default()
+                }
+                _ => return Err(vec![format!("The `{}` field is missing", WRAPPED_KEY)]),
+            }
+        } else {
+            input
+        };
+        let errors: Vec<String> = self
+            .validator
+            .iter_errors(&value)
+            .map(|e| match e.instance_path.to_string() {
+                path if path.is_empty() => e.to_string(),
+                path => format!("{}: {}", path, e),
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(value)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn tool_configuration(&self) -> Result<ToolConfiguration> {
+        let tool = ToolSpecification::builder()
+            .name(OUTPUT_TOOL)
+            .description(OUTPUT_TOOL_DESCRIPTION)
+            .input_schema(ToolInputSchema::Json(json_to_document(&self.tool_schema)))
+            .build()?;
+        Ok(ToolConfiguration::builder()
+            .tools(Tool::ToolSpec(tool))
+            .tool_choice(ToolChoice::Any(AnyToolChoice::builder().build()))
+            .build()?)
+    }
+}
+
+// Asks the model and returns its answer as JSON matching the schema. Gives up after
+// `max_attempts` answers that do not validate.
+pub async fn call_structured(
+    bc: &aws_sdk_bedrockruntime::Client,
+    model_id: &str,
+    inference_parameters: &InferenceParams,
+    guardrail: &GuardrailConfig,
+    content: Vec<ContentBlock>,
+    schema: &OutputSchema,
+    max_attempts: usize,
+) -> Result<Value> {
+    // NOTE: extended thinking does not allow forcing the use of a tool
+    let mut inference_parameters = inference_parameters.clone();
+    inference_parameters.thinking_budget = None;
+
+    let mut messages = vec![Message::builder()
+        .role(ConversationRole::User)
+        .set_content(Some(content))
+        .build()?];
+    let mut last_errors = Vec::new();
+    for attempt in 1..=max_attempts {
+        let response = bc
+            .converse()
+            .model_id(model_id)
+            .set_messages(Some(messages.clone()))
+            .tool_config(schema.tool_configuration()?)
+            .inference_config(inference_parameters.to_inference_configuration())
+            .set_additional_model_request_fields(
+                inference_parameters.additional_model_request_fields(),
+            )
+            .set_guardrail_config(guardrail.to_guardrail_configuration())
+            .send()
+            .await
+            .map_err(|e| match e.as_service_error() {
+                Some(e) => anyhow!(BedrockConverseError::from(e)),
+                None => anyhow!("Unknown service error"),
+            })?;
+        let message = response
+            .output()
+            .and_then(|o| o.as_message().ok())
+            .cloned()
+            .ok_or_else(|| anyhow!("The model did not return a message"))?;
+
+        let tool_use = message
+            .content()
+            .iter()
+            .find_map(|b| b.as_tool_use().ok())
+            .cloned();
+        messages.push(message);
+        let reply = match tool_use {
+            Some(tool_use) => match schema.check(document_to_json(tool_use.input())) {
+                Ok(value) => return Ok(value),
+                Err(errors) => {
+                    eprintln!(
+                        "🔴 | The answer does not match the schema (attempt {}/{}), asking again",
+                        attempt, max_attempts
+                    );
+                    let text = format!(
+                        "The input does not match the schema:\n- {}\nCall the {} tool again, with input that matches the schema.",
+                        errors.join("\n- "),
+                        OUTPUT_TOOL
+                    );
+                    last_errors = errors;
+                    ContentBlock::ToolResult(
+                        ToolResultBlock::builder()
+                            .tool_use_id(tool_use.tool_use_id())
+                            .content(ToolResultContentBlock::Text(text))
+                            .status(ToolResultStatus::Error)
+                            .build()?,
+                    )
+                }
+            },
+            // some models answer with text anyway
+            None => {
+                eprintln!(
+                    "🔴 | The model did not use the {} tool (attempt {}/{}), asking again",
+                    OUTPUT_TOOL, attempt, max_attempts
+                );
+                last_errors = vec![format!("the model did not use the {} tool", OUTPUT_TOOL)];
+                ContentBlock::Text(format!("Answer by calling the {} tool.", OUTPUT_TOOL))
+            }
+        };
+        messages.push(
+            Message::builder()
+                .role(ConversationRole::User)
+                .content(reply)
+                .build()?,
+        );
+    }
+    Err(anyhow!(
+        "No valid answer after {} attempts: {}",
+        max_attempts,
+        last_errors.join("; ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_and_unwraps_the_tool_input() {
+        let schema = OutputSchema::new(&json!({
+            "type": "array",
+            "items": { "type": "string" },
+            "minItems": 1
+        }))
+        .unwrap();
+        assert_eq!(schema.tool_schema["required"], json!(["value"]));
+        assert_eq!(
+            schema.check(json!({ "value": ["rs", "toml"] })).unwrap(),
+            json!(["rs", "toml"])
+        );
+        assert!(schema.check(json!({ "value": [] })).is_err());
+        assert!(schema.check(json!(["rs"])).is_err());
+
+        let schema = OutputSchema::new(&json!({
+            "type": "object",
+            "properties": { "name": { "type": "string" }, "age": { "type": "integer" } },
+            "required": ["name"]
+        }))
+        .unwrap();
+        assert!(schema.check(json!({ "name": "Ferris", "age": 9 })).is_ok());
+        let errors = schema.check(json!({ "age": "nine" })).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().any(|e| e.starts_with("/age: ")));
+
+        assert!(OutputSchema::new(&json!({ "type": "nope" })).is_err());
+    }
+}
@@ -0,0 +1,313 @@
+use crate::code::{
+    decode_source, default_token_budget, estimate_tokens, hash_contents, CodeContext, SkipReason,
+    SourceCollection, SourceFile,
+};
+use crate::constants;
+use crate::utils::{print_warning, ArgModels, CodeChatConfig};
+use anyhow::anyhow;
+use colored::*;
+use dialoguer::Confirm;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// NOTE:
+// Code review of a git change (`bedrust code --diff/--staged/--commit`). Instead of the whole
+// code base, the model gets the unified diff and the full contents of the changed files (as they
+// are after the change, with line numbers), and a system prompt asking for findings with file and
+// line references. We shell out to `git`, so it needs to be installed.
+
+// What gets reviewed
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReviewTarget {
+    // a commit range (`main..HEAD`, `main...feature`), or a revision compared to the working tree
+    Diff(String),
+    // the changes staged for the next commit
+    Staged,
+    // a single commit
+    Commit(String),
+}
+
+impl ReviewTarget {
+    pub fn from_args(diff: Option<&String>, staged: bool, commit: Option<&String>) -> Option<Self> {
+        match (diff, staged, commit) {
+            (Some(range), _, _) => Some(ReviewTarget::Diff(range.clone())),
+            (_, true, _) => Some(ReviewTarget::Staged),
+            (_, _, Some(sha)) => Some(ReviewTarget::Commit(sha.clone())),
+            _ => None,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            ReviewTarget::Diff(range) => format!("the changes in {}", range),
+            ReviewTarget::Staged => "the staged changes".into(),
+            ReviewTarget::Commit(sha) => format!("commit {}", sha),
+        }
+    }
+
+    // the git command showing the change, `extra` goes before the revisions
+    fn git_args<'a>(&'a self, extra: &[&'a str]) -> Vec<&'a str> {
+        let mut args = match self {
+            ReviewTarget::Diff(_) | ReviewTarget::Staged => vec!["diff"],
+            ReviewTarget::Commit(_) => vec!["show", "--format="],
+        };
+        args.extend(["--no-color", "--no-ext-diff"]);
+        args.extend(extra);
+        match self {
+            ReviewTarget::Diff(range) => args.push(range),
+            ReviewTarget::Staged => args.push("--cached"),
+            ReviewTarget::Commit(sha) => args.push(sha),
+        }
+        args
+    }
+
+    // The revision holding the reviewed version of the files - an empty string is the index
+    // (staged files). None means the working tree.
+    fn revision(&self) -> Option<String> {
+        match self {
+            ReviewTarget::Diff(range) => {
+                let (_, right) = range.split_once("...").or_else(|| range.split_once(".."))?;
+                Some(if right.is_empty() { "HEAD" } else { right }.to_string())
+            }
+            ReviewTarget::Staged => Some(String::new()),
+            ReviewTarget::Commit(sha) => Some(sha.clone()),
+        }
+    }
+}
+
+// Starts a code review, returns None if the user decides not to send the change
+pub async fn review_process(
+    dir: &Path,
+    target: &ReviewTarget,
+    model: &ArgModels,
+    max_tokens: i32,
+    config: &CodeChatConfig,
+) -> Result<Option<CodeContext>, anyhow::Error> {
+    println!("----------------------------------------");
+    print_warning("⚠ THIS IS A BETA FEATURE ⚠");
+    println!("----------------------------------------");
+    println!(
+        "💾 | Let's review some code! Looking at {}",
+        target.describe().cyan()
+    );
+
+    let token_budget =
+        default_token_budget(config, model, max_tokens, constants::CODE_REVIEW_PROMPT);
+    let root = repo_root(dir)?;
+    let collection = collect_review(&root, target, config.max_file_size, token_budget)?;
+
+    collection.print_report(&root, model);
+    let send = Confirm::new()
+        .with_prompt("Send the changes to the model for review?")
+        .default(true)
+        .interact()?;
+    if !send {
+        return Ok(None);
+    }
+
+    // NOTE: Here is something stupid for my edge case - see code.rs
+    let (p1, p2) = ("<bedrust_be", "gin_source>");
+    let (p3, p4) = ("</bedrust_en", "d_source>");
+    let wrapped_code = format!("{}{}{}{}{}", p1, p2, collection.to_prompt_string(), p3, p4);
+    println!("----------------------------------------");
+    println!("💾 | Press ENTER to start the review, or ask a question about the change");
+    Ok(Some(CodeContext::Review(
+        constants::CODE_REVIEW_PROMPT.replace("{SOURCE_CODE}", wrapped_code.as_str()),
+    )))
+}
+
+// The diff and the changed files, the diff always comes first
+pub fn collect_review(
+    root: &Path,
+    target: &ReviewTarget,
+    max_file_size: u64,
+    token_budget: usize,
+) -> Result<SourceCollection, anyhow::Error> {
+    let diff = String::from_utf8_lossy(&run_git(root, &target.git_args(&[]))?).to_string();
+    if diff.trim().is_empty() {
+        return Err(anyhow!("🔴 | There are no changes to review"));
+    }
+    let formatted = format!("\n<diff>\n{}</diff>", diff);
+    let tokens = estimate_tokens(&formatted);
+    if tokens > token_budget {
+        return Err(anyhow!(
+            "🔴 | The diff is about {} tokens, more than the {} token budget. Try a smaller change",
+            tokens,
+            token_budget
+        ));
+    }
+    let mut collection = SourceCollection {
+        files: vec![SourceFile {
+            path: PathBuf::from("git diff"),
+            bytes: diff.len() as u64,
+            hash: hash_contents(&diff),
+            formatted,
+            tokens,
+        }],
+        skipped: Vec::new(),
+        token_budget,
+    };
+    let mut used = tokens;
+
+    // deleted files are only in the diff
+    let names = run_git(root, &target.git_args(&["--name-only", "--diff-filter=d"]))?;
+    let revision = target.revision();
+    for name in String::from_utf8_lossy(&names)
+        .lines()
+        .filter(|l| !l.is_empty())
+    {
+        let path = root.join(name);
+        let bytes = match &revision {
+            Some(rev) => run_git(root, &["show", &format!("{}:{}", rev, name)]),
+            None => fs::read(&path).map_err(anyhow::Error::from),
+        };
+        let contents = match bytes {
+            Ok(bytes) if bytes.len() as u64 > max_file_size => {
+                Err(SkipReason::TooLarge(bytes.len() as u64))
+            }
+            Ok(bytes) => decode_source(bytes),
+            Err(e) => Err(SkipReason::Unreadable(e.to_string())),
+        };
+        let contents = match contents {
+            Ok(contents) => contents,
+            Err(reason) => {
+                collection.skipped.push((path, reason));
+                continue;
+            }
+        };
+        let formatted = format!(
+            "\n<filename>{}</filename>\n<file_contents>\n{}</file_contents>",
+            name,
+            number_lines(&contents)
+        );
+        let tokens = estimate_tokens(&formatted);
+        if used + tokens > token_budget {
+            collection.skipped.push((path, SkipReason::OverBudget));
+            continue;
+        }
+        used += tokens;
+        collection.files.push(SourceFile {
+            path,
+            bytes: contents.len() as u64,
+            hash: hash_contents(&contents),
+            formatted,
+            tokens,
+        });
+    }
+    Ok(collection)
+}
+
+// So the model can point at the lines of the new version of the file
+fn number_lines(contents: &str) -> String {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| format!("{:>5} | {}\n", i + 1, line))
+        .collect()
+}
+
+pub fn repo_root(dir: &Path) -> Result<PathBuf, anyhow::Error> {
+    let output = run_git(dir, &["rev-parse", "--show-toplevel"])
+        .map_err(|e| anyhow!("🔴 | {} is not in a git repository ({})", dir.display(), e))?;
+    Ok(PathBuf::from(String::from_utf8_lossy(&output).trim()))
+}
+
+fn run_git(dir: &Path, args: &[&str]) -> Result<Vec<u8>, anyhow::Error> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .map_err(|e| anyhow!("🔴 | Could not run git: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+
+    // git for setting up the test repository, without the global config of whoever runs the
+    // tests (commit signing, hooks and the like)
+    fn git(dir: &Path, args: &[&str]) {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args([
+                "-c",
+                "user.name=bedrust",
+                "-c",
+                "user.email=bedrust@example.com",
+            ])
+            .args(["-c", "commit.gpgsign=false"])
+            .args(args)
+            .env("GIT_CONFIG_GLOBAL", "/dev/null")
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    // a repository with one commit, a change and a deletion staged on top of it, and a change that
+    // is not staged
+    fn repo_with_staged_changes() -> TempDir {
+        let tmp = TempDir::new("bedrust-review").unwrap();
+        let dir = tmp.path();
+        git(dir, &["init", "-q"]);
+        fs::write(dir.join("main.rs"), "fn main() {}\n").unwrap();
+        fs::write(dir.join("old.rs"), "// gone soon\n").unwrap();
+        git(dir, &["add", "."]);
+        git(dir, &["commit", "-q", "-m", "first"]);
+
+        fs::write(dir.join("main.rs"), "fn main() {\n    todo!()\n}\n").unwrap();
+        git(dir, &["rm", "-q", "old.rs"]);
+        git(dir, &["add", "."]);
+        fs::write(dir.join("main.rs"), "fn main() {}\n// unstaged\n").unwrap();
+        tmp
+    }
+
+    #[test]
+    fn reviews_only_the_staged_changes() {
+        let tmp = repo_with_staged_changes();
+        let root = repo_root(tmp.path()).unwrap();
+        let review = collect_review(&root, &ReviewTarget::Staged, 1024, 10_000).unwrap();
+        let prompt = review.to_prompt_string();
+        assert!(prompt.starts_with("\n<diff>\n"));
+        assert!(prompt.contains("-// gone soon"));
+        assert!(prompt.contains("<filename>main.rs</filename>"));
+        assert!(prompt.contains("    2 |     todo!()"));
+        assert!(!prompt.contains("unstaged"));
+        assert!(!prompt.contains("<filename>old.rs</filename>"));
+    }
+
+    #[test]
+    fn reviews_a_commit() {
+        let tmp = repo_with_staged_changes();
+        git(tmp.path(), &["commit", "-q", "-m", "second"]);
+        let root = repo_root(tmp.path()).unwrap();
+        let review = collect_review(&root, &ReviewTarget::Commit("HEAD".into()), 1024, 10_000);
+        assert_eq!(review.unwrap().files.len(), 2);
+    }
+
+    #[test]
+    fn a_diff_range_is_reviewed_as_of_its_end() {
+        assert_eq!(
+            ReviewTarget::Diff("main...".into()).revision(),
+            Some("HEAD".into())
+        );
+        assert_eq!(ReviewTarget::Diff("main".into()).revision(), None);
+    }
+}
@@ -1,11 +1,21 @@
 use anyhow::anyhow;
-use clap::{Parser, ValueEnum};
+use aws_sdk_bedrockruntime::types::{
+    GuardrailConfiguration, GuardrailStreamConfiguration, GuardrailTrace, InferenceConfiguration,
+};
+use aws_smithy_types::{Document, Number};
+use clap::{Parser, Subcommand, ValueEnum};
 use dialoguer::{theme::ColorfulTheme, FuzzySelect};
 use figlet_rs::FIGfont;
+use rand::distributions::{Alphanumeric, DistString};
 use ron::ser::PrettyConfig;
 
 use serde::{Deserialize, Serialize};
-use std::{fmt::Display, fs, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
 use colored::*;
@@ -13,10 +23,13 @@ use colored::*;
 use dirs::home_dir;
 
 use crate::constants;
+use crate::export::ExportFormat;
+use crate::video::TrackFormat;
 
 // ######################################## ARGUMENT PARSING
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
+#[command(group(clap::ArgGroup::new("input").args(["source", "caption"]).multiple(true)))]
 pub struct Args {
     #[arg(long, conflicts_with("model_id"))]
     pub init: bool,
@@ -25,34 +38,877 @@ pub struct Args {
     #[arg(short, long)]
     pub model_id: Option<ArgModels>,
 
+    /// A directory of images, a glob (e.g. "photos/**/*.jpg"), an s3://bucket/prefix, an https:// URL,
+    /// a .txt file listing images and URLs, or - to read a list of files from stdin
     #[arg(short, long)]
     pub caption: Option<PathBuf>,
 
     #[arg(short, long)]
     pub source: Option<PathBuf>,
 
+    /// Only send (or caption) the files matching this glob (can be repeated)
+    #[arg(long, value_name = "GLOB", requires = "input")]
+    pub include: Vec<String>,
+
+    /// Never send (or caption) the files matching this glob (can be repeated)
+    #[arg(long, value_name = "GLOB", requires = "input")]
+    pub exclude: Vec<String>,
+
+    /// Let the model read, search and propose patches to the source code instead of sending it
+    #[arg(long, requires = "source")]
+    pub edit: bool,
+
     #[arg(short)]
     pub xml: bool,
+
+    /// Where to write the captions, the format follows the extension: .json, .xml, .csv or .html
+    #[arg(short, long, value_name = "PATH", requires = "caption")]
+    pub output: Option<PathBuf>,
+
+    /// Also write the caption of every image to a file next to it
+    #[arg(long, value_enum, requires = "caption")]
+    pub sidecar: Option<SidecarFormat>,
+
+    /// Also write the captions into the images (EXIF ImageDescription and XMP dc:description)
+    #[arg(long, requires = "caption")]
+    pub embed: bool,
+
+    /// Where to write the manifest of downloaded images (source URI -> captions), a path or an s3:// URI
+    #[arg(long, value_name = "PATH", requires = "caption")]
+    pub manifest: Option<String>,
+
+    /// Also caption the images in the subdirectories of the -c directory
+    #[arg(long, requires = "caption")]
+    pub recursive: bool,
+
+    /// Caption with a Bedrock batch inference job (at least 100 images, set up in caption.batch)
+    #[arg(long, requires = "caption")]
+    pub batch: bool,
+
+    /// Extract the fields of this caption profile (from the configuration) instead of one caption
+    #[arg(long, value_name = "NAME", requires = "caption")]
+    pub profile: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+pub enum Commands {
+    /// Inspect the Bedrust configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    /// Review a git change: a commit range, the staged changes or a single commit
+    #[command(group(clap::ArgGroup::new("change").required(true).args(["diff", "staged", "commit"])))]
+    Code {
+        /// A directory inside the git repository
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+        /// Review a commit range (e.g. main..HEAD), or the working tree against a revision
+        #[arg(long, value_name = "RANGE")]
+        diff: Option<String>,
+        /// Review the changes staged for the next commit
+        #[arg(long)]
+        staged: bool,
+        /// Review a single commit
+        #[arg(long, value_name = "SHA")]
+        commit: Option<String>,
+    },
+    /// Ask a single question and print the answer as JSON matching a JSON Schema
+    Ask {
+        /// The question, read from stdin if it is not given
+        prompt: Option<String>,
+        /// The JSON Schema the answer has to match
+        #[arg(long, value_name = "FILE")]
+        schema: PathBuf,
+        /// How many answers the model can give before giving up on one that matches the schema
+        #[arg(long, default_value_t = 3)]
+        attempts: usize,
+    },
+    /// Describe a video: caption frames sampled from it into a WebVTT or SRT track (needs ffmpeg)
+    Video {
+        /// The video file
+        file: PathBuf,
+        /// Seconds between the sampled frames (defaults to caption.video.interval)
+        #[arg(long, value_name = "SECONDS", conflicts_with = "scenes")]
+        interval: Option<f64>,
+        /// Sample a frame on every scene change instead of at an interval
+        #[arg(long)]
+        scenes: bool,
+        /// The format of the description track
+        #[arg(long, value_enum, default_value = "vtt")]
+        format: TrackFormat,
+        /// Where to write the track, the JSON goes next to it (defaults to the video file name)
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Work with the saved chats (~/.config/bedrust/chats)
+    History {
+        #[command(subcommand)]
+        command: HistoryCommands,
+    },
+    /// Build or update the embedding index of a source directory, used by code chat
+    Index {
+        /// The source directory to index
+        dir: PathBuf,
+        /// Only index the source files matching this glob (can be repeated)
+        #[arg(long, value_name = "GLOB")]
+        include: Vec<String>,
+        /// Never index the source files matching this glob (can be repeated)
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+        /// Throw away the existing index and embed everything again
+        #[arg(long)]
+        rebuild: bool,
+    },
+}
+
+// The caption files written next to the images
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum SidecarFormat {
+    Txt,
+    Alt,
+}
+
+impl SidecarFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SidecarFormat::Txt => "txt",
+            SidecarFormat::Alt => "alt",
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommands {
+    /// Show the effective configuration after all the layers are merged
+    Show {
+        /// Show which layer each value came from
+        #[arg(long)]
+        origin: bool,
+    },
+    /// Check configuration files for unknown keys and invalid values
+    Validate {
+        /// The file to validate, defaults to the global and project configuration files
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum HistoryCommands {
+    /// Export saved chats as HTML, print ready HTML, markdown, plain text or JSONL
+    #[command(group(clap::ArgGroup::new("chats").required(true).args(["names", "all"])))]
+    Export {
+        /// The saved chats, by file name (the .json can be left out) or path
+        names: Vec<String>,
+        /// Export every saved chat
+        #[arg(long)]
+        all: bool,
+        #[arg(long, value_enum, default_value = "markdown")]
+        format: ExportFormat,
+        /// A file or a directory. JSONL puts several chats into one file, the other formats
+        /// write a file per chat into a directory (defaults to export.dir or the current one)
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<PathBuf>,
+        /// Keep the source code sent in code chat
+        #[arg(long)]
+        source: bool,
+    },
 }
 
+// NOTE: Every field has a default, so that configuration files only need to contain the values
+// they want to change. This is what makes the layered configuration (see `config.rs`) work.
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
 pub struct BedrustConfig {
+    // version of the configuration schema, files without it are version 1
+    #[serde(default = "_legacy_config_version")]
+    pub version: u32,
     pub aws_profile: String,
     pub supported_images: Vec<String>,
     pub caption_prompt: String,
     pub default_model: Option<ArgModels>,
-    // FIX: Implement a better way for configuration defaults
-    // for now if there is no configuration line use true
-    #[serde(default = "_default_true")]
     pub show_banner: bool,
+    // show the reasoning (thinking) of the model while it streams, toggled with `/thinking`
+    pub show_reasoning: bool,
     pub inference_params: InferenceParams,
+    // per-model changes to `inference_params`, keyed by the model name (e.g. "ClaudeV35Haiku")
+    pub model_inference_params: BTreeMap<String, InferenceOverrides>,
+    // models and parameters used for the helper tasks (titles, summaries, project guessing)
+    pub helper_model: HelperModelConfig,
+    // the Bedrock Guardrail applied to every model call
+    pub guardrail: GuardrailConfig,
+    // what source code gets collected for code chat
+    pub code_chat: CodeChatConfig,
+    // the embedding index used by code chat (see `bedrust index`)
+    pub index: IndexConfig,
+    // how images are captioned (`--caption`)
+    pub caption: CaptionConfig,
+    // where and how the chat is exported with `/h`
+    pub export: ExportConfig,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl BedrustConfig {
+    // The inference parameters for a model, with its overrides applied
+    pub fn inference_params_for(&self, model: &ArgModels) -> InferenceParams {
+        // the overrides can be keyed by the name (as in `default_model`) or by the model id
+        let overrides = self
+            .model_inference_params
+            .get(&format!("{:?}", model))
+            .or_else(|| self.model_inference_params.get(model.to_str()));
+        match overrides {
+            Some(o) => self.inference_params.with_overrides(o),
+            None => self.inference_params.clone(),
+        }
+    }
+}
+
+impl Default for BedrustConfig {
+    fn default() -> Self {
+        BedrustConfig {
+            version: constants::CONFIG_VERSION,
+            aws_profile: "default".into(),
+            supported_images: vec!["jpg".into(), "jpeg".into(), "png".into(), "bmp".into()],
+            caption_prompt: constants::DEFAULT_CAPTION_PROMPT.into(),
+            default_model: None,
+            show_banner: true,
+            show_reasoning: true,
+            inference_params: InferenceParams::default(),
+            model_inference_params: BTreeMap::new(),
+            helper_model: HelperModelConfig::default(),
+            guardrail: GuardrailConfig::default(),
+            code_chat: CodeChatConfig::default(),
+            index: IndexConfig::default(),
+            caption: CaptionConfig::default(),
+            export: ExportConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
 pub struct InferenceParams {
     pub temperature: f32,
     pub max_tokens: i32,
     pub top_p: f32,
+    // the model stops generating once it produces one of these
+    pub stop_sequences: Vec<String>,
+    // model specific fields, sent as `additionalModelRequestFields` - e.g. {"top_k": 50}
+    pub additional_fields: BTreeMap<String, serde_json::Value>,
+    // enables extended thinking (Claude 3.7 and newer) with this many tokens to think with
+    pub thinking_budget: Option<u32>,
+    // insert cache points for the models that support prompt caching
+    pub prompt_caching: bool,
+}
+
+impl Default for InferenceParams {
+    fn default() -> Self {
+        InferenceParams {
+            temperature: 0.5,
+            max_tokens: 2048,
+            top_p: 0.8,
+            stop_sequences: Vec::new(),
+            additional_fields: BTreeMap::new(),
+            thinking_budget: None,
+            prompt_caching: true,
+        }
+    }
+}
+
+impl InferenceParams {
+    pub fn to_inference_configuration(&self) -> InferenceConfiguration {
+        // NOTE: with thinking enabled, Claude only accepts the default temperature and top_p
+        let thinking = self.thinking_budget.is_some();
+        InferenceConfiguration::builder()
+            .max_tokens(self.max_tokens)
+            .set_top_p((!thinking).then_some(self.top_p))
+            .set_temperature((!thinking).then_some(self.temperature))
+            .set_stop_sequences(if self.stop_sequences.is_empty() {
+                None
+            } else {
+                Some(self.stop_sequences.clone())
+            })
+            .build()
+    }
+
+    pub fn additional_model_request_fields(&self) -> Option<Document> {
+        let mut fields: HashMap<String, Document> = self
+            .additional_fields
+            .iter()
+            .map(|(k, v)| (k.clone(), json_to_document(v)))
+            .collect();
+        // a `thinking` field set by hand wins over the budget
+        if let Some(budget) = self.thinking_budget {
+            fields.entry("thinking".into()).or_insert_with(|| {
+                Document::Object(HashMap::from([
+                    ("type".into(), Document::String("enabled".into())),
+                    (
+                        "budget_tokens".into(),
+                        Document::Number(Number::PosInt(budget.into())),
+                    ),
+                ]))
+            });
+        }
+        if fields.is_empty() {
+            return None;
+        }
+        Some(Document::Object(fields))
+    }
+
+    // Returns a copy of these parameters, with the values set in the overrides replaced
+    pub fn with_overrides(&self, overrides: &InferenceOverrides) -> InferenceParams {
+        let mut params = self.clone();
+        if let Some(temperature) = overrides.temperature {
+            params.temperature = temperature;
+        }
+        if let Some(max_tokens) = overrides.max_tokens {
+            params.max_tokens = max_tokens;
+        }
+        if let Some(top_p) = overrides.top_p {
+            params.top_p = top_p;
+        }
+        if let Some(stop_sequences) = &overrides.stop_sequences {
+            params.stop_sequences = stop_sequences.clone();
+        }
+        for (k, v) in &overrides.additional_fields {
+            params.additional_fields.insert(k.clone(), v.clone());
+        }
+        if let Some(thinking_budget) = overrides.thinking_budget {
+            params.thinking_budget = Some(thinking_budget);
+        }
+        if let Some(prompt_caching) = overrides.prompt_caching {
+            params.prompt_caching = prompt_caching;
+        }
+        params
+    }
+
+    // The parameter and the value of a `/set <parameter> <value>` chat command. The value is the
+    // rest of the line, as lists and JSON values can have spaces in them
+    pub fn parse_set_command(question: &str) -> Option<(&str, &str)> {
+        let (key, value) = question.strip_prefix("/set ")?.trim().split_once(' ')?;
+        Some((key, value.trim())).filter(|(_, value)| !value.is_empty())
+    }
+
+    // Checks the parameters that only make sense together
+    pub fn check(&self) -> Result<(), anyhow::Error> {
+        // the thinking tokens count towards max_tokens, and the answer needs some too
+        if let Some(budget) = self.thinking_budget {
+            if self.max_tokens <= budget as i32 {
+                return Err(anyhow!(
+                    "max_tokens ({}) has to be larger than thinking_budget ({})",
+                    self.max_tokens,
+                    budget
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Sets a single parameter from its textual value, used by the `/set` chat command.
+    // `field.<name>` keys go into the model specific `additional_fields`, and setting one to
+    // `none` removes it. Nothing changes if the key is unknown, or if the result does not pass
+    // `check`.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), anyhow::Error> {
+        let mut params = self.clone();
+        params.set_value(key, value)?;
+        params.check()?;
+        *self = params;
+        Ok(())
+    }
+
+    fn set_value(&mut self, key: &str, value: &str) -> Result<(), anyhow::Error> {
+        let invalid = || anyhow!("`{}` is not a valid value for {}", value, key);
+        match key {
+            "temperature" => self.temperature = value.parse().map_err(|_| invalid())?,
+            "max_tokens" => self.max_tokens = value.parse().map_err(|_| invalid())?,
+            "top_p" => self.top_p = value.parse().map_err(|_| invalid())?,
+            "prompt_caching" => self.prompt_caching = value.parse().map_err(|_| invalid())?,
+            "thinking_budget" => {
+                self.thinking_budget = if value == "none" {
+                    None
+                } else {
+                    Some(value.parse().map_err(|_| invalid())?)
+                }
+            }
+            "stop_sequences" => {
+                self.stop_sequences = if value == "none" {
+                    Vec::new()
+                } else {
+                    value
+                        .split(',')
+                        .map(|s| s.trim().to_string())
+                        .filter(|s| !s.is_empty())
+                        .collect()
+                }
+            }
+            key => {
+                // a typo would otherwise only show up as an error of the model on the next turn
+                let Some(key) = key.strip_prefix("field.").filter(|k| !k.is_empty()) else {
+                    return Err(anyhow!(
+                        "There is no parameter called `{}`. Use one of temperature, max_tokens, top_p, prompt_caching, thinking_budget, stop_sequences, or field.<name> for a model specific field",
+                        key
+                    ));
+                };
+                if value == "none" {
+                    self.additional_fields.remove(key);
+                } else {
+                    // numbers, booleans and JSON objects keep their type - the rest are strings
+                    let v = serde_json::from_str(value)
+                        .unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+                    self.additional_fields.insert(key.to_string(), v);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for InferenceParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "temperature: {}", self.temperature)?;
+        writeln!(f, "max_tokens: {}", self.max_tokens)?;
+        writeln!(f, "top_p: {}", self.top_p)?;
+        writeln!(f, "prompt_caching: {}", self.prompt_caching)?;
+        write!(f, "stop_sequences: {:?}", self.stop_sequences)?;
+        if let Some(budget) = self.thinking_budget {
+            write!(f, "\nthinking_budget: {}", budget)?;
+        }
+        for (k, v) in &self.additional_fields {
+            write!(f, "\nfield.{}: {}", k, v)?;
+        }
+        Ok(())
+    }
+}
+
+// Per-model changes to the inference parameters, only the values that are set are replaced
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct InferenceOverrides {
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<i32>,
+    pub top_p: Option<f32>,
+    pub stop_sequences: Option<Vec<String>>,
+    pub additional_fields: BTreeMap<String, serde_json::Value>,
+    pub thinking_budget: Option<u32>,
+    pub prompt_caching: Option<bool>,
+}
+
+// Limits for the source code collected in code chat
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CodeChatConfig {
+    // how deep to look into the source directory, None means no limit
+    pub max_depth: Option<usize>,
+    // files larger than this (in bytes) are skipped
+    pub max_file_size: u64,
+    // the most tokens of source code to send, defaults to what fits in the context window
+    pub token_budget: Option<usize>,
+    // globs of the files to send, when set the project type is not detected
+    pub include: Vec<String>,
+    // globs of the files to never send
+    pub exclude: Vec<String>,
+    // ask the helper model for the project type, when it can not be detected from the files
+    pub llm_project_guess: bool,
+}
+
+impl Default for CodeChatConfig {
+    fn default() -> Self {
+        CodeChatConfig {
+            max_depth: None,
+            max_file_size: 256 * 1024,
+            token_budget: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            llm_project_guess: false,
+        }
+    }
+}
+
+// How the source code gets chunked and embedded by `bedrust index`, and how much of it is
+// retrieved for every question in code chat
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct IndexConfig {
+    pub embedding_model: EmbeddingModel,
+    // lines of code in a chunk
+    pub chunk_lines: usize,
+    // lines shared between two neighbouring chunks
+    pub chunk_overlap: usize,
+    // how many chunks are sent with every question
+    pub top_k: usize,
+    // use the index in code chat when the source directory has one
+    pub enabled: bool,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        IndexConfig {
+            embedding_model: EmbeddingModel::TitanEmbedTextV2,
+            chunk_lines: 60,
+            chunk_overlap: 10,
+            top_k: 8,
+            enabled: true,
+        }
+    }
+}
+
+// Where the exported chats go (`/h`), and what they look like
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct ExportConfig {
+    // the directory the exports are written to, the current directory when not set
+    pub dir: Option<PathBuf>,
+    // templates found here (e.g. `chat_export.html`) are used instead of the built-in ones,
+    // ~/.config/bedrust/templates when not set
+    pub template_dir: Option<PathBuf>,
+    // keep the source code sent in code chat, instead of replacing it with a note
+    pub keep_source: bool,
+}
+
+impl ExportConfig {
+    pub fn template_dir(&self) -> PathBuf {
+        match &self.template_dir {
+            Some(dir) => dir.clone(),
+            None => home_dir()
+                .unwrap_or_default()
+                .join(format!(".config/{}/templates", constants::CONFIG_DIR_NAME)),
+        }
+    }
+}
+
+// How a directory of images gets captioned
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CaptionConfig {
+    // how many images are captioned at the same time
+    pub concurrency: usize,
+    // how many times an image is retried when the model is throttled or not ready
+    pub max_retries: u32,
+    // larger images are downscaled to fit in this many pixels (width and height)
+    pub max_dimension: u32,
+    // look for images in the subdirectories too, same as `--recursive`
+    pub recursive: bool,
+    // globs of the images to caption, relative to the directory (matched case insensitively)
+    pub include: Vec<String>,
+    // globs of the images to never caption
+    pub exclude: Vec<String>,
+    // talk to this S3 compatible endpoint instead of Amazon S3 (e.g. MinIO or LocalStack)
+    pub s3_endpoint: Option<String>,
+    // the profile from `profiles` to caption with, instead of the single `caption_prompt` caption
+    pub profile: Option<String>,
+    // named sets of fields to extract from every image in one call
+    pub profiles: BTreeMap<String, CaptionProfile>,
+    // captioning with a Bedrock batch inference job (`--batch`)
+    pub batch: CaptionBatchConfig,
+    // describing videos (`bedrust video`)
+    pub video: VideoConfig,
+}
+
+impl Default for CaptionConfig {
+    fn default() -> Self {
+        CaptionConfig {
+            concurrency: 4,
+            max_retries: 5,
+            max_dimension: 1568,
+            recursive: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            s3_endpoint: None,
+            profile: None,
+            profiles: BTreeMap::from([("cms".into(), CaptionProfile::default())]),
+            batch: CaptionBatchConfig::default(),
+            video: VideoConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct VideoConfig {
+    // the prompt every sampled frame is described with
+    pub prompt: String,
+    // seconds between the sampled frames
+    pub interval: f64,
+    // how much the picture has to change to count as a new scene (0 to 1), with `--scenes`
+    pub scene_threshold: f64,
+    // stop sampling after this many frames
+    pub max_frames: usize,
+    // the ffmpeg and ffprobe commands, or their full paths
+    pub ffmpeg: String,
+    pub ffprobe: String,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        VideoConfig {
+            prompt: constants::DEFAULT_VIDEO_FRAME_PROMPT.into(),
+            interval: 10.0,
+            scene_threshold: 0.3,
+            max_frames: 500,
+            ffmpeg: "ffmpeg".into(),
+            ffprobe: "ffprobe".into(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CaptionBatchConfig {
+    // caption every run with a batch job, same as `--batch`
+    pub enabled: bool,
+    // where the model inputs and outputs go, e.g. "s3://my-bucket/bedrust-batch/"
+    pub s3_uri: Option<String>,
+    // the service role Bedrock uses to read and write `s3_uri`
+    pub role_arn: Option<String>,
+    // how often to check on the job
+    pub poll_seconds: u64,
+    // how long the job can run before it is stopped (24 to 168 hours, Bedrock defaults to 168)
+    pub timeout_hours: Option<i32>,
+}
+
+impl Default for CaptionBatchConfig {
+    fn default() -> Self {
+        CaptionBatchConfig {
+            enabled: false,
+            s3_uri: None,
+            role_arn: None,
+            poll_seconds: 60,
+            timeout_hours: None,
+        }
+    }
+}
+
+// Several fields extracted from an image in one call (as structured output). The first field is
+// also the caption of the image, the one written to sidecars, embedded into the image and shown in
+// the gallery, so it should be text.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CaptionProfile {
+    pub prompt: String,
+    pub fields: Vec<CaptionField>,
+}
+
+impl Default for CaptionProfile {
+    fn default() -> Self {
+        let field = |name: &str, kind, description: &str| CaptionField {
+            name: name.into(),
+            kind,
+            description: description.into(),
+        };
+        CaptionProfile {
+            prompt: constants::DEFAULT_CAPTION_PROFILE_PROMPT.into(),
+            fields: vec![
+                field(
+                    "alt_text",
+                    CaptionFieldKind::Text,
+                    "Short alt text for the image, under 125 characters",
+                ),
+                field(
+                    "description",
+                    CaptionFieldKind::Text,
+                    "A detailed description of the image, in a few sentences",
+                ),
+                field(
+                    "tags",
+                    CaptionFieldKind::List,
+                    "Keywords for searching the image, lowercase",
+                ),
+                field(
+                    "text",
+                    CaptionFieldKind::Text,
+                    "All the text visible in the image, word for word. Empty if there is none",
+                ),
+                field(
+                    "sensitive",
+                    CaptionFieldKind::Flag,
+                    "true if the image shows nudity, violence, gore or anything else not safe for work",
+                ),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CaptionField {
+    pub name: String,
+    pub kind: CaptionFieldKind,
+    // what the model is asked to put in the field
+    pub description: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum CaptionFieldKind {
+    // a string
+    Text,
+    // a list of strings
+    List,
+    // true or false
+    Flag,
+}
+
+// Amazon Bedrock Guardrail settings, the guardrail is only used when `id` is set
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct GuardrailConfig {
+    // the guardrail identifier or ARN
+    pub id: Option<String>,
+    // a version number, or "DRAFT"
+    pub version: String,
+    pub trace: GuardrailTraceMode,
+}
+
+impl Default for GuardrailConfig {
+    fn default() -> Self {
+        GuardrailConfig {
+            id: None,
+            version: "DRAFT".into(),
+            trace: GuardrailTraceMode::Enabled,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum GuardrailTraceMode {
+    Disabled,
+    Enabled,
+    EnabledFull,
+}
+
+impl From<GuardrailTraceMode> for GuardrailTrace {
+    fn from(value: GuardrailTraceMode) -> Self {
+        match value {
+            GuardrailTraceMode::Disabled => GuardrailTrace::Disabled,
+            GuardrailTraceMode::Enabled => GuardrailTrace::Enabled,
+            GuardrailTraceMode::EnabledFull => GuardrailTrace::EnabledFull,
+        }
+    }
+}
+
+impl GuardrailConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.id.is_some()
+    }
+
+    pub fn to_guardrail_configuration(&self) -> Option<GuardrailConfiguration> {
+        GuardrailConfiguration::builder()
+            .set_guardrail_identifier(self.id.clone())
+            .guardrail_version(&self.version)
+            .trace(self.trace.into())
+            .build()
+            .ok()
+    }
+
+    // the streaming API takes its own (but same looking) configuration type
+    pub fn to_guardrail_stream_configuration(&self) -> Option<GuardrailStreamConfiguration> {
+        GuardrailStreamConfiguration::builder()
+            .set_guardrail_identifier(self.id.clone())
+            .guardrail_version(&self.version)
+            .trace(self.trace.into())
+            .build()
+            .ok()
+    }
+}
+
+// Converts JSON (how we store the model specific fields) to the Document type the SDK expects
+pub(crate) fn json_to_document(value: &serde_json::Value) -> Document {
+    match value {
+        serde_json::Value::Null => Document::Null,
+        serde_json::Value::Bool(b) => Document::Bool(*b),
+        serde_json::Value::Number(n) => {
+            if let Some(u) = n.as_u64() {
+                Document::Number(Number::PosInt(u))
+            } else if let Some(i) = n.as_i64() {
+                Document::Number(Number::NegInt(i))
+            } else {
+                Document::Number(Number::Float(n.as_f64().unwrap_or_default()))
+            }
+        }
+        serde_json::Value::String(s) => Document::String(s.clone()),
+        serde_json::Value::Array(a) => Document::Array(a.iter().map(json_to_document).collect()),
+        serde_json::Value::Object(o) => Document::Object(
+            o.iter()
+                .map(|(k, v)| (k.clone(), json_to_document(v)))
+                .collect(),
+        ),
+    }
+}
+
+// And back, for the documents we get from the models (e.g. the input of a tool)
+pub(crate) fn document_to_json(document: &Document) -> serde_json::Value {
+    match document {
+        Document::Null => serde_json::Value::Null,
+        Document::Bool(b) => serde_json::Value::Bool(*b),
+        Document::Number(Number::PosInt(u)) => serde_json::Value::from(*u),
+        Document::Number(Number::NegInt(i)) => serde_json::Value::from(*i),
+        Document::Number(Number::Float(f)) => serde_json::Value::from(*f),
+        Document::String(s) => serde_json::Value::String(s.clone()),
+        Document::Array(a) => serde_json::Value::Array(a.iter().map(document_to_json).collect()),
+        Document::Object(o) => serde_json::Value::Object(
+            o.iter()
+                .map(|(k, v)| (k.clone(), document_to_json(v)))
+                .collect(),
+        ),
+    }
+}
+
+// The helper model is a (usually small and cheap) model used for the tasks that happen in the
+// background: generating conversation titles and summaries, and guessing project types in code
+// chat. Every task can override the model, otherwise the `model` defined here is used.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HelperModelConfig {
+    #[serde(default = "_default_helper_model")]
+    pub model: Option<ArgModels>,
+    #[serde(default = "HelperTaskConfig::default_title")]
+    pub title: HelperTaskConfig,
+    #[serde(default = "HelperTaskConfig::default_summary")]
+    pub summary: HelperTaskConfig,
+    #[serde(default = "HelperTaskConfig::default_project_guess")]
+    pub project_guess: HelperTaskConfig,
+}
+
+impl Default for HelperModelConfig {
+    fn default() -> Self {
+        HelperModelConfig {
+            model: _default_helper_model(),
+            title: HelperTaskConfig::default_title(),
+            summary: HelperTaskConfig::default_summary(),
+            project_guess: HelperTaskConfig::default_project_guess(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HelperTaskConfig {
+    // overrides the helper model for this task only
+    #[serde(default)]
+    pub model: Option<ArgModels>,
+    #[serde(default)]
+    pub inference_params: InferenceParams,
+}
+
+impl HelperTaskConfig {
+    fn new(max_tokens: i32) -> Self {
+        HelperTaskConfig {
+            model: None,
+            inference_params: InferenceParams {
+                temperature: 0.2,
+                max_tokens,
+                top_p: 0.8,
+                ..Default::default()
+            },
+        }
+    }
+    pub fn default_title() -> Self {
+        Self::new(32)
+    }
+    pub fn default_summary() -> Self {
+        Self::new(256)
+    }
+    pub fn default_project_guess() -> Self {
+        Self::new(2048)
+    }
 }
 
 #[derive(clap::ValueEnum, Clone, Serialize, Deserialize, Debug, Copy)]
@@ -69,6 +925,7 @@ pub enum ArgModels {
     ClaudeV3Haiku,
     ClaudeV35Sonnet,
     ClaudeV352Sonnet,
+    ClaudeV37Sonnet,
     ClaudeV35Haiku,
     Jurrasic2Ultra,
     TitanTextExpressV1,
@@ -98,6 +955,7 @@ impl ArgModels {
             ArgModels::ClaudeV3Opus => "anthropic.claude-3-opus-20240229-v1:0",
             ArgModels::ClaudeV35Sonnet => "anthropic.claude-3-5-sonnet-20240620-v1:0",
             ArgModels::ClaudeV352Sonnet => "anthropic.claude-3-5-sonnet-20241022-v2:0",
+            ArgModels::ClaudeV37Sonnet => "us.anthropic.claude-3-7-sonnet-20250219-v1:0",
             ArgModels::Llama270b => "meta.llama2-70b-chat-v1",
             ArgModels::Llama31405bInstruct => "meta.llama3-1-405b-instruct-v1:0",
             ArgModels::Llama3170bInstruct => "meta.llama3-1-70b-instruct-v1:0",
@@ -114,28 +972,138 @@ impl ArgModels {
             ArgModels::NovaPro => "us.amazon.nova-pro-v1:0",
         }
     }
+
+    pub fn from_model_id(model_id: &str) -> Option<ArgModels> {
+        ArgModels::value_variants()
+            .iter()
+            .find(|m| m.to_str() == model_id)
+            .copied()
+    }
+
+    // the size of the context window, in tokens
+    pub fn context_window(&self) -> usize {
+        match self {
+            ArgModels::Llama270b | ArgModels::CohereCommand => 4_096,
+            ArgModels::Jurrasic2Ultra | ArgModels::TitanTextExpressV1 => 8_192,
+            ArgModels::Mixtral8x7bInstruct
+            | ArgModels::Mistral7bInstruct
+            | ArgModels::MistralLarge => 32_000,
+            ArgModels::ClaudeV2 => 100_000,
+            ArgModels::Llama31405bInstruct
+            | ArgModels::Llama3170bInstruct
+            | ArgModels::Llama318bInstruct
+            | ArgModels::MistralLarge2
+            | ArgModels::NovaMicro => 128_000,
+            ArgModels::ClaudeV21
+            | ArgModels::ClaudeV3Opus
+            | ArgModels::ClaudeV3Sonnet
+            | ArgModels::ClaudeV3Haiku
+            | ArgModels::ClaudeV35Sonnet
+            | ArgModels::ClaudeV352Sonnet
+            | ArgModels::ClaudeV37Sonnet
+            | ArgModels::ClaudeV35Haiku => 200_000,
+            ArgModels::NovaLite | ArgModels::NovaPro => 300_000,
+        }
+    }
+
+    // on-demand price (USD) per 1000 input tokens in us-east-1, only used for estimates
+    pub fn input_price_per_1k(&self) -> f64 {
+        match self {
+            ArgModels::Llama270b => 0.00195,
+            ArgModels::Llama31405bInstruct => 0.00532,
+            ArgModels::Llama3170bInstruct => 0.00072,
+            ArgModels::Llama318bInstruct => 0.00022,
+            ArgModels::CohereCommand => 0.0015,
+            ArgModels::ClaudeV2 | ArgModels::ClaudeV21 => 0.008,
+            ArgModels::ClaudeV3Opus => 0.015,
+            ArgModels::ClaudeV3Sonnet
+            | ArgModels::ClaudeV35Sonnet
+            | ArgModels::ClaudeV352Sonnet
+            | ArgModels::ClaudeV37Sonnet => 0.003,
+            ArgModels::ClaudeV3Haiku => 0.00025,
+            ArgModels::ClaudeV35Haiku => 0.0008,
+            ArgModels::Jurrasic2Ultra => 0.0188,
+            ArgModels::TitanTextExpressV1 => 0.0002,
+            ArgModels::Mixtral8x7bInstruct => 0.00045,
+            ArgModels::Mistral7bInstruct => 0.00015,
+            ArgModels::MistralLarge => 0.004,
+            ArgModels::MistralLarge2 => 0.002,
+            ArgModels::NovaMicro => 0.000035,
+            ArgModels::NovaLite => 0.00006,
+            ArgModels::NovaPro => 0.0008,
+        }
+    }
+
+    // models that accept cache point blocks (prompt caching) on Amazon Bedrock
+    pub fn supports_prompt_caching(&self) -> bool {
+        matches!(
+            self,
+            ArgModels::ClaudeV35Haiku
+                | ArgModels::ClaudeV37Sonnet
+                | ArgModels::NovaMicro
+                | ArgModels::NovaLite
+                | ArgModels::NovaPro
+        )
+    }
+}
+
+// The Bedrock models used to embed the source code for `bedrust index`
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum EmbeddingModel {
+    TitanEmbedTextV2,
+    TitanEmbedTextV1,
+    CohereEmbedEnglish,
+    CohereEmbedMultilingual,
+}
+
+impl EmbeddingModel {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            EmbeddingModel::TitanEmbedTextV2 => "amazon.titan-embed-text-v2:0",
+            EmbeddingModel::TitanEmbedTextV1 => "amazon.titan-embed-text-v1",
+            EmbeddingModel::CohereEmbedEnglish => "cohere.embed-english-v3",
+            EmbeddingModel::CohereEmbedMultilingual => "cohere.embed-multilingual-v3",
+        }
+    }
+
+    // on-demand price in USD per 1000 input tokens (us-east-1)
+    pub fn input_price_per_1k(&self) -> f64 {
+        match self {
+            EmbeddingModel::TitanEmbedTextV2 => 0.00002,
+            EmbeddingModel::TitanEmbedTextV1 => 0.0001,
+            EmbeddingModel::CohereEmbedEnglish | EmbeddingModel::CohereEmbedMultilingual => 0.0001,
+        }
+    }
 }
 // ######################################## END ARGUMENT PARSING
 // ######################################## CONST FUNCTIONS
 // Used to set default values to struct fields during serialization
-const fn _default_true() -> bool {
-    true
+const fn _legacy_config_version() -> u32 {
+    1
+}
+const fn _default_helper_model() -> Option<ArgModels> {
+    Some(ArgModels::ClaudeV3Haiku)
 }
 // ######################################## END CONST FUNCTIONS
 
-pub fn hello_header(s: &str) -> Result<(), anyhow::Error> {
+pub fn hello_header(s: &str, show_banner: bool) -> Result<(), anyhow::Error> {
     let home_dir = home_dir().expect("Failed to get HOME directory");
     let config_dir = home_dir.join(format!(".config/{}", constants::CONFIG_DIR_NAME));
     let mut stdout = StandardStream::stdout(ColorChoice::Always);
 
     // test if show_banner is true
-    if load_bedrust_config()?.show_banner {
+    if show_banner {
         let figlet_font_file_path = config_dir.join(constants::FIGLET_FONT_FILENAME);
-        let figlet_path_str = figlet_font_file_path
-            .as_path()
-            .to_str()
-            .ok_or_else(|| anyhow!("Was unable to parse Figlet font path to string"))?;
-        let ansi_font = FIGfont::from_file(figlet_path_str).unwrap();
+        // the font is only there after `--init`, otherwise use the one we ship with
+        let ansi_font = if figlet_font_file_path.exists() {
+            let figlet_path_str = figlet_font_file_path
+                .as_path()
+                .to_str()
+                .ok_or_else(|| anyhow!("Was unable to parse Figlet font path to string"))?;
+            FIGfont::from_file(figlet_path_str).unwrap()
+        } else {
+            FIGfont::from_content(constants::FIGLET_FONT).unwrap()
+        };
         let hello = ansi_font.convert(s);
 
         stdout.set_color(ColorSpec::new().set_fg(Some(Color::Rgb(255, 153, 0))))?;
@@ -151,6 +1119,23 @@ pub fn hello_header(s: &str) -> Result<(), anyhow::Error> {
         "{}",
         "/c\t - Clear current chat history".truecolor(255, 229, 153)
     );
+    println!(
+        "{}",
+        "/set\t - Show or change inference parameters (/set temperature 0.2)"
+            .truecolor(255, 229, 153)
+    );
+    println!(
+        "{}",
+        "/thinking - Show or hide the reasoning of the model".truecolor(255, 229, 153)
+    );
+    println!(
+        "{}",
+        "/refresh - Send the source files that changed (code chat)".truecolor(255, 229, 153)
+    );
+    println!(
+        "{}",
+        "/undo\t - Revert the last applied patch (--edit)".truecolor(255, 229, 153)
+    );
     println!(
         "{}",
         "/s\t - (BETA) Save chat history".truecolor(255, 229, 153)
@@ -179,20 +1164,39 @@ pub fn hello_header(s: &str) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+// Loads the configuration from all the layers (defaults, global, project and environment)
 pub fn load_bedrust_config() -> Result<BedrustConfig, anyhow::Error> {
-    let home_dir = home_dir().expect("Failed to get HOME directory");
-    let config_dir = home_dir.join(format!(".config/{}", constants::CONFIG_DIR_NAME));
-    let bedrust_config_file_path = config_dir.join(constants::BEDRUST_CONFIG_FILE_NAME);
-
-    let file = fs::File::open(bedrust_config_file_path)?;
-    //let config: BedrustConfig = ron::de::from_reader(file)?;
-    let config: BedrustConfig = ron::de::from_reader(file)?;
-    Ok(config)
+    Ok(crate::config::load_layered_config(&[])?.config)
 }
 
 pub fn print_warning(s: &str) {
     println!("{}", s.yellow());
 }
+
+// A directory under the system temp dir with a random name, removed again when it is dropped
+// (even if whatever was using it failed or panicked)
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(prefix: &str) -> Result<TempDir, anyhow::Error> {
+        let suffix = Alphanumeric.sample_string(&mut rand::thread_rng(), 8);
+        let path = std::env::temp_dir().join(format!("{}-{}", prefix, suffix));
+        fs::create_dir_all(&path)?;
+        Ok(TempDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
 // TODO: Implement checking for AWS credentials
 
 // function that checks if there are any configuration files present
@@ -260,3 +1264,81 @@ pub fn initialize_config() -> Result<(), anyhow::Error> {
     println!("✅ | Bedrust configuration has been initialized in ~/.config/bedrust. You may now use it as normal.");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thinking_budget_replaces_temperature_and_top_p() {
+        let mut params = InferenceParams::default();
+        assert!(params.additional_model_request_fields().is_none());
+        assert_eq!(params.to_inference_configuration().temperature(), Some(0.5));
+
+        // thinking adds its own field, and leaves temperature and top_p to the model
+        params.set("max_tokens", "4096").unwrap();
+        params.set("thinking_budget", "1024").unwrap();
+        let configuration = params.to_inference_configuration();
+        assert_eq!(configuration.temperature(), None);
+        assert_eq!(configuration.top_p(), None);
+        assert_eq!(configuration.max_tokens(), Some(4096));
+        let fields = params.additional_model_request_fields().unwrap();
+        assert_eq!(
+            document_to_json(&fields),
+            serde_json::json!({"thinking": {"type": "enabled", "budget_tokens": 1024}})
+        );
+
+        // the budget has to leave room for the answer
+        assert!(params.set("thinking_budget", "4096").is_err());
+        assert!(params.set("max_tokens", "1000").is_err());
+        assert_eq!(params.thinking_budget, Some(1024));
+        assert_eq!(params.max_tokens, 4096);
+    }
+
+    #[test]
+    fn set_command_keeps_the_whole_value() {
+        assert_eq!(
+            InferenceParams::parse_set_command("/set stop_sequences END, STOP"),
+            Some(("stop_sequences", "END, STOP"))
+        );
+        assert_eq!(
+            InferenceParams::parse_set_command("/set  thinking {\"type\": \"enabled\"} "),
+            Some(("thinking", "{\"type\": \"enabled\"}"))
+        );
+        assert_eq!(InferenceParams::parse_set_command("/set temperature"), None);
+        assert_eq!(InferenceParams::parse_set_command("/set"), None);
+
+        let mut params = InferenceParams::default();
+        let (key, value) =
+            InferenceParams::parse_set_command("/set field.thinking {\"type\": \"enabled\"}")
+                .unwrap();
+        params.set(key, value).unwrap();
+        assert_eq!(
+            params.additional_fields["thinking"],
+            serde_json::json!({"type": "enabled"})
+        );
+        // a typo is not sent to the model as a field of its own
+        assert!(params.set("temprature", "0.2").is_err());
+        assert!(params.set("field.", "0.2").is_err());
+        assert_eq!(params.additional_fields.len(), 1);
+        params.set("field.thinking", "none").unwrap();
+        assert!(params.additional_fields.is_empty());
+    }
+
+    #[test]
+    fn guardrail_is_only_attached_when_configured() {
+        assert!(GuardrailConfig::default()
+            .to_guardrail_configuration()
+            .is_none());
+
+        let config = GuardrailConfig {
+            id: Some("gr-123".into()),
+            trace: GuardrailTraceMode::EnabledFull,
+            ..Default::default()
+        };
+        let guardrail = config.to_guardrail_stream_configuration().unwrap();
+        assert_eq!(guardrail.guardrail_identifier(), "gr-123");
+        assert_eq!(guardrail.guardrail_version(), "DRAFT");
+        assert_eq!(guardrail.trace().as_str(), "enabled_full");
+    }
+}
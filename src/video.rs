@@ -0,0 +1,495 @@
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use image::codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder};
+use image::{imageops, AnimationDecoder, DynamicImage, Frames, ImageFormat, RgbaImage};
+use regex::Regex;
+use serde::Serialize;
+
+use crate::captioner::{caption_image, CaptionTask, Image};
+use crate::models::{check_model_features, ModelFeatures};
+use crate::utils::{print_warning, BedrustConfig, TempDir, VideoConfig};
+
+// NOTE:
+// `bedrust video training.mp4` - a draft audio description of a video. ffmpeg samples frames from
+// it, at an interval or on every scene change, the frames are captioned like any other image, and
+// every caption becomes a cue lasting until the next sampled frame. The track is written as WebVTT
+// or SRT, with a JSON file of the same cues next to it (`<name>.cues.json`).
+// Without ffmpeg only animations (GIF, WebP and APNG) can be described - the image crate decodes
+// their frames, and picks them the same way.
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum TrackFormat {
+    Vtt,
+    Srt,
+}
+
+impl TrackFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            TrackFormat::Vtt => "vtt",
+            TrackFormat::Srt => "srt",
+        }
+    }
+}
+
+// How the frames are picked
+pub enum Sampling {
+    // a frame every this many seconds
+    Interval(f64),
+    // a frame whenever the picture changes more than this (0 to 1)
+    Scenes(f64),
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Cue {
+    // seconds from the start of the video
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
+// What to describe, and how
+pub struct VideoJob {
+    pub file: PathBuf,
+    pub sampling: Sampling,
+    pub format: TrackFormat,
+    // the description track, defaults to the video with the extension of the format
+    pub output: Option<PathBuf>,
+}
+
+pub async fn video_command(
+    job: VideoJob,
+    model_id: &str,
+    bedrock_client: &aws_sdk_bedrock::Client,
+    runtime_client: &aws_sdk_bedrockruntime::Client,
+    config: &BedrustConfig,
+) -> Result<(), anyhow::Error> {
+    let VideoJob {
+        file,
+        sampling,
+        format,
+        output,
+    } = job;
+    let file = file.as_path();
+    if !file.is_file() {
+        return Err(anyhow!("🔴 | {} does not exist", file.display()));
+    }
+    if !check_model_features(model_id, bedrock_client, ModelFeatures::Images).await? {
+        return Err(anyhow!(
+            "🔴 | The current model selected does not support Images. Please consider using one that does."
+        ));
+    }
+    let video = &config.caption.video;
+    let ffmpeg = has_ffmpeg(video);
+    if !ffmpeg {
+        print_warning(&format!(
+            "`{}` was not found, only animated GIF, WebP and PNG files can be described without ffmpeg (https://ffmpeg.org)",
+            video.ffmpeg
+        ));
+    }
+
+    println!("----------------------------------------");
+    println!("🎬 | Sampling frames from {}", file.display());
+    let frames_dir = TempDir::new("bedrust-video")?;
+    let sampled = match ffmpeg {
+        true => video_duration(file, video).and_then(|duration| {
+            Ok((
+                duration,
+                sample_frames(file, &sampling, frames_dir.path(), video)?,
+            ))
+        }),
+        false => sample_animation(file, &sampling, frames_dir.path(), video.max_frames),
+    }
+    .and_then(|(duration, frames)| match frames.is_empty() {
+        true => Err(anyhow!("🔴 | No frames were sampled from the video")),
+        false => Ok((duration, frames)),
+    });
+    let (duration, frames) = sampled?;
+    println!(
+        "🔎 | Sampled {} frames from {} of video",
+        frames.len(),
+        format_timestamp(duration, '.')
+    );
+
+    let mut images = frames
+        .iter()
+        .map(|(_, path)| Image::from_path(path))
+        .collect::<Result<Vec<_>, _>>()?;
+    let task = CaptionTask::Prompt(video.prompt.clone());
+    let captioned = caption_image(
+        &mut images,
+        model_id,
+        &task,
+        &config.guardrail,
+        runtime_client,
+        &config.caption,
+        None,
+    )
+    .await;
+    drop(frames_dir);
+    captioned?;
+
+    let mut captions = Vec::new();
+    for ((time, _), image) in frames.iter().zip(&images) {
+        if let Some(error) = &image.error {
+            print_warning(&format!(
+                "No description at {}: {}",
+                format_timestamp(*time, '.'),
+                error
+            ));
+        }
+        captions.push((*time, image.caption.clone()));
+    }
+    let cues = build_cues(&captions, duration);
+
+    let track = output.unwrap_or_else(|| file.with_extension(format.extension()));
+    if let Some(parent) = track.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&track, render_track(&cues, format))?;
+    let json = cues_path(&track);
+    fs::write(&json, serde_json::to_string_pretty(&cues)?)?;
+    println!(
+        "✅ | Wrote {} descriptions to `{}` and `{}`",
+        cues.len(),
+        track.display(),
+        json.display()
+    );
+    println!("----------------------------------------");
+    Ok(())
+}
+
+// The JSON cues go next to the track, as `<name>.cues.json` so they never overwrite it
+fn cues_path(track: &Path) -> PathBuf {
+    track.with_extension("cues.json")
+}
+
+// Both ffmpeg and ffprobe are needed
+fn has_ffmpeg(config: &VideoConfig) -> bool {
+    [&config.ffmpeg, &config.ffprobe].iter().all(|tool| {
+        Command::new(tool)
+            .arg("-version")
+            .output()
+            .is_ok_and(|o| o.status.success())
+    })
+}
+
+fn video_duration(file: &Path, config: &VideoConfig) -> Result<f64, anyhow::Error> {
+    let output = Command::new(&config.ffprobe)
+        .args(["-v", "error", "-show_entries", "format=duration", "-of"])
+        .arg("default=noprint_wrappers=1:nokey=1")
+        .arg(file)
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "🔴 | Could not read {}: {}",
+            file.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("🔴 | Could not tell how long {} is", file.display()))
+}
+
+// Writes the sampled frames to `dir` and returns them with their time. The `showinfo` filter logs
+// the time of every frame that gets through the `select` filter, in the order they are written.
+fn sample_frames(
+    file: &Path,
+    sampling: &Sampling,
+    dir: &Path,
+    config: &VideoConfig,
+) -> Result<Vec<(f64, PathBuf)>, anyhow::Error> {
+    let select = match sampling {
+        Sampling::Interval(seconds) => format!(
+            "select='isnan(prev_selected_t)+gte(t-prev_selected_t\\,{})'",
+            seconds.max(0.1)
+        ),
+        // the first frame, and every scene change
+        Sampling::Scenes(threshold) => format!("select='eq(n\\,0)+gt(scene\\,{})'", threshold),
+    };
+    let output = Command::new(&config.ffmpeg)
+        .args(["-hide_banner", "-nostdin", "-loglevel", "info", "-i"])
+        .arg(file)
+        .args(["-vf", &format!("{},showinfo", select)])
+        .args(["-fps_mode", "vfr", "-q:v", "3"])
+        .args(["-frames:v", &config.max_frames.to_string()])
+        .arg(dir.join("frame-%05d.jpg"))
+        .output()?;
+    let log = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        let last = log.lines().last().unwrap_or_default();
+        return Err(anyhow!("🔴 | ffmpeg could not sample the frames: {}", last));
+    }
+    let frames = frame_times(&log)
+        .into_iter()
+        .enumerate()
+        .map(|(n, time)| (time, dir.join(format!("frame-{:05}.jpg", n + 1))))
+        .filter(|(_, path)| path.is_file())
+        .collect();
+    Ok(frames)
+}
+
+// Without ffmpeg: the frames of an animated GIF, WebP or PNG, picked like ffmpeg would pick them.
+// Returns the length of the animation and the frames with their time.
+fn sample_animation(
+    file: &Path,
+    sampling: &Sampling,
+    dir: &Path,
+    max_frames: usize,
+) -> Result<(f64, Vec<(f64, PathBuf)>), anyhow::Error> {
+    let format = ImageFormat::from_path(file).ok();
+    if !matches!(
+        format,
+        Some(ImageFormat::Gif | ImageFormat::WebP | ImageFormat::Png)
+    ) {
+        return Err(anyhow!(
+            "🔴 | Describing {} needs ffmpeg (https://ffmpeg.org), install it or set its path in the `caption.video` section of the configuration. Without it only animated GIF, WebP and PNG files work",
+            file.display()
+        ));
+    }
+    let reader = BufReader::new(File::open(file)?);
+    let decoded: Frames = match format {
+        Some(ImageFormat::Gif) => GifDecoder::new(reader)?.into_frames(),
+        Some(ImageFormat::WebP) => WebPDecoder::new(reader)?.into_frames(),
+        _ => PngDecoder::new(reader)?.apng()?.into_frames(),
+    };
+
+    let mut time = 0.0;
+    let mut frames = Vec::new();
+    let mut previous: Option<RgbaImage> = None;
+    for frame in decoded {
+        let frame = frame?;
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        let delay = numerator as f64 / denominator.max(1) as f64 / 1000.0;
+        let picked = match sampling {
+            Sampling::Interval(seconds) => frames
+                .last()
+                .is_none_or(|(last, _): &(f64, PathBuf)| time - last >= seconds.max(0.1)),
+            Sampling::Scenes(threshold) => {
+                // compared to the frame before it, like the `scene` score of ffmpeg
+                let small = imageops::thumbnail(frame.buffer(), 64, 64);
+                let changed = previous
+                    .as_ref()
+                    .is_none_or(|previous| picture_change(previous, &small) > *threshold);
+                previous = Some(small);
+                changed
+            }
+        };
+        if picked && frames.len() < max_frames {
+            let path = dir.join(format!("frame-{:05}.jpg", frames.len() + 1));
+            DynamicImage::ImageRgba8(frame.into_buffer())
+                .to_rgb8()
+                .save(&path)?;
+            frames.push((time, path));
+        }
+        time += delay;
+    }
+    Ok((time, frames))
+}
+
+// How different two pictures of the same size are, from 0 (the same) to 1
+fn picture_change(a: &RgbaImage, b: &RgbaImage) -> f64 {
+    let total: u64 = a
+        .pixels()
+        .zip(b.pixels())
+        .flat_map(|(a, b)| (0..3).map(move |c| a[c].abs_diff(b[c]) as u64))
+        .sum();
+    total as f64 / (a.width() as f64 * a.height() as f64 * 3.0 * 255.0).max(1.0)
+}
+
+fn frame_times(log: &str) -> Vec<f64> {
+    let re = Regex::new(r"Parsed_showinfo.*\spts_time:\s*(-?[0-9.]+)").unwrap();
+    log.lines()
+        .filter_map(|line| re.captures(line))
+        .filter_map(|c| c[1].parse().ok())
+        .collect()
+}
+
+// Every caption lasts until the next frame (the last one until the end of the video). The same
+// caption twice in a row becomes one longer cue, frames without a caption leave a gap.
+fn build_cues(captions: &[(f64, Option<String>)], duration: f64) -> Vec<Cue> {
+    let mut cues: Vec<Cue> = Vec::new();
+    for (n, (start, caption)) in captions.iter().enumerate() {
+        let end = captions
+            .get(n + 1)
+            .map(|(next, _)| *next)
+            .unwrap_or(duration)
+            .max(*start);
+        let Some(text) = caption.as_ref().map(|c| c.trim()).filter(|c| !c.is_empty()) else {
+            continue;
+        };
+        match cues.last_mut() {
+            Some(last) if last.text == text && (last.end - start).abs() < 0.001 => last.end = end,
+            _ => cues.push(Cue {
+                start: *start,
+                end,
+                text: text.to_string(),
+            }),
+        }
+    }
+    cues
+}
+
+// 01:02:03.456 (WebVTT) or 01:02:03,456 (SRT)
+fn format_timestamp(seconds: f64, separator: char) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        separator,
+        millis % 1000
+    )
+}
+
+fn render_track(cues: &[Cue], format: TrackFormat) -> String {
+    let mut track = String::new();
+    if let TrackFormat::Vtt = format {
+        track.push_str("WEBVTT\n\n");
+    }
+    for (n, cue) in cues.iter().enumerate() {
+        let separator = match format {
+            TrackFormat::Vtt => '.',
+            TrackFormat::Srt => {
+                track.push_str(&format!("{}\n", n + 1));
+                ','
+            }
+        };
+        // an empty line ends a cue, so there can not be one in the text
+        let text = cue
+            .text
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        track.push_str(&format!(
+            "{} --> {}\n{}\n\n",
+            format_timestamp(cue.start, separator),
+            format_timestamp(cue.end, separator),
+            text
+        ));
+    }
+    track
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_become_timed_cues() {
+        let log = "\
+Input #0, mov,mp4,m4a,3gp,3g2,mj2, from 'training.mp4':
+[Parsed_showinfo_1 @ 0x5581] n:   0 pts:      0 pts_time:0       duration:512 fmt:yuv420p
+[Parsed_showinfo_1 @ 0x5581] n:   1 pts: 153600 pts_time:10      duration:512 fmt:yuv420p
+[Parsed_showinfo_1 @ 0x5581] n:   2 pts: 309760 pts_time:20.1667 duration:512 fmt:yuv420p
+[Parsed_showinfo_1 @ 0x5581] n:   3 pts: 476160 pts_time:31      duration:512 fmt:yuv420p
+frame=    4 fps=0.0 q=3.0 Lsize=N/A time=00:00:31.00 bitrate=N/A speed= 120x";
+        let times = frame_times(log);
+        assert_eq!(times, vec![0.0, 10.0, 20.1667, 31.0]);
+
+        let captions = vec![
+            (times[0], Some("A presenter at a whiteboard.".to_string())),
+            (times[1], Some("A presenter at a whiteboard.".to_string())),
+            (times[2], None),
+            (
+                times[3],
+                Some("A close-up of a valve.\n\nThe handle is red.".to_string()),
+            ),
+        ];
+        let cues = build_cues(&captions, 3725.5);
+        assert_eq!(cues.len(), 2);
+        assert_eq!((cues[0].start, cues[0].end), (0.0, 20.1667));
+        assert_eq!((cues[1].start, cues[1].end), (31.0, 3725.5));
+
+        assert_eq!(
+            render_track(&cues, TrackFormat::Vtt),
+            "WEBVTT\n\n\
+             00:00:00.000 --> 00:00:20.167\nA presenter at a whiteboard.\n\n\
+             00:00:31.000 --> 01:02:05.500\nA close-up of a valve.\nThe handle is red.\n\n"
+        );
+        assert!(render_track(&cues, TrackFormat::Srt)
+            .starts_with("1\n00:00:00,000 --> 00:00:20,167\nA presenter at a whiteboard.\n\n2\n"));
+    }
+
+    #[test]
+    fn cues_never_overwrite_the_track() {
+        assert_eq!(
+            cues_path(Path::new("out/training.vtt")),
+            PathBuf::from("out/training.cues.json")
+        );
+        assert_eq!(
+            cues_path(Path::new("training.json")),
+            PathBuf::from("training.cues.json")
+        );
+    }
+
+    // a GIF of a second each: red, red, blue, blue, blue
+    fn red_then_blue(dir: &Path) -> PathBuf {
+        use image::codecs::gif::GifEncoder;
+        use image::{Delay, Frame, Rgba};
+
+        let gif = dir.join("training.gif");
+        let mut encoder = GifEncoder::new(File::create(&gif).unwrap());
+        for color in [
+            [255, 0, 0, 255],
+            [255, 0, 0, 255],
+            [0, 0, 255, 255],
+            [0, 0, 255, 255],
+            [0, 0, 255, 255],
+        ] {
+            let picture = RgbaImage::from_pixel(16, 16, Rgba(color));
+            let delay = Delay::from_numer_denom_ms(1000, 1);
+            encoder
+                .encode_frame(Frame::from_parts(picture, 0, 0, delay))
+                .unwrap();
+        }
+        gif
+    }
+
+    #[test]
+    fn animations_are_sampled_at_an_interval() {
+        let dir = TempDir::new("bedrust-animation").unwrap();
+        let gif = red_then_blue(dir.path());
+        let (duration, frames) =
+            sample_animation(&gif, &Sampling::Interval(2.0), dir.path(), 10).unwrap();
+        assert_eq!(duration, 5.0);
+        let times: Vec<_> = frames.iter().map(|(time, _)| *time).collect();
+        assert_eq!(times, vec![0.0, 2.0, 4.0]);
+        assert!(frames.iter().all(|(_, path)| path.is_file()));
+
+        let (_, frames) = sample_animation(&gif, &Sampling::Interval(1.0), dir.path(), 2).unwrap();
+        assert_eq!(frames.len(), 2);
+    }
+
+    #[test]
+    fn animations_are_sampled_on_scene_changes() {
+        let dir = TempDir::new("bedrust-animation").unwrap();
+        let gif = red_then_blue(dir.path());
+        let (_, frames) = sample_animation(&gif, &Sampling::Scenes(0.3), dir.path(), 10).unwrap();
+        let times: Vec<_> = frames.iter().map(|(time, _)| *time).collect();
+        assert_eq!(times, vec![0.0, 2.0]);
+    }
+
+    #[test]
+    fn other_videos_need_ffmpeg() {
+        let dir = TempDir::new("bedrust-animation").unwrap();
+        assert!(sample_animation(
+            &dir.path().join("training.mp4"),
+            &Sampling::Interval(2.0),
+            dir.path(),
+            10
+        )
+        .unwrap_err()
+        .to_string()
+        .contains("needs ffmpeg"));
+    }
+}
@@ -0,0 +1,224 @@
+use crate::code::{hash_contents, read_source_file, SourceFilter};
+use crate::constants;
+use crate::utils::CodeChatConfig;
+use anyhow::anyhow;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// NOTE:
+// The source code is sent once, at the start of code chat. While we keep editing it, the watcher
+// collects the files that change, and `/refresh` sends only those with the next question (Bedrock
+// wants the user and assistant messages to alternate, so it can not be a message on its own).
+// For every file we keep the hash of the version the model has seen, so a file that was saved
+// without changes (or changed back) is not sent again.
+
+pub struct SourceWatcher {
+    root: PathBuf,
+    // the watcher stops when it is dropped
+    _watcher: RecommendedWatcher,
+    // every path that changed since the start, absolute
+    changed: Arc<Mutex<BTreeSet<PathBuf>>>,
+    // the hash of the version of every file the model has seen, relative to the root
+    seen: BTreeMap<PathBuf, String>,
+    // new files are only picked up if they have one of these extensions
+    extensions: BTreeSet<String>,
+    max_file_size: u64,
+}
+
+// A file that is different from what the model has seen
+#[derive(Debug)]
+pub struct FileChange {
+    pub path: PathBuf,
+    // None if the file was deleted
+    pub contents: Option<String>,
+}
+
+// The changed files, queued with `/refresh` and sent with the next question
+#[derive(Debug)]
+pub struct SourceUpdate {
+    pub prompt: String,
+    // what the model will have seen after this update - None for deleted files
+    versions: Vec<(PathBuf, Option<String>)>,
+}
+
+impl SourceWatcher {
+    pub fn new(
+        root: &Path,
+        seen: BTreeMap<PathBuf, String>,
+        config: &CodeChatConfig,
+    ) -> Result<Self, anyhow::Error> {
+        let root = root
+            .canonicalize()
+            .map_err(|_| anyhow!("🔴 | The specified path does not exist. Sorry!"))?;
+        let changed = Arc::new(Mutex::new(BTreeSet::new()));
+
+        let events = changed.clone();
+        // only the files code chat would send are tracked, the rest (build output, ignored
+        // files ...) can change as much as it likes
+        let filter = SourceFilter::new(&root, config)?;
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+            let Ok(event) = res else {
+                return;
+            };
+            if !matches!(
+                event.kind,
+                EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+            ) {
+                return;
+            }
+            if let Ok(mut changed) = events.lock() {
+                changed.extend(event.paths.into_iter().filter(|p| filter.contains(p)));
+            }
+        })?;
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+
+        let extensions = seen
+            .keys()
+            .filter_map(|p| p.extension().and_then(|e| e.to_str()))
+            .map(|e| e.to_string())
+            .collect();
+        Ok(SourceWatcher {
+            root,
+            _watcher: watcher,
+            changed,
+            seen,
+            extensions,
+            max_file_size: config.max_file_size,
+        })
+    }
+
+    // The files that are different from the version the model has seen
+    pub fn changes(&self) -> Vec<FileChange> {
+        let paths = self.changed.lock().map(|c| c.clone()).unwrap_or_default();
+        let mut changes = Vec::new();
+        for path in paths {
+            let Ok(relative) = path.strip_prefix(&self.root) else {
+                continue;
+            };
+            let seen = self.seen.get(relative);
+            let tracked = seen.is_some()
+                || relative
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .is_some_and(|e| self.extensions.contains(e));
+            if !tracked {
+                continue;
+            }
+            if !path.exists() {
+                if seen.is_some() {
+                    changes.push(FileChange {
+                        path: relative.to_path_buf(),
+                        contents: None,
+                    });
+                }
+                continue;
+            }
+            // directories, binaries and files that grew too large are not sent
+            let Ok(contents) = read_source_file(&path, self.max_file_size) else {
+                continue;
+            };
+            if seen != Some(&hash_contents(&contents)) {
+                changes.push(FileChange {
+                    path: relative.to_path_buf(),
+                    contents: Some(contents),
+                });
+            }
+        }
+        changes
+    }
+
+    // Formats the changes the way they are sent to the model
+    pub fn prepare_update(&self, changes: Vec<FileChange>) -> SourceUpdate {
+        let mut code = String::new();
+        let mut versions = Vec::new();
+        for change in changes {
+            let name = change.path.to_string_lossy();
+            match &change.contents {
+                Some(contents) => code.push_str(&format!(
+                    "\n<filename>{}</filename>\n<file_contents>{}\n</file_contents>",
+                    name, contents
+                )),
+                None => code.push_str(&format!("\n<deleted_file>{}</deleted_file>", name)),
+            }
+            versions.push((change.path, change.contents.as_deref().map(hash_contents)));
+        }
+        // NOTE: Here is something stupid for my edge case - see code.rs
+        let (p1, p2) = ("<bedrust_be", "gin_source>");
+        let (p3, p4) = ("</bedrust_en", "d_source>");
+        let wrapped_code = format!("{}{}{}{}{}", p1, p2, code, p3, p4);
+        SourceUpdate {
+            prompt: constants::CODE_REFRESH_PROMPT.replace("{SOURCE_CODE}", wrapped_code.as_str()),
+            versions,
+        }
+    }
+
+    // Called once the update was sent to the model
+    pub fn mark_seen(&mut self, update: &SourceUpdate) {
+        let mut changed = self.changed.lock().ok();
+        for (path, hash) in &update.versions {
+            match hash {
+                Some(hash) => self.seen.insert(path.clone(), hash.clone()),
+                None => self.seen.remove(path),
+            };
+            if let Some(changed) = changed.as_mut() {
+                changed.remove(&self.root.join(path));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+    use std::fs;
+
+    #[test]
+    fn only_files_different_from_what_the_model_saw_are_sent() {
+        let tmp = TempDir::new("bedrust-watch").unwrap();
+        let dir = tmp.path().canonicalize().unwrap();
+        fs::write(dir.join("main.rs"), "fn main() {}\n").unwrap();
+        fs::write(dir.join("lib.rs"), "// lib\n").unwrap();
+        let seen = BTreeMap::from([
+            (PathBuf::from("main.rs"), hash_contents("fn main() {}\n")),
+            (PathBuf::from("lib.rs"), hash_contents("// lib\n")),
+        ]);
+        let config = CodeChatConfig {
+            max_file_size: 1024,
+            ..Default::default()
+        };
+        let mut watcher = SourceWatcher::new(&dir, seen, &config).unwrap();
+
+        // the events are simulated, so the test does not depend on the file system notifications
+        fs::write(dir.join("main.rs"), "fn main() { todo!() }\n").unwrap();
+        fs::write(dir.join("new.rs"), "// new\n").unwrap();
+        fs::write(dir.join("notes.txt"), "not source code\n").unwrap();
+        fs::remove_file(dir.join("lib.rs")).unwrap();
+        watcher.changed.lock().unwrap().extend([
+            dir.join("main.rs"),
+            dir.join("new.rs"),
+            dir.join("notes.txt"),
+            dir.join("lib.rs"),
+        ]);
+
+        let changes = watcher.changes();
+        let paths: Vec<_> = changes.iter().map(|c| c.path.to_str().unwrap()).collect();
+        assert_eq!(paths, vec!["lib.rs", "main.rs", "new.rs"]);
+        assert!(changes[0].contents.is_none());
+
+        let update = watcher.prepare_update(changes);
+        assert!(update
+            .prompt
+            .contains("<deleted_file>lib.rs</deleted_file>"));
+        assert!(update.prompt.contains("todo!()"));
+        watcher.mark_seen(&update);
+        assert!(watcher.changes().is_empty());
+
+        // saved again without changes
+        fs::write(dir.join("main.rs"), "fn main() { todo!() }\n").unwrap();
+        watcher.changed.lock().unwrap().insert(dir.join("main.rs"));
+        assert!(watcher.changes().is_empty());
+    }
+}